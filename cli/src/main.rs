@@ -19,17 +19,24 @@ use config::*;
 use emulator_connect::servers::hd::HDOracleEmulator;
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
+use sapio::contract::compiler::memo::{CompileCache, DiskCompileCache, InMemoryCompileCache};
 use sapio::contract::context::MapEffectDB;
 
+use sapio_base::effects::{EffectPath, EffectRecord, JsonLogEffectDB};
 use sapio_base::serialization_helpers::SArc;
+use std::convert::TryFrom;
 use std::convert::TryInto;
 
+use sapio::contract::abi::receipt::ContractReceipt;
+use sapio::contract::abi::timeline::Funding;
 use sapio::contract::object::LinkedPSBT;
+use sapio::contract::object::Program;
 use sapio::contract::object::SapioStudioObject;
 use sapio::contract::Compiled;
 use sapio::contract::Context;
 use sapio::template::output::OutputMeta;
 use sapio::template::TemplateMetadata;
+use sapio::template::{StandardnessMode, StandardnessPolicy};
 use sapio::util::extended_address::ExtendedAddress;
 use sapio_base::txindex::TxIndex;
 use sapio_base::txindex::TxIndexLogger;
@@ -66,6 +73,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             (@subcommand get_key =>
                 (about: "Get Signing Condition")
                 (@arg psbt: -p --psbt +takes_value +required #{1,2} {check_file} "The file containing the PSBT to Get a Key For")
+                (@arg input: -i --input +takes_value "The index of the input spending the contract (default 0)")
             )
             (@subcommand show =>
                 (about: "Show a psbt")
@@ -88,14 +96,73 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 (@arg outpoint: --outpoint +takes_value "Use this specific outpoint")
                 (@arg json: "JSON to Bind")
             )
+            (@subcommand audit =>
+                (about: "Report where a compiled contract's funds go, failing if it could spend more than it receives")
+                (@arg amount: --amount +takes_value "The amount in sats the contract will receive, if not the max of its range")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand snapshot =>
+                (about: "Print a snapshot of the chain from the configured node, for use with create --chain")
+            )
+            (@subcommand diff =>
+                (about: "Compare two compiled contracts path by path")
+                (@arg json: --json "Output the differences as JSON")
+                (@arg a: +required {check_file} "JSON file of the old compiled contract")
+                (@arg b: +required {check_file} "JSON file of the new compiled contract")
+            )
+            (@subcommand timeline =>
+                (about: "List every path from a compiled contract to a final output, with when each transaction can confirm")
+                (@arg funding_height: --("funding-height") +takes_value "The height the contract was funded at")
+                (@arg funding_time: --("funding-time") +takes_value requires[funding_height] "The median time past of the funding block")
+                (@arg json_out: --json "Output the timeline as JSON")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand verify =>
+                (about: "Check that the fields of a compiled contract, e.g. from a counterparty, agree with each other, failing if not")
+                (@arg json_out: --json "Output the inconsistencies as JSON")
+                (@arg json: "JSON of the compiled contract")
+            )
+            (@subcommand graph =>
+                (about: "Draw a compiled contract, or the output of bind, as Graphviz DOT (the default) or Mermaid")
+                (@arg mermaid: --mermaid "Output a Mermaid flowchart instead of DOT")
+                (@arg bound: --bound "The JSON is the output of bind, not a compiled contract")
+                (@arg json: "JSON of the compiled contract")
+            )
             (@subcommand create =>
                 (about: "create a contract to a specific UTXO")
                 (@group from +required =>
                     (@arg file: -f --file +takes_value {check_file} "Which Contract to Create, given a WASM Plugin file")
                     (@arg key:  -k --key +takes_value "Which Contract to Create, given a WASM Hash")
                 )
+                (@arg trace: --trace +takes_value "Write a compilation trace to <trace>.json and flamegraph folded stacks to <trace>.folded")
+                (@arg deny_warnings: --("deny-warnings") "Fail if compilation produces any warnings")
+                (@arg cache: --cache +takes_value "Reuse compiled sub-contracts across runs, stored in this directory. Clear it if a plugin's logic changes.")
+                (@arg max_depth: --("max-depth") +takes_value "Fail if any path is deeper than this")
+                (@arg max_templates: --("max-templates") +takes_value "Fail if more than this many templates are created")
+                (@arg timeout: --timeout +takes_value "Fail if compilation takes longer than this many milliseconds")
+                (@group chain =>
+                    (@arg chain_file: --chain +takes_value {check_file} "Compile against the chain snapshot in this file")
+                    (@arg chain_from_node: --("chain-from-node") "Compile against a chain snapshot taken from the configured node")
+                )
+                (@arg effects_db: --("effects-db") +takes_value "Apply the effects logged in this directory, in addition to any in the args")
+                (@arg standardness: --standardness +takes_value "What to do with templates which won't be relayed: strict, permissive, or off. Defaults to strict on mainnet and signet, permissive otherwise.")
+                (@arg receipt: --receipt +takes_value "Write a receipt of the plugin, arguments, and digest of the result to this file")
                 (@arg json: "JSON of args")
             )
+            (@subcommand ("apply-effect") =>
+                (about: "Log an effect to a directory for use with create --effects-db")
+                (@arg by: --by +required +takes_value "Who is applying the effect")
+                (@arg db: +required "The effect log directory, created if it does not exist")
+                (@arg path: +required "The path the effect applies at")
+                (@arg name: +required "The name of the effect")
+                (@arg value: +required "JSON of the effect's arguments")
+            )
+            (@subcommand ("verify-receipt") =>
+                (about: "Recompile a contract from a receipt and check it matches the receipt's digest")
+                (@arg file: -f --file +takes_value {check_file} "The WASM Plugin file, if not already loaded")
+                (@arg compiled: --compiled +takes_value {check_file} "Also check that this compiled contract JSON matches byte for byte")
+                (@arg receipt: +required {check_file} "The receipt to verify")
+            )
             (@subcommand load =>
                 (about: "Load a wasm contract module, returns the hex sha3 hash key")
                 (@arg file: -f --file +required +takes_value {check_file} "Which Contract to Create, given a WASM Plugin file")
@@ -164,7 +231,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             Some(("get_key", args)) => {
                 let psbt = decode_psbt_file(args, "psbt")?;
-                let h = emulator.get_signer_for(psbt.extract_tx().get_ctv_hash(0))?;
+                let input = args.value_of("input").map(str::parse).transpose()?;
+                let h =
+                    emulator.get_signer_for(psbt.extract_tx().get_ctv_hash(input.unwrap_or(0)))?;
                 println!("{}", h);
             }
             Some(("show", args)) => {
@@ -297,7 +366,132 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     println!("{}", serde_json::to_string_pretty(&bound)?);
                 }
             }
+            Some(("audit", args)) => {
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let amount = args
+                    .value_of("amount")
+                    .map(|a| a.parse().map(bitcoin::Amount::from_sat))
+                    .transpose()?;
+                let audit = j.audit(amount);
+                println!("{}", serde_json::to_string_pretty(&audit)?);
+                for v in audit.violations.iter() {
+                    eprintln!("error: {}", v);
+                }
+                if !audit.is_sound() {
+                    return Err(format!("{} violation(s) found", audit.violations.len()).into());
+                }
+            }
+            Some(("apply-effect", args)) => {
+                let mut db = JsonLogEffectDB::open(args.value_of_os("db").unwrap().into())?;
+                db.apply(EffectRecord {
+                    path: SArc(Arc::new(EffectPath::try_from(
+                        args.value_of("path").unwrap(),
+                    )?)),
+                    name: SArc(Arc::new(args.value_of("name").unwrap().into())),
+                    value: serde_json::from_str(args.value_of("value").unwrap())?,
+                    applied_by: args.value_of("by").unwrap().into(),
+                    applied_at: std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)?
+                        .as_secs(),
+                })?;
+            }
+            Some(("snapshot", _args)) => {
+                let client =
+                    rpc::Client::new(cfg.api_node.url.clone(), cfg.api_node.auth.clone()).await?;
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&chain_snapshot(&client).await?)?
+                );
+            }
+            Some(("diff", args)) => {
+                let a: Compiled = serde_json::from_slice(&std::fs::read(
+                    args.value_of_os("a").expect("Required"),
+                )?)?;
+                let b: Compiled = serde_json::from_slice(&std::fs::read(
+                    args.value_of_os("b").expect("Required"),
+                )?)?;
+                let diff = a.diff(&b);
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&diff)?);
+                } else {
+                    print!("{}", diff);
+                }
+            }
+            Some(("timeline", args)) => {
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let funding = args
+                    .value_of("funding_height")
+                    .map(|h| -> Result<Funding, Box<dyn std::error::Error>> {
+                        Ok(Funding {
+                            height: h.parse()?,
+                            time: args.value_of("funding_time").map(str::parse).transpose()?,
+                        })
+                    })
+                    .transpose()?;
+                let timeline = j.timeline(funding);
+                if args.is_present("json_out") {
+                    println!("{}", serde_json::to_string_pretty(&timeline)?);
+                } else {
+                    print!("{}", timeline);
+                }
+            }
+            Some(("verify", args)) => {
+                let j: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                let inconsistencies = j.verify(config.network, emulator.as_ref())?;
+                if args.is_present("json_out") {
+                    println!("{}", serde_json::to_string_pretty(&inconsistencies)?);
+                } else {
+                    for i in inconsistencies.iter() {
+                        println!("{}", i);
+                    }
+                }
+                if !inconsistencies.is_empty() {
+                    return Err(format!("{} inconsistency(s) found", inconsistencies.len()).into());
+                }
+            }
+            Some(("graph", args)) => {
+                let s = if let Some(json) = args.value_of("json") {
+                    json.to_string()
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    s
+                };
+                let graph = if args.is_present("bound") {
+                    serde_json::from_str::<Program>(&s)?.graph()?
+                } else {
+                    serde_json::from_str::<Compiled>(&s)?.graph()
+                };
+                if args.is_present("mermaid") {
+                    print!("{}", graph.to_mermaid());
+                } else {
+                    print!("{}", graph.to_dot());
+                }
+            }
             Some(("create", args)) => {
+                let cache: Arc<dyn CompileCache> = if let Some(dir) = args.value_of_os("cache") {
+                    Arc::new(DiskCompileCache::new(dir.into())?)
+                } else {
+                    Arc::new(InMemoryCompileCache::default())
+                };
                 let sph = WasmPluginHandle::new(
                     "org".into(),
                     "judica".into(),
@@ -308,7 +502,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     config.network,
                     plugin_map,
                 )
-                .await?;
+                .await?
+                .with_compile_cache(Some(cache));
                 let api = sph.get_api()?;
                 let validator = jsonschema_valid::Config::from_schema(
                     &api,
@@ -327,11 +522,106 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                     return Ok(());
                 }
-                let create_args: CreateArgs<serde_json::Value> = serde_json::from_value(params)?;
+                let mut create_args: CreateArgs<serde_json::Value> =
+                    serde_json::from_value(params)?;
+                create_args.context.trace = args.is_present("trace");
+                if let Some(dir) = args.value_of_os("effects_db") {
+                    let db = JsonLogEffectDB::open(dir.into())?;
+                    create_args.context.effects.merge(&db);
+                }
+                if let Some(file) = args.value_of_os("chain_file") {
+                    create_args.context.chain =
+                        Some(serde_json::from_slice(&std::fs::read(file)?)?);
+                } else if args.is_present("chain_from_node") {
+                    let client =
+                        rpc::Client::new(cfg.api_node.url.clone(), cfg.api_node.auth.clone())
+                            .await?;
+                    create_args.context.chain = Some(chain_snapshot(&client).await?);
+                }
+                if let Some(mode) = args.value_of("standardness") {
+                    let mode: StandardnessMode = serde_json::from_value(mode.into())?;
+                    create_args.context.standardness = Some(
+                        StandardnessPolicy::for_network(create_args.context.network)
+                            .with_mode(mode),
+                    );
+                }
+                let limits = &mut create_args.context.limits;
+                if let Some(d) = args.value_of("max_depth") {
+                    limits.max_depth = Some(d.parse()?);
+                }
+                if let Some(t) = args.value_of("max_templates") {
+                    limits.max_templates = Some(t.parse()?);
+                }
+                if let Some(ms) = args.value_of("timeout") {
+                    limits.max_millis = Some(ms.parse()?);
+                }
 
-                let v = sph.create(&create_args)?;
+                let (v, report) = sph.create_traced(&create_args)?;
+                if let (Some(trace), Some(report)) = (args.value_of("trace"), report) {
+                    std::fs::write(
+                        format!("{}.json", trace),
+                        serde_json::to_string_pretty(&report)?,
+                    )?;
+                    std::fs::write(format!("{}.folded", trace), report.to_folded_stacks())?;
+                }
+                let diagnostics = v.all_diagnostics();
+                for d in diagnostics.iter() {
+                    eprintln!("warning: {}", d);
+                }
+                if args.is_present("deny_warnings") && !diagnostics.is_empty() {
+                    return Err(format!(
+                        "denying {} warning(s) from compilation",
+                        diagnostics.len()
+                    )
+                    .into());
+                }
+                if let Some(receipt_path) = args.value_of_os("receipt") {
+                    let receipt = ContractReceipt::new(sph.id().to_string(), create_args, &v)?;
+                    std::fs::write(receipt_path, serde_json::to_string_pretty(&receipt)?)?;
+                }
                 println!("{}", serde_json::to_string(&v)?);
             }
+            Some(("verify-receipt", args)) => {
+                let receipt: ContractReceipt = serde_json::from_slice(&std::fs::read(
+                    args.value_of_os("receipt").expect("Required"),
+                )?)?;
+                let sph = WasmPluginHandle::new(
+                    "org".into(),
+                    "judica".into(),
+                    "sapio-cli".into(),
+                    &emulator,
+                    // the plugin is checked against the receipt below
+                    Some(receipt.plugin.as_str()).filter(|_| !args.is_present("file")),
+                    args.value_of_os("file"),
+                    config.network,
+                    plugin_map,
+                )
+                .await?;
+                if sph.id().to_string() != receipt.plugin {
+                    return Err(format!(
+                        "plugin {} does not match the receipt's plugin {}",
+                        sph.id().to_string(),
+                        receipt.plugin
+                    )
+                    .into());
+                }
+                let v = sph.create(&receipt.arguments)?;
+                if !receipt.matches(&v)? {
+                    return Err(format!(
+                        "recompiled contract has digest {}, but the receipt has {}",
+                        v.digest()?,
+                        receipt.digest
+                    )
+                    .into());
+                }
+                if let Some(compiled) = args.value_of_os("compiled") {
+                    let given: Compiled = serde_json::from_slice(&std::fs::read(compiled)?)?;
+                    if given.to_canonical_json()? != v.to_canonical_json()? {
+                        return Err("the compiled contract does not match the receipt".into());
+                    }
+                }
+                println!("OK {}", receipt.digest);
+            }
             Some(("api", args)) => {
                 let sph = WasmPluginHandle::new(
                     "org".into(),
@@ -6,6 +6,9 @@
 
 use bitcoin::consensus::deserialize;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoincore_rpc_async::RpcApi;
+use sapio::contract::context::ChainSnapshot;
+use std::collections::BTreeMap;
 
 /// Checks that a file exists during argument parsing
 ///
@@ -34,3 +37,25 @@ pub fn decode_psbt_file(
     let psbt: PartiallySignedTransaction = deserialize(&bytes[..])?;
     Ok(psbt)
 }
+
+/// Block targets to estimate feerates for when taking a chain snapshot
+const FEERATE_TARGETS: [u16; 5] = [1, 3, 6, 36, 144];
+
+/// Takes a snapshot of the chain from a node, for compiling against
+pub async fn chain_snapshot(
+    client: &bitcoincore_rpc_async::Client,
+) -> Result<ChainSnapshot, Box<dyn std::error::Error>> {
+    let info = client.get_blockchain_info().await?;
+    let mut feerates = BTreeMap::new();
+    for target in FEERATE_TARGETS.iter() {
+        // estimates may be unavailable, e.g. on regtest
+        if let Some(rate) = client.estimate_smart_fee(*target, None).await?.fee_rate {
+            feerates.insert(*target, rate.as_sat());
+        }
+    }
+    Ok(ChainSnapshot {
+        height: info.blocks as u32,
+        median_time_past: info.median_time as u32,
+        feerates,
+    })
+}
@@ -5,7 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
-use bitcoin::SigHash;
+use bitcoin::{Script, SigHash};
 #[derive(Clone)]
 pub struct HDOracleEmulator {
     root: ExtendedPrivKey,
@@ -51,60 +51,80 @@ impl HDOracleEmulator {
 
     /// Signs a PSBT with the correct derived key.
     ///
-    /// Always signs for spending index 0.
+    /// Signs every input whose witness script contains the key for the
+    /// template hash committing to that input's index. An input without a
+    /// witness script is signed as a p2wpkh spend if its utxo pays to that
+    /// key. Other inputs, and inputs whose key can't be derived, are skipped.
     ///
-    /// May fail to sign if the PSBT is not properly formatted
+    /// May fail to sign if the PSBT is not properly formatted, or if no input
+    /// commits to the oracle's key.
     fn sign(
         &self,
         mut b: PartiallySignedTransaction,
         secp: &Secp256k1<All>,
     ) -> Result<PartiallySignedTransaction, std::io::Error> {
         let tx = b.clone().extract_tx();
-        let h = tx.get_ctv_hash(0);
-        if let Ok(key) = self.derive(h, secp) {
-            if let Some(utxo) = &b.inputs[0].witness_utxo {
-                // This is *funny*. In this case, we are assuming that our signature is required
-                // and if a scriptcode is not present than it must be the case that it is a p2wpkh
-                // script, so we generate a scriptcode for our key as a p2wpkh... this is a reasonable
-                // choice! We do not look at the utxo (for now) to verify this.
-
-                let scriptcode = b.inputs[0].witness_script.clone().unwrap_or_else(|| {
-                    let mut v = vec![0u8; 26];
-                    v[0..4].copy_from_slice(&[0x19, 0x76, 0xa9, 0x14]);
-                    v[4..24].copy_from_slice(&key.identifier(secp).as_hash()[..]);
-                    v[24..26].copy_from_slice(&[0x88, 0xac]);
-                    bitcoin::blockdata::script::Builder::from(v).into_script()
-                });
-                let mut sighash = bitcoin::util::bip143::SigHashCache::new(&tx);
-                let sighash = sighash.signature_hash(
-                    0,
-                    &scriptcode,
-                    utxo.value,
-                    bitcoin::blockdata::transaction::SigHashType::All,
-                );
-                use bitcoin::secp256k1::ThirtyTwoByteHash;
-                struct Wrapped(SigHash);
-                impl ThirtyTwoByteHash for Wrapped {
-                    fn into_32(self) -> [u8; 32] {
-                        self.0.as_hash().into_inner()
+        let mut sighash = bitcoin::util::bip143::SigHashCache::new(&tx);
+        let mut signed = false;
+        for (idx, input) in b.inputs.iter_mut().enumerate() {
+            let h = tx.get_ctv_hash(idx as u32);
+            let key = match self.derive(h, secp) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            let pk = key.private_key.public_key(secp);
+            let utxo = match &input.witness_utxo {
+                Some(utxo) => utxo,
+                None => continue,
+            };
+            let scriptcode = match &input.witness_script {
+                Some(script) => {
+                    let pk_bytes = pk.to_bytes();
+                    if !script
+                        .as_bytes()
+                        .windows(pk_bytes.len())
+                        .any(|w| w == &pk_bytes[..])
+                    {
+                        continue;
+                    }
+                    script.clone()
+                }
+                // Without a witness script, our signature can only be for
+                // a p2wpkh output, whose scriptcode is the p2pkh script.
+                None => match pk.wpubkey_hash() {
+                    Some(wpkh) if utxo.script_pubkey == Script::new_v0_wpkh(&wpkh) => {
+                        Script::new_p2pkh(&pk.pubkey_hash())
                     }
+                    _ => continue,
+                },
+            };
+            let sighash = sighash.signature_hash(
+                idx,
+                &scriptcode,
+                utxo.value,
+                bitcoin::blockdata::transaction::SigHashType::All,
+            );
+            use bitcoin::secp256k1::ThirtyTwoByteHash;
+            struct Wrapped(SigHash);
+            impl ThirtyTwoByteHash for Wrapped {
+                fn into_32(self) -> [u8; 32] {
+                    self.0.as_hash().into_inner()
                 }
-                let msg = bitcoin::secp256k1::Message::from(Wrapped(sighash));
-                let mut signature: Vec<u8> = secp
-                    .sign(&msg, &key.private_key.key)
-                    .serialize_der()
-                    .to_vec();
-                signature.push(0x01);
-                let pk = key.private_key.public_key(secp);
-                b.inputs[0].partial_sigs.insert(pk, signature);
-                return Ok(b);
-            } else {
-                input_error("Could not find UTXO")?;
             }
+            let msg = bitcoin::secp256k1::Message::from(Wrapped(sighash));
+            let mut signature: Vec<u8> = secp
+                .sign(&msg, &key.private_key.key)
+                .serialize_der()
+                .to_vec();
+            signature.push(0x01);
+            input.partial_sigs.insert(pk, signature);
+            signed = true;
+        }
+        if signed {
+            Ok(b)
         } else {
-            input_error("Could Not Derive Key")?;
+            input_error("No input commits to the oracle key")
         }
-        input_error("Unknown Failure to Sign")
     }
 
     /// the main server business logic.
@@ -156,3 +176,86 @@ impl HDOracleEmulator {
         t.flush().await
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::OP_CHECKSIG;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::{OutPoint, Transaction, TxIn, TxOut};
+    fn checksig(pk: &bitcoin::PublicKey) -> Script {
+        Builder::new()
+            .push_key(pk)
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+    #[test]
+    fn test_sign_inputs() {
+        let secp = Secp256k1::new();
+        let root = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1; 32]).unwrap();
+        let oracle = HDOracleEmulator::new(root, false);
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..3)
+                .map(|vout| TxIn {
+                    previous_output: OutPoint::new(Default::default(), vout),
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let key_for = |idx: u32| {
+            let key = oracle.derive(tx.get_ctv_hash(idx), &secp).unwrap();
+            key.private_key.public_key(&secp)
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        // 0 commits to the key for index 1, so isn't ours to sign
+        // 1 commits to its own key in a witness script
+        // 2 is a p2wpkh output to its own key
+        let scripts = vec![
+            (0, Some(checksig(&key_for(1)))),
+            (1, Some(checksig(&key_for(1)))),
+            (2, None),
+        ];
+        for (idx, script) in scripts {
+            let input = &mut psbt.inputs[idx as usize];
+            input.witness_utxo = Some(TxOut {
+                value: 2000,
+                script_pubkey: match &script {
+                    Some(s) => Script::new_v0_wsh(&s.wscript_hash()),
+                    None => Script::new_v0_wpkh(&key_for(idx).wpubkey_hash().unwrap()),
+                },
+            });
+            input.witness_script = script;
+        }
+        let signed = oracle.sign(psbt.clone(), &secp).unwrap();
+        assert!(signed.inputs[0].partial_sigs.is_empty());
+        let mut cache = bitcoin::util::bip143::SigHashCache::new(&tx);
+        for (idx, scriptcode) in [
+            (1, checksig(&key_for(1))),
+            (2, Script::new_p2pkh(&key_for(2).pubkey_hash())),
+        ]
+        .iter()
+        {
+            let pk = key_for(*idx);
+            let sig = &signed.inputs[*idx as usize].partial_sigs[&pk];
+            let sighash = cache.signature_hash(
+                *idx as usize,
+                scriptcode,
+                2000,
+                bitcoin::blockdata::transaction::SigHashType::All,
+            );
+            let msg = bitcoin::secp256k1::Message::from_slice(&sighash[..]).unwrap();
+            let sig = bitcoin::secp256k1::Signature::from_der(&sig[..sig.len() - 1]).unwrap();
+            secp.verify(&msg, &sig, &pk.key).unwrap();
+        }
+        // nothing to sign
+        psbt.inputs.truncate(1);
+        psbt.global.unsigned_tx.input.truncate(1);
+        assert!(oracle.sign(psbt, &secp).is_err());
+    }
+}
@@ -40,11 +40,11 @@ use bitcoin::util::amount::Amount;
 trait CoopKeys {
     fn get_keys(&self) -> Vec<PublicKey>;
 }
-trait PayThisThing: CoopKeys {
+trait PayThisThing: CoopKeys + Sync {
     fn as_compilable(&self) -> &dyn Compilable;
 }
 
-struct JustAKey(PublicKey, Box<dyn Compilable>);
+struct JustAKey(PublicKey, Box<dyn Compilable + Sync>);
 impl CoopKeys for JustAKey {
     fn get_keys(&self) -> Vec<PublicKey> {
         vec![self.0.clone()]
@@ -56,7 +56,7 @@ impl JustAKey {
         amt.update_range(payment.amount);
         let address = Address::p2wpkh(&payment.key, ctx.network)
             .map_err(|_| CompilationError::TerminateCompilation)?;
-        let b: Box<dyn Compilable> = Box::new(Compiled::from_address(address, Some(amt)));
+        let b: Box<dyn Compilable + Sync> = Box::new(Compiled::from_address(address, Some(amt)));
         Ok(JustAKey(payment.key, b))
     }
 }
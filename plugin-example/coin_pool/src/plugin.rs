@@ -63,10 +63,10 @@ impl TryFrom<PoolTypes> for CoinPool {
     fn try_from(v: PoolTypes) -> Result<CoinPool, CompilationError> {
         match v {
             PoolTypes::Basic(payouts) => {
-                let refunds: Vec<(Arc<Mutex<dyn Compilable>>, AmountF64)> = payouts
+                let refunds: Vec<(Arc<Mutex<dyn Compilable + Send>>, AmountF64)> = payouts
                     .iter()
                     .map(|s| {
-                        let compilable: Arc<Mutex<dyn Compilable>> =
+                        let compilable: Arc<Mutex<dyn Compilable + Send>> =
                             Arc::new(Mutex::new(s.key.clone()));
                         Ok((compilable, s.amount))
                     })
@@ -79,20 +79,19 @@ impl TryFrom<PoolTypes> for CoinPool {
             PoolTypes::PluginPool { clauses, refunds } => {
                 let mut processed_refunds = vec![];
                 for payout in refunds.iter() {
-                    if let Some(key) = payout.payout_handle.to_key() {
-                        if let Some(compiled) = create_contract_by_key(
-                            &key,
-                            serde_json::from_str(&payout.payout_args)
-                                .map_err(|_| CompilationError::TerminateCompilation)?,
-                            payout.amount.into(),
-                        ) {
-                            let compilable: Arc<Mutex<dyn Compilable>> =
-                                Arc::new(Mutex::new(compiled));
-                            processed_refunds.push((compilable, payout.amount));
-                            continue;
-                        }
-                    }
-                    return Err(CompilationError::TerminateCompilation);
+                    let key = payout
+                        .payout_handle
+                        .to_key()
+                        .ok_or(CompilationError::TerminateCompilation)?;
+                    let compiled = create_contract_by_key(
+                        &key,
+                        serde_json::from_str(&payout.payout_args)
+                            .map_err(|_| CompilationError::TerminateCompilation)?,
+                        payout.amount.into(),
+                    )?;
+                    let compilable: Arc<Mutex<dyn Compilable + Send>> =
+                        Arc::new(Mutex::new(compiled));
+                    processed_refunds.push((compilable, payout.amount));
                 }
                 Ok(CoinPool {
                     clauses: clauses,
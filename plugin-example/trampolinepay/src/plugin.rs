@@ -36,26 +36,36 @@ enum Versions {
 impl TrampolinePay {
     #[then]
     fn expand(self, ctx: Context) {
-        let compiled = create_contract_by_key(
+        let (contract, report) = create_contract_by_key_traced(
             &self.handle.key,
             serde_json::to_value(CreateArgs {
                 context: ContextualArguments {
                     amount: ctx.funds(),
                     network: ctx.network,
-                    effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
+                    effects: context::MapEffectDB::from(
+                        unsafe { ctx.get_effects_internal() }.as_ref(),
+                    ),
+                    trace: ctx.tracer().is_some(),
+                    limits: ctx.child_limits(),
+                    chain: ctx.chain_snapshot().cloned(),
+                    keys: ctx.child_path_keys()?,
+                    standardness: Some(ctx.standardness_policy().clone()),
                 },
                 arguments: Versions::BatchingTraitVersion0_1_1(self.data.clone()),
             })
             .map_err(|_| CompilationError::TerminateCompilation)?,
             Amount::from_sat(0),
-        );
-        if let Some(contract) = compiled {
-            let mut builder = ctx.template();
-            builder = builder.add_output(contract.amount_range.max(), &contract, None)?;
-            builder.into()
-        } else {
-            Err(CompilationError::TerminateCompilation)
+        )?;
+        // the plugin compiled separately, so account for it here
+        if let (Some(tracer), Some(report)) = (ctx.tracer(), report) {
+            tracer.merge(ctx.path(), report);
         }
+        if let Some(limiter) = ctx.limiter() {
+            limiter.charge(&contract)?;
+        }
+        let mut builder = ctx.template();
+        builder = builder.add_output(contract.amount_range.max(), &contract, None)?;
+        builder.into()
     }
 }
 impl Contract for TrampolinePay {
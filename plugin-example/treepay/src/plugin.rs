@@ -41,6 +41,9 @@ pub struct TreePay {
 }
 
 use bitcoin::util::amount::Amount;
+/// Fees are a fixed amount set aside with `add_fees` rather than a
+/// `set_feerate`, as the parent must know `total_to_pay` to fund this node's
+/// output before it is compiled, while a feerate's fee is only known after.
 struct PayThese {
     contracts: Vec<(Amount, Box<dyn Compilable>)>,
     fees: Amount,
@@ -8,6 +8,8 @@
 use super::*;
 use bitcoin::Amount;
 use core::convert::TryFrom;
+use sapio::contract::compiler::memo::{CompileCache, MemoEntry};
+use sapio::contract::compiler::trace::CompilationReport;
 use sapio_trait::SapioJSONTrait;
 use std::marker::PhantomData;
 /// Print a &str to the parent's console.
@@ -18,7 +20,26 @@ pub fn log(s: &str) {
 }
 
 /// Given a 32 byte plugin identifier, create a new contract instance.
-pub fn create_contract_by_key(key: &[u8; 32], args: Value, amt: Amount) -> Option<Compiled> {
+pub fn create_contract_by_key(
+    key: &[u8; 32],
+    args: Value,
+    amt: Amount,
+) -> Result<Compiled, CompilationError> {
+    Ok(create_contract_by_key_traced(key, args, amt)?.0)
+}
+
+/// As `create_contract_by_key`, also returning the compilation trace if
+/// `args` requested one. Errors from the plugin, such as an exceeded
+/// resource limit, are re-raised here.
+///
+/// Neither the trace nor the templates created are recorded against the
+/// calling `Context`; see `CompilationTracer::merge` and
+/// `ResourceLimiter::charge`.
+pub fn create_contract_by_key_traced(
+    key: &[u8; 32],
+    args: Value,
+    amt: Amount,
+) -> Result<(Compiled, Option<CompilationReport>), CompilationError> {
     unsafe {
         let s = args.to_string();
         let l = s.len();
@@ -30,9 +51,11 @@ pub fn create_contract_by_key(key: &[u8; 32], args: Value, amt: Amount) -> Optio
         );
         if p != 0 {
             let cs = CString::from_raw(p as *mut c_char);
-            serde_json::from_slice(cs.as_bytes()).ok()
+            let created: Result<_, CreateError> = serde_json::from_slice(cs.as_bytes())
+                .map_err(|_| CompilationError::TerminateCompilation)?;
+            Ok(created?)
         } else {
-            None
+            Err(CompilationError::TerminateCompilation)
         }
     }
 }
@@ -129,8 +152,8 @@ impl<T: SapioJSONTrait> TryFrom<SapioHostAPIVerifier<T>> for SapioHostAPI<T> {
 }
 
 /// Given a human readable name, create a new contract instance
-pub fn create_contract(key: &str, args: Value, amt: Amount) -> Option<Compiled> {
-    let key = lookup_module_name(key)?;
+pub fn create_contract(key: &str, args: Value, amt: Amount) -> Result<Compiled, CompilationError> {
+    let key = lookup_module_name(key).ok_or(CompilationError::TerminateCompilation)?;
     create_contract_by_key(&key, args, amt)
 }
 
@@ -170,3 +193,28 @@ impl CTVEmulator for WasmHostEmulator {
         Ok(j)
     }
 }
+
+/// A empty type tag to bind the host's compile cache, if it has one
+pub struct WasmHostCompileCache;
+impl CompileCache for WasmHostCompileCache {
+    fn get(&self, key: &bitcoin::hashes::sha256::Hash) -> Option<MemoEntry> {
+        let p = unsafe { sapio_v1_wasm_plugin_compile_cache_get(key.as_ptr() as i32) };
+        if p != 0 {
+            let cs = unsafe { CString::from_raw(p as *mut c_char) };
+            serde_json::from_slice(cs.as_bytes()).ok()
+        } else {
+            None
+        }
+    }
+    fn put(&self, key: bitcoin::hashes::sha256::Hash, entry: MemoEntry) {
+        if let Ok(s) = serde_json::to_string(&entry) {
+            unsafe {
+                sapio_v1_wasm_plugin_compile_cache_put(
+                    key.as_ptr() as i32,
+                    s.as_ptr() as i32,
+                    s.len() as i32,
+                )
+            }
+        }
+    }
+}
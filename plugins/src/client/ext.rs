@@ -28,6 +28,14 @@ extern "C" {
     /// if ok == 1, result is valid.
     /// out is written and must be 32 bytes of writable memory.
     pub fn sapio_v1_wasm_plugin_lookup_module_name(name: i32, name_len: i32, out: i32, ok: i32);
+    /// get the host's clock, in microseconds, for compilation tracing.
+    pub fn sapio_v1_wasm_plugin_clock_micros() -> i64;
+    /// look up a compiled contract in the host's compile cache.
+    /// key must be 32 bytes. Returns 0 if there is no entry.
+    pub fn sapio_v1_wasm_plugin_compile_cache_get(key: i32) -> i32;
+    /// store a compiled contract in the host's compile cache, if it has one.
+    /// key must be 32 bytes.
+    pub fn sapio_v1_wasm_plugin_compile_cache_put(key: i32, json: i32, len: i32);
 }
 
 #[no_mangle]
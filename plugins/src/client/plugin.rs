@@ -6,6 +6,8 @@
 
 //! binding for making a type into a plugin
 use super::*;
+use sapio::contract::compiler::limits::ResourceLimiter;
+use sapio::contract::compiler::trace::CompilationTracer;
 use sapio_base::effects::EffectPath;
 
 use std::convert::TryFrom;
@@ -28,8 +30,8 @@ where
         encode_json(&res)
     }
 
-    unsafe fn create_result_err(c: *mut c_char) -> Result<String, String> {
-        Self::create_result(c).map_err(|e| e.to_string())
+    unsafe fn create_result_err(c: *mut c_char) -> Result<String, CreateError> {
+        Self::create_result(c).map_err(CreateError::from)
     }
     unsafe fn create_result(c: *mut c_char) -> Result<String, Box<dyn Error>> {
         let s = CString::from_raw(c);
@@ -40,12 +42,17 @@ where
                     network,
                     amount,
                     effects,
+                    trace,
+                    limits,
+                    chain,
+                    keys,
+                    standardness,
                 },
         } = serde_json::from_slice(s.to_bytes())?;
         // TODO: Get The wasm ID here?
         // TODO: In theory, these trampoline bounds are robust/serialization safe...
         // But the API needs stiching to the parent in a sane way...
-        let ctx = Context::new(
+        let mut ctx = Context::new(
             network,
             amount,
             Arc::new(client::WasmHostEmulator),
@@ -53,10 +60,29 @@ where
             EffectPath::try_from("plugin_trampoline")?,
             // TODO: load database?
             Arc::new(effects),
-        );
+        )
+        .with_compile_cache(Arc::new(WasmHostCompileCache))?;
+        if let Some(chain) = chain {
+            ctx = ctx.with_chain_snapshot(chain);
+        }
+        if let Some(keys) = keys {
+            ctx = ctx.with_path_keys(keys);
+        }
+        if let Some(policy) = standardness {
+            ctx = ctx.with_standardness_policy(policy);
+        }
+        if !limits.is_unlimited() {
+            ctx = ctx.with_limiter(ResourceLimiter::with_clock(limits, host_clock));
+        }
         let converted = Self::ToType::try_from(arguments)?;
-        let compiled = converted.compile(ctx)?;
-        Ok(serde_json::to_string(&compiled)?)
+        if trace {
+            let tracer = CompilationTracer::with_clock(host_clock);
+            let compiled = converted.compile(ctx.with_tracer(tracer.clone()))?;
+            Ok(serde_json::to_string(&(compiled, tracer.report()))?)
+        } else {
+            let compiled = converted.compile(ctx)?;
+            Ok(serde_json::to_string(&compiled)?)
+        }
     }
     /// binds this type to the wasm interface, must be called before the plugin can be used.
     unsafe fn register(name: &'static str, logo: Option<&'static [u8]>) {
@@ -69,6 +95,11 @@ where
     }
 }
 
+/// Clock for the `CompilationTracer` and `ResourceLimiter`, as WASM has no system clock of its own
+fn host_clock() -> u64 {
+    unsafe { sapio_v1_wasm_plugin_clock_micros() as u64 }
+}
+
 /// Helper function for encoding a JSON into WASM linear memory
 fn encode_json<S: Serialize>(s: &S) -> *mut c_char {
     if let Ok(Ok(c)) = serde_json::to_string(s).map(CString::new) {
@@ -6,11 +6,14 @@
 
 use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 
 pub use plugin_handle::PluginHandle;
 pub use plugin_handle::WasmPluginHandle;
 
+use crate::CreateError;
+use sapio::contract::compiler::memo::{CompileCache, MemoEntry};
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::cell::Cell;
 use std::collections::HashMap;
@@ -33,6 +36,10 @@ pub struct HostEnvironmentInner {
     pub store: Arc<Mutex<Store>>,
     pub net: bitcoin::Network,
     pub emulator: Arc<dyn CTVEmulator>,
+    /// the module's cache key, used to keep compile cache entries of different
+    /// plugins apart
+    pub module: String,
+    pub compile_cache: Option<Arc<dyn CompileCache>>,
     #[wasmer(export)]
     pub memory: LazyInit<Memory>,
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_allocate_bytes"))]
@@ -164,6 +171,7 @@ mod exports {
         let org = env.org.clone();
         let proj = env.proj.clone();
         let net = env.net;
+        let compile_cache = env.compile_cache.clone();
         let (tx_json, mut rx_json) =
             tokio::sync::oneshot::channel::<Result<serde_json::Value, _>>();
 
@@ -173,7 +181,8 @@ mod exports {
             let plugin =
                 WasmPluginHandle::new(typ, org, proj, &emulator, Some(&h), None, net, Some(mmap))
                     .await
-                    .ok();
+                    .ok()
+                    .map(|sph| sph.with_compile_cache(compile_cache));
             match action {
                 Action::GetAPI => {
                     plugin
@@ -182,9 +191,13 @@ mod exports {
                 }
                 Action::Create { .. } => {
                     if let Some(Ok(create_args)) = v {
-                        plugin
-                            .and_then(|sph| sph.create(&create_args).ok())
-                            .map(|comp| tx_json.send(serde_json::to_value(comp)));
+                        // errors are sent back too, so that the caller can
+                        // re-raise them
+                        let created = match plugin {
+                            Some(sph) => sph.create_traced(&create_args).map_err(CreateError::from),
+                            None => Err(CreateError::Other("Could not load plugin".into())),
+                        };
+                        tx_json.send(serde_json::to_value(created)).ok();
                     }
                 }
             }
@@ -215,6 +228,79 @@ mod exports {
         })
     }
 
+    /// get the host's clock, in microseconds, for compilation tracing.
+    pub fn sapio_v1_wasm_plugin_clock_micros(_env: &HostEnvironment) -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_micros() as i64)
+            .unwrap_or(0)
+    }
+
+    /// the compile cache key for `key` in this plugin, as plugins are not trusted
+    /// to pick keys which don't collide with other plugins.
+    fn compile_cache_key(env: &HostEnvironmentInner, key: i32) -> sha256::Hash {
+        let key = key as usize;
+        let mut engine = sha256::Hash::engine();
+        engine.input(env.module.as_bytes());
+        for byte in env.memory_ref().unwrap().view::<u8>()[key..key + 32]
+            .iter()
+            .map(Cell::get)
+        {
+            engine.input(&[byte]);
+        }
+        sha256::Hash::from_engine(engine)
+    }
+
+    /// look up a compiled contract in the host's compile cache.
+    /// key must be 32 bytes. Returns 0 if there is no entry.
+    pub fn sapio_v1_wasm_plugin_compile_cache_get(env: &HostEnvironment, key: i32) -> i32 {
+        let env = env.lock().unwrap();
+        let entry = match &env.compile_cache {
+            Some(cache) => cache.get(&compile_cache_key(&env, key)),
+            None => None,
+        };
+        let s = match entry.map(|e| serde_json::to_string(&e)) {
+            Some(Ok(s)) => s,
+            _ => return 0,
+        };
+        let bytes = env
+            .allocate_wasm_bytes_ref()
+            .unwrap()
+            .call(s.len() as i32)
+            .unwrap();
+        for (byte, c) in env.memory_ref().unwrap().view::<u8>()[bytes as usize..]
+            .iter()
+            .zip(s.as_bytes())
+        {
+            byte.set(*c);
+        }
+        bytes
+    }
+
+    /// store a compiled contract in the host's compile cache, if it has one.
+    /// key must be 32 bytes.
+    pub fn sapio_v1_wasm_plugin_compile_cache_put(
+        env: &HostEnvironment,
+        key: i32,
+        json: i32,
+        len: i32,
+    ) {
+        let env = env.lock().unwrap();
+        if let Some(cache) = &env.compile_cache {
+            let mut buf = vec![0u8; len as usize];
+            for (src, dst) in env.memory_ref().unwrap().view()[json as usize..(json + len) as usize]
+                .iter()
+                .map(Cell::get)
+                .zip(buf.iter_mut())
+            {
+                *dst = src;
+            }
+            if let Ok(entry) = serde_json::from_slice::<MemoEntry>(&buf[..]) {
+                cache.put(compile_cache_key(&env, key), entry);
+            }
+        }
+    }
+
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(env: &HostEnvironment, a: i32, len: i32) {
         let env = env.lock().unwrap();
@@ -5,7 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::wasm_cache;
-use crate::CreateArgs;
+use crate::{CreateArgs, CreateError};
 pub use plugin_handle::*;
 use sapio::contract::Compiled;
 use sapio_ctv_emulator_trait::NullEmulator;
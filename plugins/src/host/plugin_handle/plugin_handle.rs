@@ -5,12 +5,18 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use super::*;
+use sapio::contract::compiler::trace::CompilationReport;
 use std::error::Error;
 /// Generic plugin handle interface.
 ///
 /// TODO: trait objects for being able to e.g. run plugins remotely.
 pub trait PluginHandle {
     fn create(&self, c: &CreateArgs<serde_json::Value>) -> Result<Compiled, Box<dyn Error>>;
+    /// create, also returning the compilation trace if `c.context.trace` is set.
+    fn create_traced(
+        &self,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Result<(Compiled, Option<CompilationReport>), Box<dyn Error>>;
     fn get_api(&self) -> Result<serde_json::value::Value, Box<dyn Error>>;
     fn get_name(&self) -> Result<String, Box<dyn Error>>;
     fn get_logo(&self) -> Result<String, Box<dyn Error>>;
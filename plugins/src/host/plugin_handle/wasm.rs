@@ -9,6 +9,8 @@ use super::*;
 use crate::host::exports::*;
 use crate::host::wasm_cache::get_all_keys_from_fs;
 use crate::host::{HostEnvironment, HostEnvironmentInner};
+use sapio::contract::compiler::memo::CompileCache;
+use sapio::contract::compiler::trace::CompilationReport;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::error::Error;
 pub struct WasmPluginHandle {
@@ -110,6 +112,8 @@ impl WasmPluginHandle {
             store: Arc::new(Mutex::new(store.clone())),
             net,
             emulator: emulator.clone(),
+            module: key.to_string(),
+            compile_cache: None,
             memory: LazyInit::new(),
             get_api: LazyInit::new(),
             get_name: LazyInit::new(),
@@ -127,7 +131,10 @@ impl WasmPluginHandle {
             sapio_v1_wasm_plugin_debug_log_string,
             sapio_v1_wasm_plugin_create_contract,
             sapio_v1_wasm_plugin_get_api,
-            sapio_v1_wasm_plugin_lookup_module_name
+            sapio_v1_wasm_plugin_lookup_module_name,
+            sapio_v1_wasm_plugin_clock_micros,
+            sapio_v1_wasm_plugin_compile_cache_get,
+            sapio_v1_wasm_plugin_compile_cache_put
         );
 
         let instance = Instance::new(&module, &import_object)?;
@@ -152,6 +159,13 @@ impl WasmPluginHandle {
         })
     }
 
+    /// Serve the plugin's (and any plugins it calls) `Memoized` contracts
+    /// from `cache`.
+    pub fn with_compile_cache(self, cache: Option<Arc<dyn CompileCache>>) -> Self {
+        self.env.lock().unwrap().compile_cache = cache;
+        self
+    }
+
     /// forget an allocated pointer
     pub fn forget(&self, p: i32) -> Result<(), Box<dyn Error>> {
         Ok(self
@@ -210,6 +224,13 @@ impl WasmPluginHandle {
 
 impl PluginHandle for WasmPluginHandle {
     fn create(&self, c: &CreateArgs<serde_json::Value>) -> Result<Compiled, Box<dyn Error>> {
+        Ok(self.create_traced(c)?.0)
+    }
+    fn create_traced(
+        &self,
+        c: &CreateArgs<serde_json::Value>,
+    ) -> Result<(Compiled, Option<CompilationReport>), Box<dyn Error>> {
+        let trace = c.context.trace;
         let arg_str = serde_json::to_string(c)?;
         let offset = self.pass_string(&arg_str)?;
         let create_func = {
@@ -219,9 +240,15 @@ impl PluginHandle for WasmPluginHandle {
         let offset = create_func.get_ref().ok_or("Uninitialized")?.call(offset)?;
         let buf = self.read_to_vec(offset)?;
         self.forget(offset)?;
-        let c: Result<String, String> = serde_json::from_slice(&buf)?;
-        let v: Compiled = serde_json::from_str(&c?)?;
-        Ok(v)
+        let c: Result<String, CreateError> = serde_json::from_slice(&buf)?;
+        let c = c?;
+        if trace {
+            let (v, report): (Compiled, CompilationReport) = serde_json::from_str(&c)?;
+            Ok((v, Some(report)))
+        } else {
+            let v: Compiled = serde_json::from_str(&c)?;
+            Ok((v, None))
+        }
     }
     fn get_api(&self) -> Result<serde_json::value::Value, Box<dyn Error>> {
         let p = self
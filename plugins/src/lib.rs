@@ -5,10 +5,14 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 #[deny(missing_docs)]
-use sapio::contract::{Compilable, Context};
+use sapio::contract::compiler::limits::ResourceLimit;
+use sapio::contract::{Compilable, CompilationError, Context};
+use sapio_base::effects::EffectPath;
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
+use std::error::Error;
 use std::ffi::CString;
+use std::fmt;
 use std::os::raw::c_char;
 use std::sync::Arc;
 
@@ -21,6 +25,59 @@ where
     serde_json::from_str(&s).map_err(serde::de::Error::custom)
 }
 
+/// An error creating a contract in a plugin, in a form which can be passed
+/// from a plugin to the host, and from the host to a plugin which called it.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum CreateError {
+    /// A `CompilationLimits` limit was exceeded at the given path
+    ResourceLimitExceeded(ResourceLimit, EffectPath),
+    /// Any other error, by its message
+    Other(String),
+}
+
+impl From<Box<dyn Error>> for CreateError {
+    fn from(e: Box<dyn Error>) -> Self {
+        let e = match e.downcast::<CreateError>() {
+            Ok(e) => return *e,
+            Err(e) => e,
+        };
+        match e.downcast::<CompilationError>().map(|e| *e) {
+            Ok(CompilationError::ResourceLimitExceeded(limit, path)) => {
+                CreateError::ResourceLimitExceeded(limit, path.as_ref().clone())
+            }
+            Ok(e) => CreateError::Other(e.to_string()),
+            Err(e) => CreateError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<CreateError> for CompilationError {
+    fn from(e: CreateError) -> Self {
+        match e {
+            CreateError::ResourceLimitExceeded(limit, path) => {
+                CompilationError::ResourceLimitExceeded(limit, Arc::new(path))
+            }
+            CreateError::Other(s) => CompilationError::Custom(s.into()),
+        }
+    }
+}
+
+impl fmt::Display for CreateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CreateError::ResourceLimitExceeded(limit, path) => write!(
+                f,
+                "ResourceLimitExceeded({:?}) at {}",
+                limit,
+                String::from(path.clone())
+            ),
+            CreateError::Other(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Error for CreateError {}
+
 #[cfg(feature = "host")]
 pub mod host;
 
@@ -0,0 +1,59 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A view of the chain for contracts to compile against
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// # Chain Snapshot
+/// The state of the chain at some point, e.g. for conditionally compiling
+/// branches which are only useful before or after a certain time.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ChainSnapshot {
+    /// # Block Height
+    /// The height of the chain tip
+    pub height: u32,
+    /// # Median Time Past
+    /// The median time of the last 11 blocks, as a unix timestamp
+    pub median_time_past: u32,
+    /// # Feerates
+    /// Estimated feerates in sats per 1000 vbytes, keyed by the number of
+    /// blocks within which a transaction paying that rate should confirm
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub feerates: BTreeMap<u16, u64>,
+}
+
+impl ChainSnapshot {
+    /// The feerate estimate (in sats per 1000 vbytes) for confirming within
+    /// `target` blocks, using the closest faster estimate if there isn't one
+    /// for `target` exactly.
+    pub fn feerate_for(&self, target: u16) -> Option<u64> {
+        self.feerates
+            .range(..=target)
+            .next_back()
+            .map(|(_, rate)| *rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_feerate_for() {
+        let snapshot = ChainSnapshot {
+            height: 700_000,
+            median_time_past: 1_630_000_000,
+            feerates: vec![(1, 20_000), (6, 5_000), (144, 1_000)]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(snapshot.feerate_for(1), Some(20_000));
+        assert_eq!(snapshot.feerate_for(10), Some(5_000));
+        assert_eq!(snapshot.feerate_for(1008), Some(1_000));
+        assert_eq!(snapshot.feerate_for(0), None);
+    }
+}
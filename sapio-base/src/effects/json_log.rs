@@ -0,0 +1,150 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A persistent EffectDB, stored as a directory of append-only JSON logs
+use super::{EffectDB, EffectDBError, EffectPath, MapEffectDB};
+use crate::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// # Effect Record
+/// One entry in an effect log
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct EffectRecord {
+    /// # Path
+    /// Where the effect applies
+    pub path: SArc<EffectPath>,
+    /// # Name
+    /// The name of the effect at `path`, later records with the same name
+    /// replace earlier ones
+    pub name: SArc<String>,
+    /// # Value
+    /// The arguments to the effect
+    pub value: serde_json::Value,
+    /// # Applied By
+    /// Who applied the effect
+    pub applied_by: String,
+    /// # Applied At
+    /// When the effect was applied, as a unix timestamp
+    pub applied_at: u64,
+}
+
+/// An `EffectDB` backed by a directory holding one log per `EffectPath`,
+/// with one JSON `EffectRecord` per line. Records are only ever appended, so
+/// the full history of each path is kept.
+pub struct JsonLogEffectDB {
+    dir: PathBuf,
+    current: MapEffectDB,
+    history: HashMap<SArc<EffectPath>, Vec<EffectRecord>>,
+}
+
+impl JsonLogEffectDB {
+    /// Open the logs in `dir`, creating it if it does not exist
+    pub fn open(dir: PathBuf) -> Result<Self, EffectDBError> {
+        std::fs::create_dir_all(&dir)?;
+        let mut db = JsonLogEffectDB {
+            dir,
+            current: Default::default(),
+            history: Default::default(),
+        };
+        for entry in std::fs::read_dir(&db.dir)? {
+            let file = entry?.path();
+            if file.extension() == Some("jsonl".as_ref()) {
+                for line in BufReader::new(std::fs::File::open(file)?).lines() {
+                    db.load(serde_json::from_str(&line?)?);
+                }
+            }
+        }
+        Ok(db)
+    }
+    fn load(&mut self, record: EffectRecord) {
+        self.current.insert(
+            record.path.0.clone(),
+            record.name.0.clone(),
+            record.value.clone(),
+        );
+        self.history
+            .entry(record.path.clone())
+            .or_default()
+            .push(record);
+    }
+    /// The log file for `path`. Path fragments never contain '.', so it can
+    /// stand in for the '/' separator.
+    fn log_file(&self, path: &EffectPath) -> PathBuf {
+        let name = String::from(path.clone()).replace('/', ".");
+        self.dir.join(format!("{}.jsonl", name))
+    }
+    /// Append `record` to its path's log, and apply it
+    pub fn apply(&mut self, record: EffectRecord) -> Result<(), EffectDBError> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.log_file(&record.path.0))?
+            .write_all(&line)?;
+        self.load(record);
+        Ok(())
+    }
+    /// Every record applied at `path`, oldest first
+    pub fn history(&self, path: &Arc<EffectPath>) -> &[EffectRecord] {
+        self.history
+            .get(&SArc(path.clone()))
+            .map_or(&[], |v| &v[..])
+    }
+}
+
+impl EffectDB for JsonLogEffectDB {
+    fn get_value<'a>(
+        &'a self,
+        at: &Arc<EffectPath>,
+    ) -> Box<dyn Iterator<Item = (&'a Arc<String>, &'a serde_json::Value)> + 'a> {
+        self.current.get_value(at)
+    }
+    fn get_paths<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Arc<EffectPath>> + 'a> {
+        self.current.get_paths()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryFrom;
+    #[test]
+    fn test_json_log() {
+        let dir = std::env::temp_dir().join(format!("sapio-effect-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = Arc::new(EffectPath::try_from("root/@finish_or_fn/#1").unwrap());
+        let record = |v: u64, by: &str| EffectRecord {
+            path: SArc(path.clone()),
+            name: SArc(Arc::new("update".into())),
+            value: serde_json::json!(v),
+            applied_by: by.into(),
+            applied_at: v,
+        };
+        {
+            let mut db = JsonLogEffectDB::open(dir.clone()).unwrap();
+            db.apply(record(1, "alice")).unwrap();
+            db.apply(record(2, "bob")).unwrap();
+        }
+        let db = JsonLogEffectDB::open(dir.clone()).unwrap();
+        assert_eq!(db.history(&path), &[record(1, "alice"), record(2, "bob")]);
+        let values: Vec<_> = db.get_value(&path).collect();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].1, &serde_json::json!(2));
+        let map = MapEffectDB::from(&db as &dyn EffectDB);
+        assert_eq!(
+            map.get_value(&path).next().unwrap().1,
+            &serde_json::json!(2)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,6 +13,8 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 use std::sync::Arc;
+pub mod json_log;
+pub use json_log::*;
 pub mod path_fragment;
 pub use path_fragment::*;
 pub mod reverse_path;
@@ -25,6 +27,8 @@ pub type EffectPath = ReversePath<PathFragment>;
 pub enum EffectDBError {
     /// Error was from Deserialization
     SerializationError(serde_json::Error),
+    /// Error was from reading or writing a persistent EffectDB
+    IoError(std::io::Error),
 }
 
 impl From<serde_json::Error> for EffectDBError {
@@ -32,13 +36,30 @@ impl From<serde_json::Error> for EffectDBError {
         EffectDBError::SerializationError(e)
     }
 }
+impl std::error::Error for EffectDBError {}
+impl std::fmt::Display for EffectDBError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        std::fmt::Debug::fmt(self, f)
+    }
+}
+impl From<std::io::Error> for EffectDBError {
+    fn from(e: std::io::Error) -> Self {
+        EffectDBError::IoError(e)
+    }
+}
 /// A Generic Trait for EffectDB Functionality
-pub trait EffectDB {
+pub trait EffectDB: Send + Sync {
     /// internal implementation to retrieve a JSON for the path
     fn get_value<'a>(
         &'a self,
         at: &Arc<EffectPath>,
     ) -> Box<dyn Iterator<Item = (&'a Arc<String>, &'a serde_json::Value)> + 'a>;
+    /// every path which may have effects
+    fn get_paths<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Arc<EffectPath>> + 'a>;
+    /// true if there are no effects at any path
+    fn is_empty(&self) -> bool {
+        self.get_paths().all(|p| self.get_value(p).next().is_none())
+    }
 }
 /// #  Effects
 /// Map of all effects to process during compilation.  Each Key represents a
@@ -56,6 +77,36 @@ impl MapEffectDB {
     pub fn skip_serializing(&self) -> bool {
         self.effects.is_empty()
     }
+    /// set the effect `name` at `at`, returning the value it replaced
+    pub fn insert(
+        &mut self,
+        at: Arc<EffectPath>,
+        name: Arc<String>,
+        value: serde_json::Value,
+    ) -> Option<serde_json::Value> {
+        self.effects
+            .entry(SArc(at))
+            .or_default()
+            .insert(SArc(name), value)
+    }
+    /// copy every effect in `other` into this database, replacing any with
+    /// the same path and name
+    pub fn merge(&mut self, other: &dyn EffectDB) {
+        for path in other.get_paths() {
+            for (name, value) in other.get_value(path) {
+                self.insert(path.clone(), name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Copies any `EffectDB` into a `MapEffectDB`, e.g. to pass it to a plugin
+impl From<&dyn EffectDB> for MapEffectDB {
+    fn from(db: &dyn EffectDB) -> Self {
+        let mut map = MapEffectDB::default();
+        map.merge(db);
+        map
+    }
 }
 
 impl EffectDB for MapEffectDB {
@@ -66,6 +117,9 @@ impl EffectDB for MapEffectDB {
         let r: &HashMap<_, _> = self.effects.get(&SArc(at.clone())).unwrap_or(&self.empty);
         Box::new(r.iter().map(|(a, b)| (&a.0, b)))
     }
+    fn get_paths<'a>(&'a self) -> Box<dyn Iterator<Item = &'a Arc<EffectPath>> + 'a> {
+        Box::new(self.effects.keys().map(|p| &p.0))
+    }
 }
 
 #[cfg(test)]
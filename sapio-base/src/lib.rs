@@ -8,7 +8,11 @@
 /// Extra functionality for working with Bitcoin types
 pub mod util;
 pub use util::CTVHash;
+pub mod chain_snapshot;
+pub mod limits;
+pub mod path_keys;
 pub mod plugin_args;
+pub mod standardness;
 
 /// Helpers for making correct time locks
 pub mod timelocks;
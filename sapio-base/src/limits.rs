@@ -0,0 +1,35 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Bounds on the resources a single compilation may use
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// # Compilation Limits
+/// Bounds on the work done compiling a contract, for compiling untrusted
+/// arguments safely. Unset limits are unbounded.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompilationLimits {
+    /// # Maximum Path Depth
+    /// The most fragments any derived path may have
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_depth: Option<usize>,
+    /// # Maximum Templates
+    /// The most templates that may be created in total
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_templates: Option<usize>,
+    /// # Maximum Time
+    /// The most wall-clock time compilation may take, in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub max_millis: Option<u64>,
+}
+
+impl CompilationLimits {
+    /// true if no limit is set
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Deterministic derivation of a fresh key for each `EffectPath`
+use crate::effects::EffectPath;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{Secp256k1, Verification};
+use bitcoin::util::bip32::{self, ChildNumber, DerivationPath, ExtendedPubKey, KeySource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// # Key Derivation Scheme
+/// How the derivation path for an `EffectPath` is chosen
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyDerivationScheme {
+    /// # Hashed Path
+    /// Four unhardened steps, taken from the first 16 bytes of the SHA256 of
+    /// the path's string form (each masked to 31 bits)
+    #[default]
+    HashedPath,
+}
+
+impl KeyDerivationScheme {
+    /// The derivation path, relative to the extended key, for `path`
+    pub fn derivation_path(&self, path: &EffectPath) -> DerivationPath {
+        match self {
+            KeyDerivationScheme::HashedPath => {
+                let h = sha256::Hash::hash(String::from(path.clone()).as_bytes());
+                h[..16]
+                    .chunks(4)
+                    .map(|c| ChildNumber::Normal {
+                        index: u32::from_be_bytes([c[0], c[1], c[2], c[3]]) & 0x7fff_ffff,
+                    })
+                    .collect::<Vec<_>>()
+                    .into()
+            }
+        }
+    }
+}
+
+/// # Path Keys
+/// An extended public key from which a fresh key is derived for each path
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct PathKeys {
+    /// # Extended Public Key
+    #[schemars(with = "String")]
+    pub xpub: ExtendedPubKey,
+    /// # Origin
+    /// The fingerprint of the master key and the path from it to `xpub`.
+    /// If unset, `xpub` is treated as the master key.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[schemars(with = "Option<(String, String)>")]
+    pub origin: Option<KeySource>,
+    /// # Scheme
+    #[serde(default)]
+    pub scheme: KeyDerivationScheme,
+}
+
+impl PathKeys {
+    /// The origin of `xpub`, which is its own master key if `origin` is
+    /// unset
+    pub fn source(&self) -> KeySource {
+        self.origin
+            .clone()
+            .unwrap_or_else(|| (self.xpub.fingerprint(), DerivationPath::from(vec![])))
+    }
+    fn child<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &EffectPath,
+    ) -> Result<(ExtendedPubKey, KeySource), bip32::Error> {
+        let child = self.scheme.derivation_path(path);
+        let xpub = self.xpub.derive_pub(secp, &child)?;
+        let (fingerprint, origin) = self.source();
+        Ok((xpub, (fingerprint, origin.extend(&child))))
+    }
+    /// Derive the key for `path`, along with the master fingerprint and full
+    /// derivation path a signer needs to find its private key.
+    pub fn derive<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &EffectPath,
+    ) -> Result<(bitcoin::PublicKey, KeySource), bip32::Error> {
+        self.child(secp, path)
+            .map(|(xpub, source)| (xpub.public_key, source))
+    }
+    /// Re-root these keys at the extended key for `path`, e.g. for a plugin
+    /// which compiles from its own root path.
+    pub fn child_keys<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        path: &EffectPath,
+    ) -> Result<PathKeys, bip32::Error> {
+        let (xpub, source) = self.child(secp, path)?;
+        Ok(PathKeys {
+            xpub,
+            origin: Some(source),
+            scheme: self.scheme,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+    #[test]
+    fn test_derive() {
+        let secp = Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1; 32]).unwrap();
+        let keys = PathKeys {
+            xpub: ExtendedPubKey::from_private(&secp, &xpriv),
+            origin: None,
+            scheme: Default::default(),
+        };
+        let a = EffectPath::try_from("root/@then_fn/@guard/a").unwrap();
+        let b = EffectPath::try_from("root/@then_fn/@guard/b").unwrap();
+        let (key_a, (fingerprint, path_a)) = keys.derive(&secp, &a).unwrap();
+        let (key_b, _) = keys.derive(&secp, &b).unwrap();
+        assert_ne!(key_a, key_b);
+        assert_eq!(keys.derive(&secp, &a).unwrap().0, key_a);
+        // a signer holding the master key can recover the private key
+        assert_eq!(fingerprint, xpriv.fingerprint(&secp));
+        let derived = xpriv.derive_priv(&secp, &path_a).unwrap();
+        assert_eq!(
+            ExtendedPubKey::from_private(&secp, &derived).public_key,
+            key_a
+        );
+        // keys with an origin report the path from the master key
+        let origin = (
+            bitcoin::util::bip32::Fingerprint::from(&[1, 2, 3, 4][..]),
+            DerivationPath::from_str("m/48'/1'").unwrap(),
+        );
+        let keys = PathKeys {
+            origin: Some(origin.clone()),
+            ..keys
+        };
+        let (_, (fingerprint, path)) = keys.derive(&secp, &a).unwrap();
+        assert_eq!(fingerprint, origin.0);
+        assert_eq!(path, origin.1.extend(&path_a));
+    }
+}
@@ -1,4 +1,8 @@
+use crate::chain_snapshot::ChainSnapshot;
 use crate::effects::MapEffectDB;
+use crate::limits::CompilationLimits;
+use crate::path_keys::PathKeys;
+use crate::standardness::StandardnessPolicy;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -39,4 +43,31 @@ pub struct ContextualArguments {
     /// # Effects to augment compilations with
     #[serde(skip_serializing_if = "MapEffectDB::skip_serializing", default)]
     pub effects: MapEffectDB,
+
+    /// # Record a Compilation Trace
+    /// If set, the plugin returns a compilation trace alongside the contract.
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub trace: bool,
+
+    /// # Compilation Limits
+    /// If set, compilation fails once any limit is exceeded.
+    #[serde(skip_serializing_if = "CompilationLimits::is_unlimited", default)]
+    pub limits: CompilationLimits,
+
+    /// # Chain Snapshot
+    /// The state of the chain to compile against, if known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub chain: Option<ChainSnapshot>,
+
+    /// # Path Keys
+    /// An extended key for contracts to derive a fresh key per path from,
+    /// if any.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub keys: Option<PathKeys>,
+
+    /// # Standardness Policy
+    /// The relay rules to check templates against, if not the network's
+    /// default.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub standardness: Option<StandardnessPolicy>,
 }
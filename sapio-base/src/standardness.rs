@@ -0,0 +1,87 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The relay rules compiled templates are checked against
+use bitcoin::util::amount::Amount;
+use bitcoin::Network;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// # Standardness Mode
+/// What to do with a template which would not be relayed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StandardnessMode {
+    /// # Strict
+    /// Fail compilation
+    Strict,
+    /// # Permissive
+    /// Report a `Diagnostic` and continue
+    Permissive,
+    /// # Off
+    /// Don't check templates
+    Off,
+}
+
+/// # Standardness Policy
+/// The relay rules templates are checked against, Bitcoin Core's defaults
+/// unless configured otherwise
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct StandardnessPolicy {
+    /// # Mode
+    pub mode: StandardnessMode,
+    /// # Maximum Version
+    /// The largest standard transaction version (the smallest is 1)
+    pub max_version: i32,
+    /// # Maximum Weight
+    /// The largest standard transaction weight, including witnesses
+    pub max_weight: u64,
+    /// # Maximum OP_RETURN Size
+    /// The largest standard OP_RETURN output script, in bytes
+    pub max_op_return_size: usize,
+    /// # Maximum OP_RETURN Outputs
+    /// The most OP_RETURN outputs a standard transaction may have
+    pub max_op_return_outputs: usize,
+    /// # Maximum TRUC Size
+    /// The largest standard version 3 transaction, in vbytes
+    pub truc_max_vsize: u64,
+    /// # Maximum TRUC Child Size
+    /// The largest standard version 3 transaction spending an unconfirmed
+    /// version 3 transaction, in vbytes
+    pub truc_child_max_vsize: u64,
+    /// # Dust Relay Feerate
+    /// The feerate, in sats/vbyte, below which spending an output costs more
+    /// than it is worth
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub dust_relay_feerate: Amount,
+}
+
+impl StandardnessPolicy {
+    /// Bitcoin Core's default policy for `network`. Core doesn't require
+    /// standard transactions on testnet and regtest, so violations there are
+    /// only reported as warnings.
+    pub fn for_network(network: Network) -> Self {
+        StandardnessPolicy {
+            mode: match network {
+                Network::Bitcoin | Network::Signet => StandardnessMode::Strict,
+                Network::Testnet | Network::Regtest => StandardnessMode::Permissive,
+            },
+            max_version: 3,
+            max_weight: 400_000,
+            max_op_return_size: 83,
+            max_op_return_outputs: 1,
+            truc_max_vsize: 10_000,
+            truc_child_max_vsize: 1_000,
+            dust_relay_feerate: Amount::from_sat(3),
+        }
+    }
+    /// Use `mode` for violations
+    pub fn with_mode(mut self, mode: StandardnessMode) -> Self {
+        self.mode = mode;
+        self
+    }
+}
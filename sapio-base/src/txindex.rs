@@ -14,7 +14,7 @@ pub enum TxIndexError {
     NetworkError(std::io::Error),
     UnknownTxid(Txid),
     IndexTooHigh(u32),
-    RpcError(Box<dyn std::error::Error>),
+    RpcError(Box<dyn std::error::Error + Send + Sync>),
 }
 impl std::error::Error for TxIndexError {}
 
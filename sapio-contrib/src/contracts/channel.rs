@@ -76,7 +76,7 @@ mod tests {
             Amount::from_sat(10000),
             std::sync::Arc::new(CTVAvailable),
             "root".try_into().unwrap(),
-            Default::default(),
+            Arc::new(sapio::contract::context::MapEffectDB::default()),
         );
         Compilable::compile(&x, ctx.derive_str(Arc::new("X".into())).unwrap()).ok();
         Compilable::compile(&y, ctx.derive_str(Arc::new("Y".into())).unwrap()).ok();
@@ -124,7 +124,7 @@ pub struct DBHandle {
 }
 /// DB Trait is for a Trait Object that can be used to record state updates for a channel.
 /// Examples implements a MockDB
-pub trait DB {
+pub trait DB: Send {
     /// Simply save a transcript of all messages to reconstrue channel state
     fn save(&self, a: Args);
     /// gets a handle to this DB instance for global lookup
@@ -16,7 +16,7 @@ use schemars::JsonSchema;
 use serde::Deserialize;
 use std::convert::{TryFrom, TryInto};
 use std::sync::{Arc, Mutex};
-type Payouts = Vec<(Arc<Mutex<dyn Compilable>>, AmountF64)>;
+type Payouts = Vec<(Arc<Mutex<dyn Compilable + Send>>, AmountF64)>;
 /// A CoinPool is a contract that allows a group of individuals to
 /// cooperatively share a UTXO.
 pub struct CoinPool {
@@ -150,7 +150,7 @@ impl TryFrom<UpdateTypes> for Option<CoinPoolUpdate> {
                     .unwrap_or(vec![])
                     .iter()
                     .map(|(a, b)| {
-                        let k: Arc<Mutex<dyn Compilable>> = Arc::new(Mutex::new(a.clone()));
+                        let k: Arc<Mutex<dyn Compilable + Send>> = Arc::new(Mutex::new(a.clone()));
                         (k, (*b).into())
                     })
                     .collect(),
@@ -14,7 +14,7 @@ use sapio_base::Clause;
 use sapio_macros::guard;
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::rc::Rc;
+use std::sync::Arc;
 
 pub mod oracle;
 pub use oracle::{Oracle, Symbol};
@@ -49,7 +49,7 @@ impl<'a> From<GenericBetArguments<'a>> for GenericBet {
         GenericBet {
             amount: v.amount,
             outcomes: v.outcomes,
-            oracle: Rc::new(h),
+            oracle: Arc::new(h),
             cooperate: v.cooperate,
         }
     }
@@ -60,7 +60,7 @@ impl<'a> From<GenericBetArguments<'a>> for GenericBet {
 pub struct GenericBet {
     amount: Amount,
     outcomes: Vec<(i64, Template)>,
-    oracle: Rc<HashMap<i64, (Clause, Clause)>>,
+    oracle: Arc<HashMap<i64, (Clause, Clause)>>,
     cooperate: Clause,
 }
 
@@ -56,6 +56,7 @@ impl DynamicExample {
                     guard: &[],
                     func: |_s, _ctx| Err(CompilationError::TerminateCompilation),
                     name: Arc::new("Empty".into()),
+                    weight: 1,
                 })
             }],
             finish: vec![],
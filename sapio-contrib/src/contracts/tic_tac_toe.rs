@@ -67,8 +67,8 @@ impl Board {
 pub struct TicTacToe {
     board: Board,
     whose_turn: Tile,
-    win_key_x: Arc<dyn Compilable>,
-    win_key_o: Arc<dyn Compilable>,
+    win_key_x: Arc<dyn Compilable + Send + Sync>,
+    win_key_o: Arc<dyn Compilable + Send + Sync>,
     cache: Arc<Mutex<HashMap<(&'static str, Board, Tile), Vec<Template>>>>,
 }
 
@@ -5,6 +5,7 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! contracts for paying a large set of recipients fee efficiently
+use sapio::contract::compiler::memo::Memoized;
 use sapio::contract::*;
 use sapio::*;
 
@@ -35,6 +36,7 @@ impl TreePay {
     fn expand(self, ctx: sapio::Context) {
         let mut builder = ctx.template();
         if self.participants.len() > self.radix {
+            let mut children = vec![];
             for c in self
                 .participants
                 .chunks(self.participants.len() / self.radix)
@@ -43,15 +45,22 @@ impl TreePay {
                 for Payment { amount, .. } in c {
                     amt += amount.clone().try_into()?;
                 }
-                builder = builder.add_output(
+                children.push((
                     amt,
-                    &TreePay {
+                    Memoized(TreePay {
                         participants: c.to_vec(),
                         radix: self.radix,
-                    },
-                    None,
-                )?;
+                    }),
+                ));
             }
+            // sub-trees are independent, so they may be compiled in parallel,
+            // and identical sub-trees need only be compiled once
+            builder = builder.add_outputs(
+                children
+                    .iter()
+                    .map(|(amt, child)| (*amt, child as &(dyn Compilable + Sync), None))
+                    .collect(),
+            )?;
         } else {
             for Payment { amount, address } in self.participants.iter() {
                 builder = builder.add_output(
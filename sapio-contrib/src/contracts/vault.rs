@@ -14,14 +14,14 @@ use sapio_base::timelocks::AnyRelTimeLock;
 use schemars::*;
 use serde::*;
 use std::convert::{TryFrom, TryInto};
-use std::rc::Rc;
 use std::sync::Arc;
 
 /// A Vault makes a "annuity chain" which pays out to `hot_storage` every `timeout` period for `n_steps`.
 /// The funds in `hot_storage` are in an UndoSend contract for a timeout of
 /// `mature`. At any time the remaining funds can be moved to `cold_storage`, which may vary based on the amount.
 pub struct Vault {
-    cold_storage: Rc<dyn Fn(CoinAmount, Context) -> Result<Compiled, CompilationError>>,
+    cold_storage:
+        Arc<dyn Fn(CoinAmount, Context) -> Result<Compiled, CompilationError> + Send + Sync>,
     hot_storage: bitcoin::Address,
     n_steps: u64,
     amount_step: CoinAmount,
@@ -110,7 +110,7 @@ pub struct VaultAddress {
 impl From<VaultAddress> for Vault {
     fn from(v: VaultAddress) -> Self {
         Vault {
-            cold_storage: Rc::new({
+            cold_storage: Arc::new({
                 let cs = v.cold_storage.clone();
                 move |_a, _ctx| Ok(Compiled::from_address(cs.clone(), None))
             }),
@@ -150,7 +150,7 @@ impl TryFrom<VaultTree> for Vault {
     type Error = CompilationError;
     fn try_from(v: VaultTree) -> Result<Self, CompilationError> {
         Ok(Vault {
-            cold_storage: Rc::new({
+            cold_storage: Arc::new({
                 let cs = v.cold_storage.clone();
                 let max: bitcoin::Amount = bitcoin::Amount::try_from(v.max_per_address)
                     .map_err(|_| CompilationError::TerminateCompilation)?;
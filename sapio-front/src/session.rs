@@ -10,6 +10,7 @@ use bitcoin::hashes::Hash;
 use bitcoin::util::amount::Amount;
 use sapio::contract::context::MapEffectDB;
 
+use sapio::contract::compiler::limits::{CompilationLimits, ResourceLimiter};
 use sapio::contract::object::Program;
 use sapio::contract::{Compilable, CompilationError, Compiled, Context};
 use sapio::util::extended_address::ExtendedAddress;
@@ -285,6 +286,8 @@ pub struct Session {
     example_msg: Option<String>,
     menu: &'static Menu,
     network: bitcoin::Network,
+    limits: CompilationLimits,
+    parallel: bool,
 }
 
 /// Internal msg type to permit either strings or bytes
@@ -303,20 +306,39 @@ impl Session {
             example_msg: None,
             menu,
             network,
+            limits: Default::default(),
+            parallel: false,
         }
     }
+    /// bound the resources used compiling any contract in this session
+    pub fn with_limits(mut self, limits: CompilationLimits) -> Session {
+        self.limits = limits;
+        self
+    }
+    /// compile independent sub-contracts in this session in parallel. Has no
+    /// effect unless sapio is built with the `parallel` feature.
+    pub fn with_parallelism(mut self, enabled: bool) -> Session {
+        self.parallel = enabled;
+        self
+    }
     /// get a context for this session
     /// TODO: link to a bitcoin node or something to determine available funds
     /// TODO: use an emulator if desired?
     pub fn get_context(&self) -> Context {
         // Todo: Make Create specify the amount to send.
-        Context::new(
+        let ctx = Context::new(
             self.network,
             Amount::from_sat(100_000_000_000),
             Arc::new(CTVAvailable),
             "frontend_session".try_into().unwrap(),
             Arc::new(MapEffectDB::default()),
         )
+        .with_parallelism(self.parallel);
+        if self.limits.is_unlimited() {
+            ctx
+        } else {
+            ctx.with_limiter(ResourceLimiter::new(self.limits))
+        }
     }
 
     /// process a message from the Session manager (e.g., networking stack)
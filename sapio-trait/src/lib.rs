@@ -27,6 +27,11 @@ pub trait SapioJSONTrait: JsonSchema + Serialize + for<'a> Deserialize<'a> {
                     amount: Amount::from_sat(0),
                     network: Network::Bitcoin,
                     effects: Default::default(),
+                    trace: false,
+                    limits: Default::default(),
+                    chain: None,
+                    keys: None,
+                    standardness: None,
                 },
             })?)
             .map_err(|e| {
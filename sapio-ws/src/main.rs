@@ -9,6 +9,7 @@ use actix::{Actor, StreamHandler};
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 
+use sapio::contract::compiler::limits::CompilationLimits;
 use sapio_contrib::contracts;
 use sapio_front::session;
 
@@ -56,7 +57,9 @@ async fn index(
 ) -> Result<HttpResponse, Error> {
     let resp = ws::start(
         MyWs {
-            sesh: session::Session::new(m, bitcoin::Network::Regtest),
+            sesh: session::Session::new(m, bitcoin::Network::Regtest)
+                .with_limits(LIMITS)
+                .with_parallelism(true),
         },
         &req,
         stream,
@@ -65,6 +68,13 @@ async fn index(
     resp
 }
 
+/// Bounds on compiling the arguments sent by clients
+const LIMITS: CompilationLimits = CompilationLimits {
+    max_depth: Some(256),
+    max_templates: Some(10_000),
+    max_millis: Some(10_000),
+};
+
 lazy_static::lazy_static! {
     static ref MENU : session::Menu = {
         let mut m = session::MenuBuilder::new();
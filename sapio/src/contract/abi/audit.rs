@@ -0,0 +1,281 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Auditing that a compiled contract never spends more than it receives
+use super::object::Object;
+use crate::util::amountrange::AmountRange;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Where the sats sent to one output of a template go
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct OutputFlow {
+    /// the index of the output in the template
+    pub index: usize,
+    /// the amount sent to the output
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub amount: Amount,
+    /// the path of the contract receiving the output
+    pub contract: SArc<EffectPath>,
+}
+
+/// Where the sats spent by one template go
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TemplateFlow {
+    /// the path of the contract the template spends
+    pub path: SArc<EffectPath>,
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// the amount the contract receives from its parent
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub incoming: Amount,
+    /// the outputs created
+    pub outputs: Vec<OutputFlow>,
+    /// fees set aside with `Builder::add_fees`
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub declared_fees: Amount,
+    /// any part of `incoming` neither sent to an output nor declared as a
+    /// fee, which will also go to miners
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub undeclared_fees: Amount,
+    /// the amount which must be brought in by the template's other inputs
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub external_funds: Amount,
+}
+
+/// A way in which a contract fails to conserve funds
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditViolation {
+    /// A template with no inputs besides the contract spends more than the
+    /// contract receives
+    Overspend {
+        /// the path of the contract
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+        /// outputs plus declared fees
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        spent: Amount,
+        /// what the contract receives
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        available: Amount,
+    },
+    /// A template's transaction does not pay the amounts its outputs record
+    OutputMismatch {
+        /// the path of the contract
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+    },
+    /// An output sends an amount outside of the receiving contract's
+    /// `AmountRange`
+    RangeNotCovered {
+        /// the path of the contract
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+        /// the index of the output in the template
+        index: usize,
+        /// the amount sent
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        amount: Amount,
+        /// the receiving contract's range
+        range: AmountRange,
+    },
+}
+
+impl fmt::Display for AuditViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |p: &SArc<EffectPath>| String::from(p.0.as_ref().clone());
+        match self {
+            AuditViolation::Overspend {
+                path,
+                template,
+                spent,
+                available,
+            } => write!(
+                f,
+                "{}: template {} spends {} but only {} is available",
+                show(path),
+                template,
+                spent,
+                available
+            ),
+            AuditViolation::OutputMismatch { path, template } => write!(
+                f,
+                "{}: the transaction of template {} does not match its outputs",
+                show(path),
+                template
+            ),
+            AuditViolation::RangeNotCovered {
+                path,
+                template,
+                index,
+                amount,
+                range,
+            } => write!(
+                f,
+                "{}: output {} of template {} sends {}, outside of the receiving contract's range (max {})",
+                show(path),
+                index,
+                template,
+                amount,
+                range.max()
+            ),
+        }
+    }
+}
+
+/// A per-template account of where a contract's sats go, and any
+/// violations of amount conservation found. See `Object::audit`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct AmountAudit {
+    /// every template reachable from the contract, sorted by path
+    pub flows: Vec<TemplateFlow>,
+    /// the problems found, sorted by path
+    pub violations: Vec<AuditViolation>,
+}
+
+impl AmountAudit {
+    /// true if no violations were found
+    pub fn is_sound(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl Object {
+    /// Audit every template reachable from this contract, assuming the
+    /// contract receives `amount`, or the max of its `AmountRange` if None.
+    ///
+    /// For each template, outputs plus declared fees must not exceed what
+    /// the contract receives, unless the template has other inputs to make
+    /// up the difference. Each output must also fall within the receiving
+    /// contract's `AmountRange`.
+    pub fn audit(&self, amount: Option<Amount>) -> AmountAudit {
+        let mut audit = AmountAudit::default();
+        let mut stack = vec![(self, amount.unwrap_or_else(|| self.amount_range.max()))];
+        while let Some((obj, incoming)) = stack.pop() {
+            let path = &obj.root_path;
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                let outputs_total = tmpl.total_amount();
+                let declared_fees = tmpl.max.checked_sub(outputs_total).unwrap_or(Amount::ZERO);
+                let spent = outputs_total + declared_fees;
+                let external_funds = spent.checked_sub(incoming).unwrap_or(Amount::ZERO);
+                if external_funds > Amount::ZERO && tmpl.tx.input.len() <= 1 {
+                    audit.violations.push(AuditViolation::Overspend {
+                        path: path.clone(),
+                        template: tmpl.hash(),
+                        spent,
+                        available: incoming,
+                    });
+                }
+                if tmpl.tx.output.len() != tmpl.outputs.len()
+                    || tmpl
+                        .tx
+                        .output
+                        .iter()
+                        .zip(tmpl.outputs.iter())
+                        .any(|(txout, out)| txout.value != out.amount.as_sat())
+                {
+                    audit.violations.push(AuditViolation::OutputMismatch {
+                        path: path.clone(),
+                        template: tmpl.hash(),
+                    });
+                }
+                for (index, out) in tmpl.outputs.iter().enumerate() {
+                    if !out.contract.amount_range.contains(out.amount) {
+                        audit.violations.push(AuditViolation::RangeNotCovered {
+                            path: path.clone(),
+                            template: tmpl.hash(),
+                            index,
+                            amount: out.amount,
+                            range: out.contract.amount_range,
+                        });
+                    }
+                    stack.push((&out.contract, out.amount));
+                }
+                audit.flows.push(TemplateFlow {
+                    path: path.clone(),
+                    template: tmpl.hash(),
+                    incoming,
+                    outputs: tmpl
+                        .outputs
+                        .iter()
+                        .enumerate()
+                        .map(|(index, out)| OutputFlow {
+                            index,
+                            amount: out.amount,
+                            contract: out.contract.root_path.clone(),
+                        })
+                        .collect(),
+                    declared_fees,
+                    undeclared_fees: incoming.checked_sub(spent).unwrap_or(Amount::ZERO),
+                    external_funds,
+                });
+            }
+        }
+        let show = |p: &SArc<EffectPath>| String::from(p.0.as_ref().clone());
+        audit.flows.sort_by_key(|f| (show(&f.path), f.template));
+        audit.violations.sort_by_key(|v| match v {
+            AuditViolation::Overspend { path, .. }
+            | AuditViolation::OutputMismatch { path, .. }
+            | AuditViolation::RangeNotCovered { path, .. } => show(path),
+        });
+        audit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{ctx, key};
+    use crate::contract::Compilable;
+    use crate::template::Template;
+    #[test]
+    fn test_audit() {
+        let key = key();
+        let tmpl: Template = ctx()
+            .template()
+            .add_fees(Amount::from_sat(1000))
+            .unwrap()
+            .add_output(Amount::from_sat(5000), &key, None)
+            .unwrap()
+            .add_output(Amount::from_sat(4000), &key, None)
+            .unwrap()
+            .into();
+        let mut obj = key.compile(ctx()).unwrap();
+        obj.ctv_to_tx.insert(tmpl.hash(), tmpl);
+        let audit = obj.audit(Some(Amount::from_sat(10500)));
+        assert!(audit.is_sound());
+        assert_eq!(audit.flows.len(), 1);
+        assert_eq!(audit.flows[0].declared_fees, Amount::from_sat(1000));
+        assert_eq!(audit.flows[0].undeclared_fees, Amount::from_sat(500));
+        let audit = obj.audit(Some(Amount::from_sat(9000)));
+        assert_eq!(
+            audit.violations,
+            vec![AuditViolation::Overspend {
+                path: obj.root_path.clone(),
+                template: audit.flows[0].template,
+                spent: Amount::from_sat(10000),
+                available: Amount::from_sat(9000),
+            }]
+        );
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Fee bumping of bound templates via a child spending their anchor output
+use super::diagnostics::dust_limit;
+use super::object::Object;
+use crate::template::Template;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, PublicKey, Script, Transaction, TxIn, TxOut};
+use miniscript::{Descriptor, DescriptorTrait};
+use sapio_base::CTVHash;
+use std::fmt;
+
+/// Errors that can arise when building a CPFP child
+#[derive(Debug)]
+pub enum CPFPError {
+    /// The parent is not a template of this contract
+    UnknownTemplate,
+    /// The parent's template has no anchor output
+    NoAnchor,
+    /// The anchor output's descriptor is not known
+    UnknownAnchorDescriptor,
+    /// The wallet UTXOs can't pay for the child
+    InsufficientFunds {
+        /// the amount the child's inputs must add up to
+        needed: Amount,
+        /// the amount of the anchor and all wallet UTXOs
+        available: Amount,
+    },
+    /// The Error was due to Miniscript
+    Miniscript(miniscript::Error),
+    /// The child could not be made into a PSBT
+    Psbt(bitcoin::util::psbt::Error),
+}
+impl std::error::Error for CPFPError {}
+impl From<miniscript::Error> for CPFPError {
+    fn from(e: miniscript::Error) -> Self {
+        CPFPError::Miniscript(e)
+    }
+}
+impl From<bitcoin::util::psbt::Error> for CPFPError {
+    fn from(e: bitcoin::util::psbt::Error) -> Self {
+        CPFPError::Psbt(e)
+    }
+}
+impl fmt::Display for CPFPError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Weight of an input spending `d`, with the largest satisfaction of `d`
+fn input_weight(d: &Descriptor<PublicKey>) -> Result<u64, CPFPError> {
+    // outpoint and nSequence, the satisfaction weight covers the rest
+    Ok(4 * (32 + 4 + 4) + d.max_satisfaction_weight()? as u64)
+}
+
+impl Object {
+    /// Find the template `tx` was created from, in this contract or any
+    /// contract it creates, along with the contract it spends.
+    pub fn find_template(&self, tx: &Transaction) -> Option<(&Object, &Template)> {
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            for tmpl in obj.ctv_to_tx.values().chain(obj.suggested_txs.values()) {
+                if tx.get_ctv_hash(tmpl.ctv_index) == tmpl.ctv {
+                    return Some((obj, tmpl));
+                }
+                stack.extend(tmpl.outputs.iter().map(|o| &o.contract));
+            }
+        }
+        None
+    }
+
+    /// Build a child of `parent`, a PSBT of one of this contract's templates
+    /// (e.g. from `bind_psbt`), spending the template's first anchor output
+    /// so that the two transactions together pay at least `package_feerate`
+    /// sats/vbyte.
+    ///
+    /// Wallet `utxos` are added in order until the fee is covered, and the
+    /// rest is sent to `change`. The parent's fee is taken from its inputs'
+    /// `witness_utxo`s if all are known, otherwise from its template. An
+    /// anyone-can-spend anchor is finalized, other inputs are left for the
+    /// wallet to sign.
+    pub fn cpfp_psbt(
+        &self,
+        parent: &PartiallySignedTransaction,
+        utxos: Vec<(OutPoint, Amount, Descriptor<PublicKey>)>,
+        change: Script,
+        package_feerate: Amount,
+    ) -> Result<PartiallySignedTransaction, CPFPError> {
+        let parent_tx = &parent.global.unsigned_tx;
+        let (obj, tmpl) = self
+            .find_template(parent_tx)
+            .ok_or(CPFPError::UnknownTemplate)?;
+        let anchor = *tmpl.anchors.first().ok_or(CPFPError::NoAnchor)?;
+        let anchor_out = &tmpl.outputs[anchor as usize];
+        let anchor_descriptor = anchor_out
+            .contract
+            .descriptor
+            .clone()
+            .ok_or(CPFPError::UnknownAnchorDescriptor)?;
+
+        let parent_fee = parent
+            .inputs
+            .iter()
+            .map(|i| i.witness_utxo.as_ref().map(|o| o.value))
+            .sum::<Option<u64>>()
+            .map(|v| Amount::from_sat(v.saturating_sub(parent_tx.total_amount().as_sat())))
+            .unwrap_or_else(|| tmpl.max - tmpl.total_amount());
+        let witness_size = obj.max_witness_size().unwrap_or(0);
+        let parent_vsize = tmpl.estimate_vsize(witness_size);
+
+        let mut inputs = vec![(
+            OutPoint {
+                txid: parent_tx.txid(),
+                vout: anchor,
+            },
+            anchor_out.amount,
+            anchor_descriptor,
+        )];
+        // a child of a TRUC parent must be TRUC too
+        let version = if parent_tx.version == 3 { 3 } else { 2 };
+        let mut utxos = utxos.into_iter();
+        let change_dust = dust_limit(&change);
+        // version, locktime, one output and the segwit marker and flag
+        let fixed_weight =
+            4 * (4 + 4 + 8 + (VarInt(change.len() as u64).len() + change.len()) as u64) + 2;
+        let mut inputs_weight = input_weight(&inputs[0].2)?;
+        loop {
+            let weight = fixed_weight
+                + 4 * (VarInt(inputs.len() as u64).len() + VarInt(1).len()) as u64
+                + inputs_weight;
+            let child_vsize = weight.div_ceil(4);
+            let fee = Amount::from_sat(package_feerate.as_sat() * (parent_vsize + child_vsize))
+                .checked_sub(parent_fee)
+                .unwrap_or(Amount::ZERO);
+            let needed = fee + change_dust;
+            let available = inputs
+                .iter()
+                .fold(Amount::ZERO, |acc, (_, amount, _)| acc + *amount);
+            if available >= needed {
+                return Self::make_cpfp_psbt(version, inputs, change, available - fee);
+            }
+            match utxos.next() {
+                Some(utxo) => {
+                    inputs_weight += input_weight(&utxo.2)?;
+                    inputs.push(utxo);
+                }
+                None => return Err(CPFPError::InsufficientFunds { needed, available }),
+            }
+        }
+    }
+
+    fn make_cpfp_psbt(
+        version: i32,
+        inputs: Vec<(OutPoint, Amount, Descriptor<PublicKey>)>,
+        change: Script,
+        change_amount: Amount,
+    ) -> Result<PartiallySignedTransaction, CPFPError> {
+        let tx = Transaction {
+            version,
+            lock_time: 0,
+            input: inputs
+                .iter()
+                .map(|(previous_output, _, _)| TxIn {
+                    previous_output: *previous_output,
+                    script_sig: Script::new(),
+                    // signal replaceability, so the child can be bumped too
+                    sequence: 0xffff_fffd,
+                    witness: vec![],
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: change_amount.as_sat(),
+                script_pubkey: change,
+            }],
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)?;
+        for (psbt_in, (_, amount, d)) in psbt.inputs.iter_mut().zip(inputs.iter()) {
+            psbt_in.witness_utxo = Some(TxOut {
+                value: amount.as_sat(),
+                script_pubkey: d.script_pubkey(),
+            });
+            if let Descriptor::Wsh(_) = d {
+                let script = d.explicit_script();
+                // anyone-can-spend anchors need no signature
+                if script == Script::from(vec![bitcoin::blockdata::opcodes::OP_TRUE.into_u8()]) {
+                    psbt_in.final_script_witness = Some(vec![script.into_bytes()]);
+                } else {
+                    psbt_in.witness_script = Some(script);
+                }
+            }
+        }
+        Ok(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::ThenFunc;
+    use crate::contract::test_util::{ctx, empty_address, key};
+    use crate::contract::{Compilable, Compiled, Context, DynamicContract, TxTmplIt};
+    use std::sync::Arc;
+    fn pay_with_anchor(_: &(), ctx: Context) -> TxTmplIt {
+        let address = empty_address(ctx.network);
+        ctx.template()
+            .add_output(
+                Amount::from_sat(5000),
+                &Compiled::from_address(address, None),
+                None,
+            )?
+            .add_anchor_output(None)?
+            .into()
+    }
+    #[test]
+    fn test_cpfp_psbt() {
+        let contract = DynamicContract::<(), ()> {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[],
+                    conditional_compile_if: &[],
+                    func: pay_with_anchor,
+                    name: Arc::new("pay".into()),
+                    weight: 1,
+                })
+            }],
+            finish_or: vec![],
+            finish: vec![],
+            data: (),
+        };
+        let compiled = contract.compile(ctx()).unwrap();
+        let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+        assert_eq!(tmpl.anchors, vec![1]);
+        let mut tx = tmpl.tx.clone();
+        tx.input[0].previous_output.vout = 7;
+        let parent = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+
+        let wallet = Descriptor::new_wpkh(key()).unwrap();
+        let utxo = |vout, sats| {
+            (
+                OutPoint {
+                    vout,
+                    ..Default::default()
+                },
+                Amount::from_sat(sats),
+                wallet.clone(),
+            )
+        };
+        let change = wallet.script_pubkey();
+        let rate = Amount::from_sat(10);
+        assert!(matches!(
+            compiled.cpfp_psbt(&parent, vec![utxo(0, 1000)], change.clone(), rate),
+            Err(CPFPError::InsufficientFunds { .. })
+        ));
+        let child = compiled
+            .cpfp_psbt(
+                &parent,
+                vec![utxo(0, 1000), utxo(1, 100_000)],
+                change.clone(),
+                rate,
+            )
+            .unwrap();
+        let child_tx = &child.global.unsigned_tx;
+        assert_eq!(child_tx.input.len(), 3);
+        assert_eq!(
+            child_tx.input[0].previous_output,
+            OutPoint {
+                txid: parent.global.unsigned_tx.txid(),
+                vout: 1
+            }
+        );
+        assert!(child.inputs[0].final_script_witness.is_some());
+        // the parent pays no fee, so the child pays for both
+        let anchor = tmpl.outputs[1].contract.descriptor.as_ref().unwrap();
+        let child_weight = 4 * (4 + 4 + 1 + 1 + 8 + 1 + change.len() as u64)
+            + 2
+            + input_weight(anchor).unwrap()
+            + 2 * input_weight(&wallet).unwrap();
+        let witness_size = *compiled.witness_size_estimates.values().max().unwrap();
+        let vsize = tmpl.estimate_vsize(witness_size) + child_weight.div_ceil(4);
+        let fee = tmpl.outputs[1].amount + Amount::from_sat(101_000)
+            - Amount::from_sat(child_tx.output[0].value);
+        assert_eq!(fee, Amount::from_sat(10 * vsize));
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Warnings about a contract found during compilation
+use crate::template::standardness::StandardnessViolation;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Why a branch was removed from a contract's policy
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PruneReason {
+    /// the branch's guard is `Clause::Unsatisfiable`
+    Unsatisfiable,
+    /// the branch is nullable and produced no templates
+    NoTemplates,
+}
+
+/// A problem with a contract which does not prevent it from compiling, but
+/// is likely to be a mistake.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Diagnostic {
+    /// A branch which can never be taken was removed from the policy
+    PrunedBranch {
+        /// the branch removed
+        path: SArc<EffectPath>,
+        /// why it can never be taken
+        reason: PruneReason,
+    },
+    /// A branch with exactly the same spending condition as an earlier one,
+    /// which only adds to the script's size
+    DuplicateGuard {
+        /// the redundant branch
+        path: SArc<EffectPath>,
+        /// the earlier branch
+        duplicate_of: SArc<EffectPath>,
+    },
+    /// A template output which is below the dust limit, and so won't be
+    /// relayed
+    DustOutput {
+        /// the branch which created the template
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+        /// the index of the output in the template
+        index: usize,
+        /// the amount of the output
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        amount: Amount,
+        /// the smallest amount the output could have
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        dust_limit: Amount,
+    },
+    /// A template which breaks the `StandardnessPolicy` in some other way,
+    /// and so won't be relayed
+    NonStandard {
+        /// the branch which created the template
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+        /// how the template breaks the policy
+        violation: StandardnessViolation,
+    },
+}
+
+impl Diagnostic {
+    /// The warning for `violation` by `template`, created by `path`
+    pub(crate) fn non_standard(
+        path: SArc<EffectPath>,
+        template: sha256::Hash,
+        violation: StandardnessViolation,
+    ) -> Self {
+        match violation {
+            StandardnessViolation::Dust {
+                index,
+                amount,
+                dust_limit,
+            } => Diagnostic::DustOutput {
+                path,
+                template,
+                index,
+                amount,
+                dust_limit,
+            },
+            violation => Diagnostic::NonStandard {
+                path,
+                template,
+                violation,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |p: &SArc<EffectPath>| String::from(p.0.as_ref().clone());
+        match self {
+            Diagnostic::PrunedBranch { path, reason } => {
+                let why = match reason {
+                    PruneReason::Unsatisfiable => "its guard is unsatisfiable",
+                    PruneReason::NoTemplates => "it produced no templates",
+                };
+                write!(f, "{}: branch pruned as {}", show(path), why)
+            }
+            Diagnostic::DuplicateGuard { path, duplicate_of } => write!(
+                f,
+                "{}: branch has the same spending condition as {}",
+                show(path),
+                show(duplicate_of)
+            ),
+            Diagnostic::DustOutput {
+                path,
+                template,
+                index,
+                amount,
+                dust_limit,
+            } => write!(
+                f,
+                "{}: output {} of template {} has {}, below the dust limit of {}",
+                show(path),
+                index,
+                template,
+                amount,
+                dust_limit
+            ),
+            Diagnostic::NonStandard {
+                path,
+                template,
+                violation,
+            } => write!(
+                f,
+                "{}: template {} is not standard, {}",
+                show(path),
+                template,
+                violation
+            ),
+        }
+    }
+}
+
+/// The smallest value an output with `script` may have and still be relayed,
+/// at Bitcoin Core's default dust relay fee of 3 sats/vbyte.
+pub(crate) fn dust_limit(script: &Script) -> Amount {
+    dust_limit_at(script, Amount::from_sat(3))
+}
+
+/// The smallest value an output with `script` may have and still be relayed,
+/// at a dust relay fee of `dust_relay_feerate` sats/vbyte.
+pub(crate) fn dust_limit_at(script: &Script, dust_relay_feerate: Amount) -> Amount {
+    if script.is_op_return() {
+        return Amount::ZERO;
+    }
+    let output_size = 8 + VarInt(script.len() as u64).len() + script.len();
+    // the size of the input needed to spend the output, discounting witness
+    // data
+    let spend_size = if script.is_witness_program() { 67 } else { 148 };
+    dust_relay_feerate * (output_size + spend_size) as u64
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    #[test]
+    fn test_dust_limit() {
+        let wpkh = Script::new_v0_wpkh(&bitcoin::WPubkeyHash::from_inner([0; 20]));
+        let wsh = Script::new_v0_wsh(&bitcoin::WScriptHash::from_inner([0; 32]));
+        let pkh = Script::new_p2pkh(&bitcoin::PubkeyHash::from_inner([0; 20]));
+        assert_eq!(dust_limit(&wpkh), Amount::from_sat(294));
+        assert_eq!(dust_limit(&wsh), Amount::from_sat(330));
+        assert_eq!(dust_limit(&pkh), Amount::from_sat(546));
+        assert_eq!(dust_limit(&Script::new_op_return(&[])), Amount::ZERO);
+    }
+}
@@ -0,0 +1,498 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Structural comparison of two compiled contracts
+use super::object::Object;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::Script;
+use sapio_base::effects::{EffectPath, PathFragment};
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::Arc;
+
+/// A single difference between two `Object`s. `path` is always the path of
+/// the contract the difference was found in.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    /// The contract's policy is different
+    PolicyChanged {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the old policy
+        from: Option<Clause>,
+        /// the new policy
+        to: Option<Clause>,
+    },
+    /// A template is only in the new contract
+    TemplateAdded {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+    },
+    /// A template is only in the old contract
+    TemplateRemoved {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's hash
+        template: sha256::Hash,
+    },
+    /// The nLockTime (if `input` is None) or an input's nSequence of a
+    /// template is different
+    TimelockChanged {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the new template's hash
+        template: sha256::Hash,
+        /// the input whose sequence changed, if any
+        input: Option<usize>,
+        /// the old value
+        from: u32,
+        /// the new value
+        to: u32,
+    },
+    /// An output is only in the new template
+    OutputAdded {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the new template's hash
+        template: sha256::Hash,
+        /// the index of the output
+        index: usize,
+    },
+    /// An output is only in the old template
+    OutputRemoved {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the new template's hash
+        template: sha256::Hash,
+        /// the index of the output
+        index: usize,
+    },
+    /// An output sends a different amount
+    AmountChanged {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the new template's hash
+        template: sha256::Hash,
+        /// the index of the output
+        index: usize,
+        /// the old amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        from: Amount,
+        /// the new amount
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        to: Amount,
+    },
+    /// An output pays to a different script
+    ScriptChanged {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the new template's hash
+        template: sha256::Hash,
+        /// the index of the output
+        index: usize,
+        /// the old script
+        from: Script,
+        /// the new script
+        to: Script,
+    },
+    /// A continuation point was added, removed, or takes different arguments
+    ContinuationChanged {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the continuation point
+        continuation: SArc<EffectPath>,
+        /// the old schema, None if the continuation point was added
+        from: Option<Option<SArc<RootSchema>>>,
+        /// the new schema, None if the continuation point was removed
+        to: Option<Option<SArc<RootSchema>>>,
+    },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |p: &SArc<EffectPath>| String::from(p.0.as_ref().clone());
+        let policy = |c: &Option<Clause>| {
+            c.as_ref()
+                .map(|c| c.to_string())
+                .unwrap_or_else(|| "none".into())
+        };
+        match self {
+            Change::PolicyChanged { path, from, to } => write!(
+                f,
+                "{}: policy changed from {} to {}",
+                show(path),
+                policy(from),
+                policy(to)
+            ),
+            Change::TemplateAdded { path, template } => {
+                write!(f, "{}: template {} added", show(path), template)
+            }
+            Change::TemplateRemoved { path, template } => {
+                write!(f, "{}: template {} removed", show(path), template)
+            }
+            Change::TimelockChanged {
+                path,
+                template,
+                input: None,
+                from,
+                to,
+            } => write!(
+                f,
+                "{}: template {} lock_time changed from {} to {}",
+                show(path),
+                template,
+                from,
+                to
+            ),
+            Change::TimelockChanged {
+                path,
+                template,
+                input: Some(input),
+                from,
+                to,
+            } => write!(
+                f,
+                "{}: template {} input {} sequence changed from {} to {}",
+                show(path),
+                template,
+                input,
+                from,
+                to
+            ),
+            Change::OutputAdded {
+                path,
+                template,
+                index,
+            } => write!(
+                f,
+                "{}: template {} output {} added",
+                show(path),
+                template,
+                index
+            ),
+            Change::OutputRemoved {
+                path,
+                template,
+                index,
+            } => write!(
+                f,
+                "{}: template {} output {} removed",
+                show(path),
+                template,
+                index
+            ),
+            Change::AmountChanged {
+                path,
+                template,
+                index,
+                from,
+                to,
+            } => write!(
+                f,
+                "{}: template {} output {} amount changed from {} to {}",
+                show(path),
+                template,
+                index,
+                from,
+                to
+            ),
+            Change::ScriptChanged {
+                path,
+                template,
+                index,
+                from,
+                to,
+            } => write!(
+                f,
+                "{}: template {} output {} script changed from {} to {}",
+                show(path),
+                template,
+                index,
+                from,
+                to
+            ),
+            Change::ContinuationChanged {
+                path,
+                continuation,
+                from,
+                to,
+            } => {
+                let what = match (from, to) {
+                    (None, _) => "added",
+                    (_, None) => "removed",
+                    _ => "schema changed",
+                };
+                write!(
+                    f,
+                    "{}: continuation {} {}",
+                    show(path),
+                    show(continuation),
+                    what
+                )
+            }
+        }
+    }
+}
+
+/// The differences between two `Object`s. See `Object::diff`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct ObjectDiff {
+    /// every difference found
+    pub changes: Vec<Change>,
+}
+
+impl ObjectDiff {
+    /// true if no differences were found
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for ObjectDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in self.changes.iter() {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+/// The path of the branch which created `tmpl`, inferred from the paths of
+/// the contracts it creates. Contracts not compiled from a path (e.g. an
+/// address) have a root path of length one, so are ignored.
+fn branch_of(tmpl: &Template) -> Option<Arc<EffectPath>> {
+    tmpl.outputs.iter().find_map(|out| {
+        let mut v: Vec<PathFragment> = out.contract.root_path.0.as_ref().clone().into();
+        if v.len() < 2 {
+            return None;
+        }
+        v.pop();
+        v.into_iter()
+            .fold(None, |acc, frag| Some(EffectPath::push(acc, frag)))
+    })
+}
+
+impl Object {
+    /// Compare this contract to `other`, contract by contract.
+    ///
+    /// Templates are matched by hash, then by the branch which created them
+    /// (as far as it can be inferred), and finally, if only one template on
+    /// each side remains unmatched, with each other. Matched templates are
+    /// compared output by output, and the contracts they create are compared
+    /// in turn.
+    pub fn diff(&self, other: &Object) -> ObjectDiff {
+        let mut diff = ObjectDiff::default();
+        let mut stack = vec![(self, other)];
+        while let Some((a, b)) = stack.pop() {
+            let path = &b.root_path;
+            if a.policy != b.policy {
+                diff.changes.push(Change::PolicyChanged {
+                    path: path.clone(),
+                    from: a.policy.clone(),
+                    to: b.policy.clone(),
+                });
+            }
+            let continuations: BTreeSet<String> = a
+                .continue_apis
+                .keys()
+                .chain(b.continue_apis.keys())
+                .map(|k| k.0.as_ref().clone().into())
+                .collect();
+            for k in continuations {
+                let find = |o: &Object| {
+                    o.continue_apis
+                        .iter()
+                        .find(|(p, _)| String::from(p.0.as_ref().clone()) == k)
+                        .map(|(p, c)| (p.clone(), c.schema.clone()))
+                };
+                let (from, to) = (find(a), find(b));
+                if from.as_ref().map(|c| &c.1) != to.as_ref().map(|c| &c.1) {
+                    let continuation = to
+                        .as_ref()
+                        .or(from.as_ref())
+                        .map(|c| c.0.clone())
+                        .expect("Must be in at least one Object");
+                    diff.changes.push(Change::ContinuationChanged {
+                        path: path.clone(),
+                        continuation,
+                        from: from.map(|c| c.1),
+                        to: to.map(|c| c.1),
+                    });
+                }
+            }
+            let (mut removed, mut added) = (sorted_templates(a), sorted_templates(b));
+            let mut pairs = vec![];
+            // match by hash
+            removed.retain(|t| match added.iter().position(|u| u.hash() == t.hash()) {
+                Some(i) => {
+                    pairs.push((*t, added.remove(i)));
+                    false
+                }
+                None => true,
+            });
+            // match by branch
+            removed.retain(|t| {
+                let branch = branch_of(t);
+                match added
+                    .iter()
+                    .position(|u| branch.is_some() && branch_of(u) == branch)
+                {
+                    Some(i) => {
+                        pairs.push((*t, added.remove(i)));
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if removed.len() == 1 && added.len() == 1 {
+                pairs.push((removed.remove(0), added.remove(0)));
+            }
+            for t in removed {
+                diff.changes.push(Change::TemplateRemoved {
+                    path: path.clone(),
+                    template: t.hash(),
+                });
+            }
+            for t in added {
+                diff.changes.push(Change::TemplateAdded {
+                    path: path.clone(),
+                    template: t.hash(),
+                });
+            }
+            for (t, u) in pairs {
+                diff_template(path, t, u, &mut diff);
+                stack.extend(
+                    t.outputs
+                        .iter()
+                        .zip(u.outputs.iter())
+                        .map(|(x, y)| (&x.contract, &y.contract)),
+                );
+            }
+        }
+        diff
+    }
+}
+
+/// All of the templates of `o`, sorted by hash
+fn sorted_templates(o: &Object) -> Vec<&Template> {
+    let mut v: Vec<_> = o
+        .ctv_to_tx
+        .values()
+        .chain(o.suggested_txs.values())
+        .collect();
+    v.sort_by_key(|t| t.hash());
+    v
+}
+
+/// Compare the timelocks and outputs of two matched templates
+fn diff_template(path: &SArc<EffectPath>, t: &Template, u: &Template, diff: &mut ObjectDiff) {
+    let template = u.hash();
+    if t.tx.lock_time != u.tx.lock_time {
+        diff.changes.push(Change::TimelockChanged {
+            path: path.clone(),
+            template,
+            input: None,
+            from: t.tx.lock_time,
+            to: u.tx.lock_time,
+        });
+    }
+    for (input, (x, y)) in t.tx.input.iter().zip(u.tx.input.iter()).enumerate() {
+        if x.sequence != y.sequence {
+            diff.changes.push(Change::TimelockChanged {
+                path: path.clone(),
+                template,
+                input: Some(input),
+                from: x.sequence,
+                to: y.sequence,
+            });
+        }
+    }
+    for (index, (x, y)) in t.tx.output.iter().zip(u.tx.output.iter()).enumerate() {
+        if x.value != y.value {
+            diff.changes.push(Change::AmountChanged {
+                path: path.clone(),
+                template,
+                index,
+                from: Amount::from_sat(x.value),
+                to: Amount::from_sat(y.value),
+            });
+        }
+        if x.script_pubkey != y.script_pubkey {
+            diff.changes.push(Change::ScriptChanged {
+                path: path.clone(),
+                template,
+                index,
+                from: x.script_pubkey.clone(),
+                to: y.script_pubkey.clone(),
+            });
+        }
+    }
+    for index in u.tx.output.len()..t.tx.output.len() {
+        diff.changes.push(Change::OutputRemoved {
+            path: path.clone(),
+            template,
+            index,
+        });
+    }
+    for index in t.tx.output.len()..u.tx.output.len() {
+        diff.changes.push(Change::OutputAdded {
+            path: path.clone(),
+            template,
+            index,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{ctx, key_at};
+    use crate::contract::Compilable;
+    #[test]
+    fn test_diff() {
+        let make = |amt: u64, lock: u32| {
+            let mut tmpl: Template = ctx()
+                .template()
+                .add_output(Amount::from_sat(amt), &key_at(2), None)
+                .unwrap()
+                .into();
+            tmpl.tx.lock_time = lock;
+            let mut obj = key_at(1).compile(ctx()).unwrap();
+            obj.ctv_to_tx.insert(tmpl.hash(), tmpl);
+            obj
+        };
+        let a = make(5000, 0);
+        assert!(a.diff(&a).is_empty());
+        let b = make(6000, 100);
+        let d = a.diff(&b);
+        assert_eq!(d.changes.len(), 2);
+        assert!(matches!(
+            d.changes[0],
+            Change::TimelockChanged {
+                input: None,
+                from: 0,
+                to: 100,
+                ..
+            }
+        ));
+        assert!(matches!(
+            d.changes[1],
+            Change::AmountChanged { index: 0, .. }
+        ));
+    }
+}
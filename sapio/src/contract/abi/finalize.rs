@@ -0,0 +1,162 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finalizing PSBTs which spend contracts into broadcastable transactions
+use super::object::Object;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d};
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use miniscript::psbt::PsbtInputSatisfier;
+use miniscript::{BitcoinSig, Descriptor, DescriptorTrait, Preimage32, Satisfier};
+use sapio_base::CTVHash;
+
+/// Satisfies an input of a PSBT with its signatures and preimages, the
+/// transaction's locktimes, and the transaction's own template hash, which
+/// `PsbtInputSatisfier` does not check.
+struct TemplateSatisfier<'a> {
+    psbt: PsbtInputSatisfier<'a>,
+    template: sha256::Hash,
+}
+
+impl Satisfier<bitcoin::PublicKey> for TemplateSatisfier<'_> {
+    fn lookup_sig(&self, k: &bitcoin::PublicKey) -> Option<BitcoinSig> {
+        Satisfier::<bitcoin::PublicKey>::lookup_sig(&self.psbt, k)
+    }
+    fn lookup_pkh_sig(&self, h: &hash160::Hash) -> Option<(bitcoin::PublicKey, BitcoinSig)> {
+        Satisfier::<bitcoin::PublicKey>::lookup_pkh_sig(&self.psbt, h)
+    }
+    fn lookup_sha256(&self, h: sha256::Hash) -> Option<Preimage32> {
+        Satisfier::<bitcoin::PublicKey>::lookup_sha256(&self.psbt, h)
+    }
+    fn lookup_hash256(&self, h: sha256d::Hash) -> Option<Preimage32> {
+        Satisfier::<bitcoin::PublicKey>::lookup_hash256(&self.psbt, h)
+    }
+    fn lookup_ripemd160(&self, h: ripemd160::Hash) -> Option<Preimage32> {
+        Satisfier::<bitcoin::PublicKey>::lookup_ripemd160(&self.psbt, h)
+    }
+    fn lookup_hash160(&self, h: hash160::Hash) -> Option<Preimage32> {
+        Satisfier::<bitcoin::PublicKey>::lookup_hash160(&self.psbt, h)
+    }
+    fn check_older(&self, t: u32) -> bool {
+        Satisfier::<bitcoin::PublicKey>::check_older(&self.psbt, t)
+    }
+    fn check_after(&self, t: u32) -> bool {
+        Satisfier::<bitcoin::PublicKey>::check_after(&self.psbt, t)
+    }
+    fn check_tx_template(&self, h: sha256::Hash) -> bool {
+        h == self.template
+    }
+}
+
+/// Finalize input `index` of `psbt`, which spends `descriptor`, using the
+/// signatures and preimages in the input. This needs no signatures at all
+/// for a branch of only the transaction's template hash and timelocks.
+///
+/// Fails, leaving the input as it was, if it can't yet be satisfied.
+pub fn finalize_input(
+    psbt: &mut PartiallySignedTransaction,
+    index: usize,
+    descriptor: &Descriptor<bitcoin::PublicKey>,
+) -> Result<(), miniscript::Error> {
+    let satisfier = TemplateSatisfier {
+        template: psbt.global.unsigned_tx.get_ctv_hash(index as u32),
+        psbt: PsbtInputSatisfier::new(psbt, index),
+    };
+    // pass the satisfier by value: miniscript's impl for `&S` does not
+    // forward `check_tx_template`
+    let (witness, script_sig) = descriptor.get_satisfaction(satisfier)?;
+    let input = &mut psbt.inputs[index];
+    input.final_script_witness = Some(witness).filter(|w| !w.is_empty());
+    input.final_script_sig = Some(script_sig).filter(|s| !s.is_empty());
+    // a finalized input keeps only what's needed to extract and check it
+    input.partial_sigs.clear();
+    input.sighash_type = None;
+    input.redeem_script = None;
+    input.witness_script = None;
+    input.bip32_derivation.clear();
+    input.ripemd160_preimages.clear();
+    input.sha256_preimages.clear();
+    input.hash160_preimages.clear();
+    input.hash256_preimages.clear();
+    Ok(())
+}
+
+/// The inputs of `psbt` which are not finalized, i.e. still need signatures
+/// (or anything else needed to satisfy them)
+pub fn unfinalized_inputs(psbt: &PartiallySignedTransaction) -> Vec<usize> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, i)| i.final_script_witness.is_none() && i.final_script_sig.is_none())
+        .map(|(index, _)| index)
+        .collect()
+}
+
+impl Object {
+    /// Finalize the input of `psbt`, a transaction of one of this contract's
+    /// templates (or a template of a contract it creates), which spends the
+    /// contract, if its signatures and preimages are sufficient. Returns the
+    /// inputs still needing signatures.
+    pub fn finalize_psbt(&self, psbt: &mut PartiallySignedTransaction) -> Vec<usize> {
+        if let Some((obj, tmpl)) = self.find_template(&psbt.global.unsigned_tx) {
+            if let Some(d) = &obj.descriptor {
+                // an unsatisfiable input is reported below
+                let _ = finalize_input(psbt, tmpl.ctv_index as usize, d);
+            }
+        }
+        unfinalized_inputs(psbt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::test_util::{ctx, key, pay_branch, pay_contract, secret};
+    use crate::contract::{Compilable, DynamicContract};
+    use bitcoin::blockdata::transaction::SigHashType;
+    use bitcoin::secp256k1::{Message, Secp256k1};
+    use bitcoin::util::bip143::SigHashCache;
+    use sapio_base::Clause;
+    /// pays out with CTV alone, or with CTV and a signature by `key`
+    fn contract(signed: bool) -> DynamicContract<'static, (), ()> {
+        if !signed {
+            return pay_contract();
+        }
+        DynamicContract {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[|| Some(Guard::Fresh(|_, _| Clause::Key(key()), 1))],
+                    ..pay_branch()?
+                })
+            }],
+            ..pay_contract()
+        }
+    }
+    #[test]
+    fn test_finalize_psbt() {
+        for signed in [false, true] {
+            let compiled = contract(signed).compile(ctx()).unwrap();
+            let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+            let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tmpl.tx.clone()).unwrap();
+            if !signed {
+                assert!(compiled.finalize_psbt(&mut psbt).is_empty());
+                assert!(psbt.inputs[0].final_script_witness.is_some());
+                continue;
+            }
+            assert_eq!(compiled.finalize_psbt(&mut psbt), vec![0]);
+            // once signed, it can be finalized
+            let script = compiled.descriptor.as_ref().unwrap().explicit_script();
+            let sighash =
+                SigHashCache::new(&tmpl.tx).signature_hash(0, &script, 10000, SigHashType::All);
+            let sig = Secp256k1::new().sign(&Message::from_slice(&sighash[..]).unwrap(), &secret());
+            let mut raw = sig.serialize_der().to_vec();
+            raw.push(SigHashType::All.as_u32() as u8);
+            psbt.inputs[0].partial_sigs.insert(key(), raw);
+            assert!(compiled.finalize_psbt(&mut psbt).is_empty());
+            assert!(psbt.inputs[0].partial_sigs.is_empty());
+        }
+    }
+}
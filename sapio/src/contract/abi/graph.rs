@@ -0,0 +1,551 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Diagrams of compiled or bound contracts, as Graphviz DOT or Mermaid
+use super::object::Object;
+use super::spend::visit;
+use super::studio::{Program, SapioStudioFormat};
+use super::timeline::{Earliest, SEQUENCE_TYPE};
+use crate::template::TemplateMetadata;
+use bitcoin::hashes::sha256;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Transaction, Txid};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Errors that can arise when drawing a bound `Program`
+#[derive(Debug)]
+pub enum GraphError {
+    /// A PSBT was not valid base64
+    Base64(base64::DecodeError),
+    /// A PSBT could not be decoded
+    Decode(bitcoin::consensus::encode::Error),
+}
+impl std::error::Error for GraphError {}
+impl From<base64::DecodeError> for GraphError {
+    fn from(e: base64::DecodeError) -> Self {
+        GraphError::Base64(e)
+    }
+}
+impl From<bitcoin::consensus::encode::Error> for GraphError {
+    fn from(e: bitcoin::consensus::encode::Error) -> Self {
+        GraphError::Decode(e)
+    }
+}
+impl fmt::Display for GraphError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    Contract,
+    Template,
+    Continuation,
+}
+
+struct Node {
+    id: String,
+    kind: NodeKind,
+    label: Vec<String>,
+    color: Option<String>,
+}
+
+struct Edge {
+    from: String,
+    to: String,
+    label: String,
+    dashed: bool,
+}
+
+/// A diagram of a contract: contracts, templates and continuation points are
+/// nodes, and edges are labelled with the amount sent to a contract, or the
+/// timelocks and guards of spending a contract with a template.
+/// Continuation points are joined to their contract by dashed edges.
+///
+/// Render it with `Graph::to_dot` or `Graph::to_mermaid`.
+#[derive(Default)]
+pub struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+fn path_string(p: &SArc<EffectPath>) -> String {
+    String::from(p.0.as_ref().clone())
+}
+
+/// The timelocks of spending input `index` with `tx`, if any
+fn timelock_summary(tx: &Transaction, index: u32) -> Option<String> {
+    let earliest = Earliest::default().then(tx, index);
+    let mut parts = vec![];
+    if earliest.relative_blocks > 0 {
+        parts.push(format!("+{} blocks", earliest.relative_blocks));
+    }
+    if earliest.relative_seconds > 0 {
+        parts.push(format!("+{}s", earliest.relative_seconds));
+    }
+    if let Some(h) = earliest.height_lock {
+        parts.push(format!("height >= {}", h));
+    }
+    if let Some(t) = earliest.time_lock {
+        parts.push(format!("time > {}", t));
+    }
+    Some(parts.join(", ")).filter(|s| !s.is_empty())
+}
+
+/// What a branch requires besides its timelocks, e.g. `2 sigs + CTV`
+fn guard_summary(c: &Clause) -> String {
+    summarize(c).unwrap_or_else(|| "anyone".into())
+}
+
+/// `guard_summary` of one of a branch's alternatives, e.g. `1 sig + after 10
+/// blocks`. Unlike those of the branch as a whole, an alternative's timelocks
+/// aren't shown with its template, so are included here.
+fn alternative_summary(c: &Clause) -> String {
+    let conjuncts = match c {
+        Clause::And(v) => &v[..],
+        c => std::slice::from_ref(c),
+    };
+    let parts: Vec<String> = summarize(c)
+        .into_iter()
+        .chain(conjuncts.iter().filter_map(|c| match c {
+            Clause::Older(t) if t & SEQUENCE_TYPE != 0 => {
+                Some(format!("after {}s", (t & 0xffff) * 512))
+            }
+            Clause::Older(t) => Some(format!("after {} blocks", t & 0xffff)),
+            Clause::After(t) if *t < 500_000_000 => Some(format!("after height {}", t)),
+            Clause::After(t) => Some(format!("after time {}", t)),
+            _ => None,
+        }))
+        .collect();
+    if parts.is_empty() {
+        "anyone".into()
+    } else {
+        parts.join(" + ")
+    }
+}
+
+/// `guard_summary` of `c`, or None if it needs nothing but timelocks
+fn summarize(c: &Clause) -> Option<String> {
+    let plural = |n| if n == 1 { "" } else { "s" };
+    match c {
+        Clause::Trivial | Clause::After(_) | Clause::Older(_) => None,
+        Clause::Unsatisfiable => Some("never".into()),
+        Clause::Threshold(k, v) if v.iter().all(|c| matches!(c, Clause::Key(_))) => {
+            Some(format!("{}-of-{} sigs", k, v.len()))
+        }
+        Clause::Threshold(k, v) => {
+            let v: Vec<String> = v.iter().map(alternative_summary).collect();
+            Some(format!("{} of ({})", k, v.join(", ")))
+        }
+        Clause::Or(v) => {
+            let v: Vec<String> = v.iter().map(|(_, c)| alternative_summary(c)).collect();
+            Some(v.join(" or "))
+        }
+        c => {
+            let (mut keys, mut preimages, mut ctv) = (0, 0, false);
+            let mut others = vec![];
+            let conjuncts = match c {
+                Clause::And(v) => &v[..],
+                c => std::slice::from_ref(c),
+            };
+            for c in conjuncts {
+                match c {
+                    Clause::Key(_) => keys += 1,
+                    Clause::Sha256(_)
+                    | Clause::Hash256(_)
+                    | Clause::Ripemd160(_)
+                    | Clause::Hash160(_) => preimages += 1,
+                    Clause::TxTemplate(_) => ctv = true,
+                    c => others.extend(summarize(c).map(|s| format!("({})", s))),
+                }
+            }
+            let mut parts = vec![];
+            if keys > 0 {
+                parts.push(format!("{} sig{}", keys, plural(keys)));
+            }
+            if preimages > 0 {
+                parts.push(format!("{} preimage{}", preimages, plural(preimages)));
+            }
+            if ctv {
+                parts.push("CTV".into());
+            }
+            parts.extend(others);
+            if parts.is_empty() {
+                None
+            } else {
+                Some(parts.join(" + "))
+            }
+        }
+    }
+}
+
+impl Graph {
+    fn add_node(
+        &mut self,
+        kind: NodeKind,
+        label: Vec<String>,
+        metadata: Option<&TemplateMetadata>,
+    ) -> String {
+        let prefix = match kind {
+            NodeKind::Contract => "c",
+            NodeKind::Template => "t",
+            NodeKind::Continuation => "k",
+        };
+        let id = format!("{}{}", prefix, self.nodes.len());
+        let mut label = label;
+        if let Some(l) = metadata.and_then(|m| m.label.clone()) {
+            label.insert(0, l);
+        }
+        self.nodes.push(Node {
+            id: id.clone(),
+            kind,
+            label,
+            color: metadata.and_then(|m| m.color.clone()),
+        });
+        id
+    }
+    fn add_edge(&mut self, from: &str, to: &str, label: String, dashed: bool) {
+        self.edges.push(Edge {
+            from: from.into(),
+            to: to.into(),
+            label,
+            dashed,
+        });
+    }
+    fn add_continuations<'a>(
+        &mut self,
+        from: &str,
+        paths: impl Iterator<Item = &'a SArc<EffectPath>>,
+    ) {
+        let mut paths: Vec<_> = paths.map(path_string).collect();
+        paths.sort();
+        for p in paths {
+            let id = self.add_node(NodeKind::Continuation, vec![p], None);
+            self.add_edge(from, &id, "continue".into(), true);
+        }
+    }
+
+    fn add_object(&mut self, obj: &Object) -> String {
+        let mut label = vec![path_string(&obj.root_path)];
+        if obj.ctv_to_tx.is_empty() && obj.suggested_txs.is_empty() {
+            label.push(obj.address.to_string());
+        }
+        let id = self.add_node(NodeKind::Contract, label, None);
+        let mut templates: Vec<_> = obj
+            .ctv_to_tx
+            .iter()
+            .chain(obj.suggested_txs.iter())
+            .collect();
+        // HashMap order is random, so sort for a stable output
+        templates.sort_by_key(|(h, _)| *h);
+        let mut branches: Vec<_> = obj.branches.iter().collect();
+        branches.sort_by_key(|(p, _)| path_string(p));
+        for (h, tmpl) in templates {
+            let t = self.add_node(
+                NodeKind::Template,
+                vec![h.to_string()[..16].into()],
+                Some(&tmpl.metadata_map_s2s),
+            );
+            let mut label: Vec<String> = branches
+                .iter()
+                .filter(|(_, c)| Self::commits_to(c, h))
+                .map(|(p, c)| {
+                    let name = p.0.iter().next().map(String::from).unwrap_or_default();
+                    format!("{}: {}", name, guard_summary(c))
+                })
+                .collect();
+            if label.is_empty() {
+                label.push("suggested".into());
+            }
+            label.extend(timelock_summary(&tmpl.tx, tmpl.ctv_index));
+            self.add_edge(&id, &t, label.join("; "), false);
+            for out in tmpl.outputs.iter() {
+                let c = self.add_object(&out.contract);
+                self.add_edge(&t, &c, out.amount.to_string(), false);
+            }
+        }
+        self.add_continuations(&id, obj.continue_apis.keys());
+        id
+    }
+
+    fn commits_to(c: &Clause, h: &sha256::Hash) -> bool {
+        let mut found = false;
+        visit(c, &mut |c| found |= *c == Clause::TxTemplate(*h));
+        found
+    }
+
+    fn add_program(&mut self, program: &Program) -> Result<(), GraphError> {
+        let mut objects: Vec<_> = program.program.iter().collect();
+        objects.sort_by_key(|(p, _)| path_string(p));
+        let mut txs: HashMap<Txid, (String, Transaction)> = HashMap::new();
+        let mut order = vec![];
+        let mut contracts = vec![];
+        for (path, obj) in objects {
+            // the outputs of contracts with no transactions are drawn below
+            if obj.txs.is_empty() && obj.continue_apis.is_empty() {
+                continue;
+            }
+            let id = self.add_node(NodeKind::Contract, vec![path_string(path)], None);
+            let mut spends = vec![];
+            for SapioStudioFormat::LinkedPSBT { psbt, metadata, .. } in obj.txs.iter() {
+                let psbt: PartiallySignedTransaction =
+                    bitcoin::consensus::deserialize(&base64::decode(psbt)?)?;
+                let tx = psbt.extract_tx();
+                let txid = tx.txid();
+                let t = self.add_node(
+                    NodeKind::Template,
+                    vec![txid.to_string()[..16].into()],
+                    Some(metadata),
+                );
+                spends.push((t.clone(), tx.clone()));
+                txs.insert(txid, (t, tx));
+                order.push(txid);
+            }
+            self.add_continuations(&id, obj.continue_apis.keys());
+            contracts.push((id, spends));
+        }
+        // the contract's output is the one its transactions spend from
+        // another transaction in the program
+        let mut spent = HashSet::new();
+        for (id, spends) in contracts {
+            let mut funded_by = None;
+            for (t, tx) in spends {
+                let parent = tx
+                    .input
+                    .iter()
+                    .enumerate()
+                    .find_map(|(i, inp)| Some((i, inp, txs.get(&inp.previous_output.txid)?)));
+                let index = parent.map_or(0, |(i, _, _)| i as u32);
+                let label = timelock_summary(&tx, index).unwrap_or_default();
+                self.add_edge(&id, &t, label, false);
+                if let Some((_, inp, (p, ptx))) = parent {
+                    let out = &ptx.output[inp.previous_output.vout as usize];
+                    funded_by = Some((p.clone(), out.value));
+                    spent.insert(inp.previous_output);
+                }
+            }
+            if let Some((p, value)) = funded_by {
+                let amount = bitcoin::Amount::from_sat(value).to_string();
+                self.add_edge(&p, &id, amount, false);
+            }
+        }
+        for txid in order {
+            let (t, tx) = &txs[&txid];
+            for (vout, out) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                if spent.contains(&outpoint) {
+                    continue;
+                }
+                let label = format!("{}:{}", &txid.to_string()[..16], vout);
+                let c = self.add_node(NodeKind::Contract, vec![label], None);
+                let amount = bitcoin::Amount::from_sat(out.value).to_string();
+                self.add_edge(t, &c, amount, false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Render as a Graphviz DOT digraph
+    pub fn to_dot(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::from("digraph sapio {\n");
+        for n in self.nodes.iter() {
+            let shape = match n.kind {
+                NodeKind::Contract => "ellipse",
+                NodeKind::Template => "box",
+                NodeKind::Continuation => "hexagon",
+            };
+            let label: Vec<String> = n.label.iter().map(|l| escape(l)).collect();
+            out += &format!(
+                "    {} [shape={}, label=\"{}\"",
+                n.id,
+                shape,
+                label.join("\\n")
+            );
+            if let Some(c) = &n.color {
+                out += &format!(", style=filled, fillcolor=\"{}\"", escape(c));
+            }
+            out += "];\n";
+        }
+        for e in self.edges.iter() {
+            out += &format!(
+                "    {} -> {} [label=\"{}\"{}];\n",
+                e.from,
+                e.to,
+                escape(&e.label),
+                if e.dashed { ", style=dashed" } else { "" }
+            );
+        }
+        out += "}\n";
+        out
+    }
+
+    /// Render as a Mermaid flowchart
+    pub fn to_mermaid(&self) -> String {
+        let escape = |s: &str| s.replace('"', "#quot;");
+        let mut out = String::from("flowchart TD\n");
+        for n in self.nodes.iter() {
+            let label: Vec<String> = n.label.iter().map(|l| escape(l)).collect();
+            let label = label.join("<br/>");
+            out += &match n.kind {
+                NodeKind::Contract => format!("    {}([\"{}\"])\n", n.id, label),
+                NodeKind::Template => format!("    {}[\"{}\"]\n", n.id, label),
+                NodeKind::Continuation => format!("    {}{{{{\"{}\"}}}}\n", n.id, label),
+            };
+            // style values can't be quoted, so only pass through colors
+            // which are plainly a name or hex code
+            if let Some(c) = n
+                .color
+                .as_ref()
+                .filter(|c| c.chars().all(|c| c.is_ascii_alphanumeric() || c == '#'))
+            {
+                out += &format!("    style {} fill:{}\n", n.id, c);
+            }
+        }
+        for e in self.edges.iter() {
+            let arrow = if e.dashed { "-.->" } else { "-->" };
+            if e.label.is_empty() {
+                out += &format!("    {} {} {}\n", e.from, arrow, e.to);
+            } else {
+                out += &format!(
+                    "    {} {}|\"{}\"| {}\n",
+                    e.from,
+                    arrow,
+                    escape(&e.label),
+                    e.to
+                );
+            }
+        }
+        out
+    }
+}
+
+impl Object {
+    /// A diagram of this contract and every contract it creates
+    pub fn graph(&self) -> Graph {
+        let mut g = Graph::default();
+        g.add_object(self);
+        g
+    }
+}
+
+impl Program {
+    /// A diagram of the bound transactions, e.g. from `Object::bind_psbt`.
+    /// The contracts' policies aren't known here, so edges show only
+    /// amounts and timelocks.
+    pub fn graph(&self) -> Result<Graph, GraphError> {
+        let mut g = Graph::default();
+        g.add_program(self)?;
+        Ok(g)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{guard_summary, Clause, SEQUENCE_TYPE};
+    use crate::contract::actions::ThenFunc;
+    use crate::contract::test_util::{ctx, empty_address, key_at, pay_branch, pay_contract};
+    use crate::contract::{Compilable, Compiled, Context, DynamicContract, TxTmplIt};
+    use bitcoin::util::amount::Amount;
+    use sapio_base::timelocks::RelHeight;
+    use sapio_base::txindex::{TxIndex, TxIndexLogger};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    fn pay(_: &(), ctx: Context) -> TxTmplIt {
+        let address = empty_address(ctx.network);
+        ctx.template()
+            .set_label("payout \"1\"".into())
+            .set_color("pink".into())
+            .set_sequence(-1, RelHeight::from(10).into())?
+            .add_output(
+                Amount::from_sat(5000),
+                &Compiled::from_address(address, None),
+                None,
+            )?
+            .into()
+    }
+    #[test]
+    fn test_graph() {
+        let contract = DynamicContract::<(), ()> {
+            then: vec![|| {
+                Some(ThenFunc {
+                    func: pay,
+                    ..pay_branch()?
+                })
+            }],
+            ..pay_contract()
+        };
+        let compiled = contract.compile(ctx()).unwrap();
+        let graph = compiled.graph();
+        let dot = graph.to_dot();
+        assert!(dot.contains("t1 [shape=box, label=\"payout \\\"1\\\"\\n"));
+        assert!(dot.contains("style=filled, fillcolor=\"pink\""));
+        assert!(dot.contains("c0 -> t1 [label=\"pay: CTV; +10 blocks\"];"));
+        assert!(dot.contains("t1 -> c2 [label=\"0.00005000 BTC\"];"));
+        let mermaid = graph.to_mermaid();
+        assert!(mermaid.contains("t1[\"payout #quot;1#quot;<br/>"));
+        assert!(mermaid.contains("style t1 fill:pink"));
+        assert!(mermaid.contains("c0 -->|\"pay: CTV; +10 blocks\"| t1"));
+
+        let index = Rc::new(TxIndexLogger::new());
+        let funding = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        let txid = index.add_tx(Arc::new(funding)).unwrap();
+        let program = compiled
+            .bind_psbt(
+                bitcoin::OutPoint { txid, vout: 0 },
+                HashMap::new(),
+                index,
+                &CTVAvailable,
+            )
+            .unwrap();
+        let dot = program.graph().unwrap().to_dot();
+        // the contract is linked to its template, and the template to its output
+        assert!(dot.contains("c0 [shape=ellipse, label=\"root\"];"));
+        assert!(dot.contains("c0 -> t1 [label=\"+10 blocks\"];"));
+        assert!(dot.contains("t1 -> c2 [label=\"0.00005000 BTC\"];"));
+    }
+    #[test]
+    fn test_guard_summary() {
+        let key = |i: u8| Clause::Key(key_at(i));
+        let keys = || (1..4).map(key).collect::<Vec<_>>();
+        assert_eq!(guard_summary(&Clause::Threshold(2, keys())), "2-of-3 sigs");
+        assert_eq!(
+            guard_summary(&Clause::Or(vec![(1, key(1)), (1, Clause::Older(10))])),
+            "1 sig or after 10 blocks"
+        );
+        assert_eq!(
+            guard_summary(&Clause::Threshold(
+                1,
+                vec![
+                    Clause::And(vec![key(1), Clause::After(100)]),
+                    Clause::Older(SEQUENCE_TYPE | 10),
+                    Clause::Trivial
+                ]
+            )),
+            "1 of (1 sig + after height 100, after 5120s, anyone)"
+        );
+        assert_eq!(
+            guard_summary(&Clause::And(vec![
+                key(1),
+                Clause::Older(10),
+                Clause::Or(vec![(1, key(2)), (1, Clause::Threshold(2, keys()))]),
+            ])),
+            "1 sig + (1 sig or 2-of-3 sigs)"
+        );
+    }
+}
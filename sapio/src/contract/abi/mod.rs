@@ -6,6 +6,16 @@
 
 //! ABI contains the output formats of Sapio Compilatios
 
+pub mod audit;
 pub mod continuation;
+pub mod cpfp;
+pub mod diagnostics;
+pub mod diff;
+pub mod finalize;
+pub mod graph;
 pub mod object;
+pub mod receipt;
+pub mod spend;
 pub mod studio;
+pub mod timeline;
+pub mod verify;
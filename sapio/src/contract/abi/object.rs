@@ -5,8 +5,11 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Object is the output of Sapio Compilation & can be linked to a specific coin
+pub use super::diff::{Change, ObjectDiff};
 pub use super::studio::*;
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::diagnostics::Diagnostic;
+use crate::contract::abi::finalize::finalize_input;
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
 use crate::util::extended_address::ExtendedAddress;
@@ -14,6 +17,7 @@ use ::miniscript::{self, *};
 use bitcoin::hashes::sha256;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::util::amount::Amount;
+use bitcoin::util::bip32::{ExtendedPubKey, KeySource};
 use bitcoin::util::psbt::PartiallySignedTransaction;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
@@ -24,6 +28,7 @@ use sapio_base::Clause;
 use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -38,7 +43,7 @@ pub enum ObjectError {
     /// OpReturn Too Long
     OpReturnTooLong,
     /// The Error was for an unknown/unhandled reason
-    Custom(Box<dyn std::error::Error>),
+    Custom(Box<dyn std::error::Error + Send + Sync>),
 }
 impl std::error::Error for ObjectError {}
 impl From<EmulatorError> for ObjectError {
@@ -113,6 +118,37 @@ pub struct Object {
     pub descriptor: Option<Descriptor<bitcoin::PublicKey>>,
     /// The amount_range safe to send this object
     pub amount_range: AmountRange,
+    /// Estimated witness size (in bytes, including the witness script) for
+    /// spending via each branch, keyed by the branch's path.
+    #[serde(
+        rename = "estimated_witness_size_by_branch",
+        skip_serializing_if = "HashMap::is_empty",
+        default
+    )]
+    pub witness_size_estimates: HashMap<SArc<EffectPath>, usize>,
+    /// The policy of each branch, keyed by the branch's path, for spending
+    /// via a particular one. See `Object::spend`.
+    #[serde(
+        rename = "policy_by_branch",
+        skip_serializing_if = "HashMap::is_empty",
+        default
+    )]
+    pub branches: HashMap<SArc<EffectPath>, Clause>,
+    /// The master key fingerprint and derivation path of each key in the
+    /// descriptor which was derived from the `Context`'s `PathKeys`, so
+    /// that signers can find the private keys.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    #[schemars(with = "BTreeMap<String, (String, String)>")]
+    pub key_origins: BTreeMap<bitcoin::PublicKey, KeySource>,
+    /// The extended keys the `key_origins` were derived from, with their own
+    /// origins, for a PSBT's global xpubs.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    #[schemars(with = "BTreeMap<String, (String, String)>")]
+    pub xpubs: BTreeMap<ExtendedPubKey, KeySource>,
+    /// Warnings found while compiling this contract, excluding those of the
+    /// contracts it creates. See `Object::all_diagnostics`.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub diagnostics: Vec<Diagnostic>,
 }
 
 impl Object {
@@ -136,6 +172,11 @@ impl Object {
                 a.update_range(Amount::from_sat(21_000_000 * 100_000_000));
                 a
             }),
+            witness_size_estimates: HashMap::new(),
+            branches: HashMap::new(),
+            key_origins: BTreeMap::new(),
+            xpubs: BTreeMap::new(),
+            diagnostics: vec![],
         }
     }
 
@@ -167,14 +208,59 @@ impl Object {
             address: ExtendedAddress::make_op_return(data)?,
             descriptor: None,
             amount_range: AmountRange::new(),
+            witness_size_estimates: HashMap::new(),
+            branches: HashMap::new(),
+            key_origins: BTreeMap::new(),
+            xpubs: BTreeMap::new(),
+            diagnostics: vec![],
         })
     }
 
+    /// The size of the largest witness (including its stack item count)
+    /// spending this contract, if its script is known
+    pub fn max_witness_size(&self) -> Option<usize> {
+        self.witness_size_estimates
+            .values()
+            .max()
+            .copied()
+            .or_else(|| {
+                let d = self.descriptor.as_ref()?;
+                // less the empty script_sig's length
+                Some(d.max_satisfaction_weight().ok()?.saturating_sub(4))
+            })
+    }
+
+    /// The warnings found while compiling this contract and every contract
+    /// reachable from it.
+    pub fn all_diagnostics(&self) -> Vec<&Diagnostic> {
+        let mut diagnostics = vec![];
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            diagnostics.extend(obj.diagnostics.iter());
+            stack.extend(
+                obj.ctv_to_tx
+                    .values()
+                    .chain(obj.suggested_txs.values())
+                    .flat_map(|tmpl| tmpl.outputs.iter().map(|out| &out.contract)),
+            );
+        }
+        diagnostics
+    }
+
     /// bind_psbt attaches and `Object` to a specific UTXO, returning a
     /// Vector of PSBTs and transaction metadata.
     ///
     /// `bind_psbt` accepts a CTVEmulator, a txindex, and a map of outputs to be
-    /// bound to specific template hashes.
+    /// bound to specific template hashes. Each template's contract input (at
+    /// its `ctv_index`) is bound to the contract's own output, so the entry
+    /// for that index in the map is ignored.
+    ///
+    /// Each PSBT carries what a signer needs: the spent outputs (and their
+    /// transactions, where the txindex has them), the contract's witness
+    /// script, the origin of every key in its policy derived from
+    /// `PathKeys`, the same for each output, and the xpubs those were
+    /// derived from. The contract input is finalized if the emulator's
+    /// signatures (or none, e.g. with CTV) suffice to satisfy it.
     pub fn bind_psbt(
         &self,
         out_in: bitcoin::OutPoint,
@@ -195,6 +281,8 @@ impl Object {
                 descriptor,
                 ctv_to_tx,
                 suggested_txs,
+                key_origins,
+                xpubs,
                 ..
             },
         )) = stack.pop()
@@ -213,13 +301,18 @@ impl Object {
                                     metadata_map_s2s,
                                     outputs,
                                     tx,
+                                    ctv_index,
                                     ..
                                 },
                             )| {
+                                let ctv_index = *ctv_index as usize;
                                 let mut tx = tx.clone();
-                                tx.input[0].previous_output = out;
+                                tx.input[ctv_index].previous_output = out;
                                 if let Some(outputs) = output_map.get(ctv_hash) {
-                                    for (i, inp) in tx.input.iter_mut().enumerate().skip(1) {
+                                    for (i, inp) in tx.input.iter_mut().enumerate() {
+                                        if i == ctv_index {
+                                            continue;
+                                        }
                                         if let Some(out) = outputs[i] {
                                             inp.previous_output = out;
                                         }
@@ -232,14 +325,35 @@ impl Object {
                                 {
                                     psbt_in.witness_utxo =
                                         blockdata.lookup_output(&tx_in.previous_output).ok();
+                                    psbt_in.non_witness_utxo = blockdata
+                                        .lookup_tx(&tx_in.previous_output.txid)
+                                        .ok()
+                                        .map(|tx| tx.as_ref().clone());
                                     psbt_in.sighash_type =
                                         Some(bitcoin::blockdata::transaction::SigHashType::All);
                                 }
-                                // Missing other Witness Info.
-                                if let Some(d) = descriptor {
-                                    psbtx.inputs[0].witness_script = Some(d.explicit_script());
+                                let psbt_in = &mut psbtx.inputs[ctv_index];
+                                if let Some(d @ Descriptor::Wsh(_)) = descriptor {
+                                    psbt_in.witness_script = Some(d.explicit_script());
+                                }
+                                psbt_in.bip32_derivation = key_origins.clone();
+                                psbtx.global.xpub.extend(xpubs.clone());
+                                // so signers can recognize outputs to keys of their own
+                                for (psbt_out, out) in psbtx.outputs.iter_mut().zip(outputs.iter())
+                                {
+                                    let contract = &out.contract;
+                                    if let Some(d @ Descriptor::Wsh(_)) = &contract.descriptor {
+                                        psbt_out.witness_script = Some(d.explicit_script());
+                                    }
+                                    psbt_out.bip32_derivation = contract.key_origins.clone();
+                                    psbtx.global.xpub.extend(contract.xpubs.clone());
                                 }
                                 psbtx = emulator.sign(psbtx)?;
+                                if let Some(d) = descriptor {
+                                    // left for signers if it needs more than the
+                                    // emulator's signatures
+                                    let _ = finalize_input(&mut psbtx, ctv_index, d);
+                                }
                                 let final_tx = psbtx.clone().extract_tx();
                                 let txid = blockdata.add_tx(Arc::new(final_tx))?;
                                 stack.reserve(outputs.len());
@@ -266,3 +380,106 @@ impl Object {
         Ok(Program { program: result })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::context::PathKeys;
+    use crate::contract::test_util::{ctx, pay_branch};
+    use crate::contract::{Compilable, Context, DynamicContract, TxTmplIt};
+    use bitcoin::util::bip32::ExtendedPrivKey;
+    use sapio_base::txindex::TxIndexLogger;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    fn derived_key(_: &(), ctx: Context) -> Clause {
+        Clause::Key(ctx.derive_key().unwrap())
+    }
+    fn pay(_: &(), ctx: Context) -> TxTmplIt {
+        ctx.template()
+            .add_output(Amount::from_sat(5000), &contract(false), None)?
+            .into()
+    }
+    /// true for the outer contract, false for the one it pays
+    fn contract(outer: bool) -> DynamicContract<'static, (), ()> {
+        let then: fn() -> Option<ThenFunc<'static, ()>> = || {
+            Some(ThenFunc {
+                func: pay,
+                ..pay_branch()?
+            })
+        };
+        DynamicContract {
+            then: if outer { vec![then] } else { vec![] },
+            finish_or: vec![],
+            finish: vec![|| Some(Guard::Fresh(derived_key, 1))],
+            data: (),
+        }
+    }
+    #[test]
+    fn test_bind_psbt() {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Testnet, &[3; 32]).unwrap();
+        let xpub = ExtendedPubKey::from_private(&secp, &xpriv);
+        let ctx = ctx().with_path_keys(PathKeys {
+            xpub,
+            origin: None,
+            scheme: Default::default(),
+        });
+        let compiled = contract(true).compile(ctx).unwrap();
+        let inner = &compiled.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        assert_eq!(compiled.key_origins.len(), 1);
+        assert_eq!(inner.key_origins.len(), 1);
+
+        let index = Rc::new(TxIndexLogger::new());
+        let funding = bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![bitcoin::TxOut {
+                value: 10000,
+                script_pubkey: compiled.descriptor.as_ref().unwrap().script_pubkey(),
+            }],
+        };
+        let txid = index.add_tx(Arc::new(funding.clone())).unwrap();
+        let program = compiled
+            .bind_psbt(
+                bitcoin::OutPoint { txid, vout: 0 },
+                HashMap::new(),
+                index,
+                &CTVAvailable,
+            )
+            .unwrap();
+        let SapioStudioFormat::LinkedPSBT {
+            psbt,
+            unfinalized_inputs,
+            ..
+        } = &program.program[&compiled.root_path].txs[0];
+        let psbt: PartiallySignedTransaction =
+            bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()).unwrap();
+        // the template's CTV hash alone satisfies the contract
+        assert!(unfinalized_inputs.is_empty());
+        let input = &psbt.inputs[0];
+        assert_eq!(input.non_witness_utxo, Some(funding));
+        assert_eq!(
+            input.final_script_witness.as_ref().unwrap().last(),
+            Some(
+                &compiled
+                    .descriptor
+                    .as_ref()
+                    .unwrap()
+                    .explicit_script()
+                    .into_bytes()
+            )
+        );
+        assert!(input.witness_script.is_none());
+        let output = &psbt.outputs[0];
+        assert_eq!(
+            output.witness_script,
+            Some(inner.descriptor.as_ref().unwrap().explicit_script())
+        );
+        assert_eq!(output.bip32_derivation, inner.key_origins);
+        assert_eq!(
+            psbt.global.xpub.into_iter().collect::<Vec<_>>(),
+            vec![(xpub, (xpub.fingerprint(), vec![].into()))]
+        );
+    }
+}
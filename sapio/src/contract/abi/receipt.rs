@@ -0,0 +1,120 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Canonical digests of compiled contracts, and receipts recording how a
+//! contract was created so that a counterparty may reproduce it.
+use super::object::Object;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use sapio_base::plugin_args::CreateArgs;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Sort the keys of every map in `v`, recursively. Doesn't rely on the
+/// ordering of `serde_json::Map`, which depends on serde_json's features.
+fn canonicalize(v: Value) -> Value {
+    match v {
+        Value::Object(m) => {
+            let mut entries: Vec<(String, Value)> = m.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, canonicalize(v)))
+                    .collect(),
+            )
+        }
+        Value::Array(a) => Value::Array(a.into_iter().map(canonicalize).collect()),
+        v => v,
+    }
+}
+
+impl Object {
+    /// Serialize to JSON with every map's keys sorted and no whitespace, so
+    /// that equal Objects always serialize to the same bytes, regardless of
+    /// e.g. `HashMap` iteration order.
+    pub fn to_canonical_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(&canonicalize(serde_json::to_value(self)?))
+    }
+    /// The sha256 of `Object::to_canonical_json`
+    pub fn digest(&self) -> Result<sha256::Hash, serde_json::Error> {
+        Ok(sha256::Hash::hash(self.to_canonical_json()?.as_bytes()))
+    }
+}
+
+/// # Contract Receipt
+/// A record of the plugin and arguments used to create a contract, and the
+/// digest of the result. Anyone with the same plugin may recompile the
+/// contract and check that it matches the digest.
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ContractReceipt {
+    /// # Plugin
+    /// The hex encoded hash of the plugin's WASM module
+    pub plugin: String,
+    /// # Arguments
+    /// The arguments the plugin was called with
+    pub arguments: CreateArgs<Value>,
+    /// # Digest
+    /// The `Object::digest` of the resulting contract
+    pub digest: sha256::Hash,
+}
+
+impl ContractReceipt {
+    /// create a receipt for `compiled`, created by `plugin` with `arguments`
+    pub fn new(
+        plugin: String,
+        arguments: CreateArgs<Value>,
+        compiled: &Object,
+    ) -> Result<Self, serde_json::Error> {
+        Ok(ContractReceipt {
+            plugin,
+            arguments,
+            digest: compiled.digest()?,
+        })
+    }
+    /// check that `compiled` is the contract this receipt is for
+    pub fn matches(&self, compiled: &Object) -> Result<bool, serde_json::Error> {
+        Ok(compiled.digest()? == self.digest)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::test_util::{ctx, key, key_at, pay_branch, pay_contract};
+    use crate::contract::{Compilable, Context, DynamicContract, TxTmplIt};
+    use sapio_base::Clause;
+    fn pay(_: &(), ctx: Context) -> TxTmplIt {
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &key_at(2), None)?.into()
+    }
+    #[test]
+    fn test_digest_roundtrip() {
+        let contract = DynamicContract::<(), ()> {
+            then: vec![|| {
+                Some(ThenFunc {
+                    func: pay,
+                    ..pay_branch()?
+                })
+            }],
+            finish: vec![|| Some(Guard::Fresh(|_, _| Clause::Key(key()), 1))],
+            ..pay_contract()
+        };
+        let compile = || contract.compile(ctx()).unwrap();
+        let compiled = compile();
+        // recompiling and round tripping through (non-canonical) JSON must
+        // not change the digest
+        assert_eq!(
+            compiled.to_canonical_json().unwrap(),
+            compile().to_canonical_json().unwrap()
+        );
+        let json = serde_json::to_string_pretty(&compiled).unwrap();
+        let parsed: Object = serde_json::from_str(&json).unwrap();
+        assert_eq!(compiled.digest().unwrap(), parsed.digest().unwrap());
+    }
+}
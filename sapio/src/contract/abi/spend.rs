@@ -0,0 +1,269 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Spending a contract via any of its branches, to arbitrary outputs
+use super::finalize::{finalize_input, unfinalized_inputs};
+use super::object::Object;
+use super::timeline::SEQUENCE_TYPE;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::util::amount::Amount;
+use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+use miniscript::{Descriptor, DescriptorTrait};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::Clause;
+use std::fmt;
+
+/// Errors that can arise when spending a contract
+#[derive(Debug)]
+pub enum SpendError {
+    /// The contract has no branch at that path
+    UnknownBranch(SArc<EffectPath>),
+    /// The branch requires a transaction template, so must be spent with one
+    /// of the contract's templates (e.g. from `bind_psbt`) instead
+    RequiresTemplate(SArc<EffectPath>),
+    /// The branch's alternatives mix height and time based timelocks, which
+    /// no one transaction can satisfy together
+    IncompatibleTimelocks(SArc<EffectPath>),
+    /// The contract's descriptor is not known
+    UnknownDescriptor,
+    /// The spend has no input of this contract
+    NotSpent,
+    /// The Error was due to Miniscript, e.g. a missing signature
+    Miniscript(miniscript::Error),
+    /// The spend could not be made into a PSBT
+    Psbt(bitcoin::util::psbt::Error),
+}
+impl std::error::Error for SpendError {}
+impl From<miniscript::Error> for SpendError {
+    fn from(e: miniscript::Error) -> Self {
+        SpendError::Miniscript(e)
+    }
+}
+impl From<bitcoin::util::psbt::Error> for SpendError {
+    fn from(e: bitcoin::util::psbt::Error) -> Self {
+        SpendError::Psbt(e)
+    }
+}
+impl fmt::Display for SpendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Calls `f` on `c` and every clause within it
+pub(super) fn visit(c: &Clause, f: &mut impl FnMut(&Clause)) {
+    f(c);
+    match c {
+        Clause::And(v) | Clause::Threshold(_, v) => v.iter().for_each(|c| visit(c, f)),
+        Clause::Or(v) => v.iter().for_each(|(_, c)| visit(c, f)),
+        _ => {}
+    }
+}
+
+impl Object {
+    /// Build a PSBT spending `outpoint`, an output of `amount` to this
+    /// contract, to `outputs` via `branch`, one of the paths in
+    /// `Object::branches`, e.g. a `finish` guard's `root/@finish_fn/#0`.
+    ///
+    /// The sequence and lock time are set to the largest relative and
+    /// absolute timelocks in the branch, so that the spend is valid whichever
+    /// of the branch's alternatives is satisfied. A branch whose
+    /// alternatives mix height and time based timelocks can't be spent this
+    /// way, as no lock time satisfies both. The input carries the
+    /// contract's witness script and the origins of the branch's keys. Once
+    /// its signatures and preimages are added, see `Object::finalize_spend`.
+    pub fn spend(
+        &self,
+        outpoint: OutPoint,
+        amount: Amount,
+        branch: &SArc<EffectPath>,
+        outputs: Vec<TxOut>,
+    ) -> Result<PartiallySignedTransaction, SpendError> {
+        let clause = self
+            .branches
+            .get(branch)
+            .ok_or_else(|| SpendError::UnknownBranch(branch.clone()))?;
+        let descriptor = self
+            .descriptor
+            .as_ref()
+            .ok_or(SpendError::UnknownDescriptor)?;
+        let mut requires_template = false;
+        let mut older = vec![];
+        let mut after = vec![];
+        let mut keys = vec![];
+        visit(clause, &mut |c| match c {
+            Clause::TxTemplate(_) => requires_template = true,
+            Clause::Older(t) => older.push(*t),
+            Clause::After(t) => after.push(*t),
+            Clause::Key(k) => keys.push(*k),
+            _ => {}
+        });
+        if requires_template {
+            return Err(SpendError::RequiresTemplate(branch.clone()));
+        }
+        let mixed =
+            |v: &[u32], is_time: fn(&u32) -> bool| v.iter().any(is_time) && !v.iter().all(is_time);
+        if mixed(&older, |t| t & SEQUENCE_TYPE != 0) || mixed(&after, |t| *t >= 500_000_000) {
+            return Err(SpendError::IncompatibleTimelocks(branch.clone()));
+        }
+        let older = older.into_iter().max();
+        let after = after.into_iter().max();
+        let tx = Transaction {
+            // relative timelocks need version 2
+            version: 2,
+            lock_time: after.unwrap_or(0),
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: Script::new(),
+                // otherwise signal replaceability, which also enables the
+                // lock time
+                sequence: older.unwrap_or(0xffff_fffd),
+                witness: vec![],
+            }],
+            output: outputs,
+        };
+        let mut psbt = PartiallySignedTransaction::from_unsigned_tx(tx)?;
+        let input = &mut psbt.inputs[0];
+        input.witness_utxo = Some(TxOut {
+            value: amount.as_sat(),
+            script_pubkey: descriptor.script_pubkey(),
+        });
+        if let Descriptor::Wsh(_) = descriptor {
+            input.witness_script = Some(descriptor.explicit_script());
+        }
+        input.sighash_type = Some(SigHashType::All);
+        input.bip32_derivation = self
+            .key_origins
+            .iter()
+            .filter(|(k, _)| keys.contains(k))
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        psbt.global.xpub = self
+            .xpubs
+            .iter()
+            .filter(|(_, (fingerprint, _))| {
+                input
+                    .bip32_derivation
+                    .values()
+                    .any(|(f, _)| f == fingerprint)
+            })
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        Ok(psbt)
+    }
+
+    /// Finalize the inputs of `psbt` spending this contract, e.g. one from
+    /// `Object::spend`, once their signatures and preimages have been added.
+    /// Returns the inputs still needing signatures, e.g. ones of other
+    /// contracts.
+    pub fn finalize_spend(
+        &self,
+        psbt: &mut PartiallySignedTransaction,
+    ) -> Result<Vec<usize>, SpendError> {
+        let descriptor = self
+            .descriptor
+            .as_ref()
+            .ok_or(SpendError::UnknownDescriptor)?;
+        let script_pubkey = descriptor.script_pubkey();
+        let ours: Vec<usize> = psbt
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(_, i)| {
+                i.witness_utxo.as_ref().map(|o| &o.script_pubkey) == Some(&script_pubkey)
+            })
+            .map(|(index, _)| index)
+            .collect();
+        if ours.is_empty() {
+            return Err(SpendError::NotSpent);
+        }
+        for index in ours {
+            finalize_input(psbt, index, descriptor)?;
+        }
+        Ok(unfinalized_inputs(psbt))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::Guard;
+    use crate::contract::test_util::{ctx, key, key_at, path, pay_contract, secret};
+    use crate::contract::{Compilable, DynamicContract};
+    use bitcoin::secp256k1::{Message, Secp256k1};
+    use bitcoin::util::bip143::SigHashCache;
+    #[test]
+    fn test_spend() {
+        let contract = DynamicContract::<(), ()> {
+            finish: vec![
+                || {
+                    Some(Guard::Fresh(
+                        |_, _| Clause::And(vec![Clause::Key(key()), Clause::Older(10)]),
+                        1,
+                    ))
+                },
+                || {
+                    // after 10 blocks, or after 10 * 512 seconds
+                    Some(Guard::Fresh(
+                        |_, _| {
+                            Clause::Threshold(
+                                1,
+                                vec![
+                                    Clause::And(vec![Clause::Key(key_at(2)), Clause::Older(10)]),
+                                    Clause::And(vec![
+                                        Clause::Key(key_at(3)),
+                                        Clause::Older(SEQUENCE_TYPE | 10),
+                                    ]),
+                                ],
+                            )
+                        },
+                        1,
+                    ))
+                },
+            ],
+            ..pay_contract()
+        };
+        let compiled = contract.compile(ctx()).unwrap();
+        let outpoint = OutPoint::default();
+        let amount = Amount::from_sat(10000);
+        let out = TxOut {
+            value: 9000,
+            script_pubkey: Script::new(),
+        };
+        assert!(matches!(
+            compiled.spend(outpoint, amount, &path("root/@then_fn/@next/pay"), vec![]),
+            Err(SpendError::RequiresTemplate(_))
+        ));
+        assert!(matches!(
+            compiled.spend(outpoint, amount, &path("root/@finish_fn/#2"), vec![]),
+            Err(SpendError::UnknownBranch(_))
+        ));
+        assert!(matches!(
+            compiled.spend(outpoint, amount, &path("root/@finish_fn/#1"), vec![]),
+            Err(SpendError::IncompatibleTimelocks(_))
+        ));
+        let mut psbt = compiled
+            .spend(outpoint, amount, &path("root/@finish_fn/#0"), vec![out])
+            .unwrap();
+        let tx = psbt.global.unsigned_tx.clone();
+        assert_eq!(tx.input[0].sequence, 10);
+        assert!(compiled.finalize_spend(&mut psbt.clone()).is_err());
+
+        let script = psbt.inputs[0].witness_script.clone().unwrap();
+        let sighash = SigHashCache::new(&tx).signature_hash(0, &script, 10000, SigHashType::All);
+        let sig = Secp256k1::new().sign(&Message::from_slice(&sighash[..]).unwrap(), &secret());
+        let mut raw = sig.serialize_der().to_vec();
+        raw.push(SigHashType::All.as_u32() as u8);
+        psbt.inputs[0].partial_sigs.insert(key(), raw);
+        assert!(compiled.finalize_spend(&mut psbt).unwrap().is_empty());
+        assert_eq!(
+            psbt.inputs[0].final_script_witness.as_ref().unwrap().last(),
+            Some(&script.into_bytes())
+        );
+    }
+}
@@ -6,6 +6,7 @@
 
 //! Formats for Sapio Studio
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::finalize::unfinalized_inputs;
 use crate::template::output::OutputMeta;
 use crate::template::TemplateMetadata;
 use ::miniscript::*;
@@ -44,6 +45,10 @@ pub enum SapioStudioFormat {
         metadata: TemplateMetadata,
         /// per-Output Metadata
         output_metadata: Vec<OutputMeta>,
+        /// inputs which still need signatures (or preimages) before the
+        /// transaction can be broadcast
+        #[serde(skip_serializing_if = "Vec::is_empty", default)]
+        unfinalized_inputs: Vec<usize>,
     },
 }
 
@@ -53,12 +58,14 @@ impl From<LinkedPSBT> for SapioStudioFormat {
             let bytes = serialize(&l.psbt);
             base64::encode(bytes)
         };
+        let unfinalized_inputs = unfinalized_inputs(&l.psbt);
         let hex = bitcoin::consensus::encode::serialize_hex(&l.psbt.extract_tx());
         SapioStudioFormat::LinkedPSBT {
             psbt,
             hex,
             metadata: l.metadata,
             output_metadata: l.output_metadata,
+            unfinalized_inputs,
         }
     }
 }
@@ -0,0 +1,284 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! When each path through a compiled contract can confirm
+use super::object::Object;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// When the funding transaction of a contract confirmed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Funding {
+    /// the height of the block it confirmed in
+    pub height: u32,
+    /// the median time past of that block, if known
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub time: Option<u32>,
+}
+
+/// The earliest a transaction can confirm
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Earliest {
+    /// blocks after the funding transaction, from relative height locks
+    pub relative_blocks: u32,
+    /// seconds after the funding transaction, from relative time locks
+    pub relative_seconds: u32,
+    /// the lowest height it can confirm at, from absolute height locks
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub height_lock: Option<u32>,
+    /// the median time past it can confirm after, from absolute time locks
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub time_lock: Option<u32>,
+    /// the lowest height it can confirm at, given the `Funding`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub height: Option<u32>,
+    /// the median time past it can confirm after, given the `Funding` with a
+    /// time
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub time: Option<u32>,
+}
+
+/// One transaction along a `TimelinePath`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TimelineStep {
+    /// the path of the contract the template spends
+    pub path: SArc<EffectPath>,
+    /// the template's hash
+    pub template: sha256::Hash,
+    /// the template's label, if any
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub label: Option<String>,
+    /// the output of the template the path continues through
+    pub output: usize,
+    /// when the template can confirm
+    pub earliest: Earliest,
+}
+
+/// A sequence of templates from the root of a contract to a final output,
+/// i.e. one to a contract with no templates
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TimelinePath {
+    /// the templates, starting from the root
+    pub steps: Vec<TimelineStep>,
+    /// the address of the final output
+    pub address: ExtendedAddress,
+    /// the amount of the final output
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub amount: Amount,
+}
+
+/// Every path from the root of a contract to a final output
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct Timeline {
+    /// when the contract was funded, if given
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub funding: Option<Funding>,
+    /// the paths, in depth first order
+    pub paths: Vec<TimelinePath>,
+}
+
+/// 1 << 31, set if a sequence has no relative lock
+const SEQUENCE_DISABLE: u32 = 1 << 31;
+/// 1 << 22, set if a sequence's relative lock is in units of 512 seconds
+pub(super) const SEQUENCE_TYPE: u32 = 1 << 22;
+
+impl Earliest {
+    /// The earliest `tx` can confirm, spending a contract through input
+    /// `ctv_index` which was created no earlier than `self`.
+    pub(super) fn then(&self, tx: &bitcoin::Transaction, ctv_index: u32) -> Self {
+        let mut next = *self;
+        let sequence = tx.input[ctv_index as usize].sequence;
+        let (blocks, seconds) = if tx.version < 2 || sequence & SEQUENCE_DISABLE != 0 {
+            (0, 0)
+        } else if sequence & SEQUENCE_TYPE != 0 {
+            (0, (sequence & 0xffff) * 512)
+        } else {
+            (sequence & 0xffff, 0)
+        };
+        next.relative_blocks += blocks;
+        next.relative_seconds += seconds;
+        next.height = self.height.map(|h| h + blocks);
+        next.time = self.time.map(|t| t + seconds);
+        // lock_time is only enforced if some input is not final
+        if tx.lock_time != 0 && tx.input.iter().any(|i| i.sequence != u32::MAX) {
+            if tx.lock_time < 500_000_000 {
+                // a height lock allows confirming in the block after
+                let lock = tx.lock_time + 1;
+                next.height_lock = Some(next.height_lock.map_or(lock, |l| l.max(lock)));
+                next.height = next.height.map(|h| h.max(lock));
+            } else {
+                let lock = tx.lock_time;
+                next.time_lock = Some(next.time_lock.map_or(lock, |l| l.max(lock)));
+                next.time = next.time.map(|t| t.max(lock));
+            }
+        }
+        next
+    }
+}
+
+impl Object {
+    /// Every path from this contract to a final output, with when each
+    /// template along it can confirm. If `funding` is given, the earliest
+    /// height (and time, if known) of each is computed too.
+    pub fn timeline(&self, funding: Option<Funding>) -> Timeline {
+        let start = Earliest {
+            height: funding.map(|f| f.height),
+            time: funding.and_then(|f| f.time),
+            ..Default::default()
+        };
+        let mut paths = vec![];
+        self.timeline_paths(start, &mut vec![], &mut paths);
+        Timeline { funding, paths }
+    }
+
+    fn timeline_paths(
+        &self,
+        earliest: Earliest,
+        steps: &mut Vec<TimelineStep>,
+        paths: &mut Vec<TimelinePath>,
+    ) {
+        let mut templates: Vec<_> = self
+            .ctv_to_tx
+            .iter()
+            .chain(self.suggested_txs.iter())
+            .collect();
+        // HashMap order is random, so sort for a stable output
+        templates.sort_by_key(|(h, _)| *h);
+        for (h, tmpl) in templates {
+            let earliest = earliest.then(&tmpl.tx, tmpl.ctv_index);
+            for (output, out) in tmpl.outputs.iter().enumerate() {
+                steps.push(TimelineStep {
+                    path: self.root_path.clone(),
+                    template: *h,
+                    label: tmpl.metadata_map_s2s.label.clone(),
+                    output,
+                    earliest,
+                });
+                let contract = &out.contract;
+                if contract.ctv_to_tx.is_empty() && contract.suggested_txs.is_empty() {
+                    paths.push(TimelinePath {
+                        steps: steps.clone(),
+                        address: contract.address.clone(),
+                        amount: out.amount,
+                    });
+                } else {
+                    contract.timeline_paths(earliest, steps, paths);
+                }
+                steps.pop();
+            }
+        }
+    }
+}
+
+impl fmt::Display for Earliest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "+{} blocks, +{}s",
+            self.relative_blocks, self.relative_seconds
+        )?;
+        if let Some(h) = self.height_lock {
+            write!(f, ", height >= {}", h)?;
+        }
+        if let Some(t) = self.time_lock {
+            write!(f, ", time > {}", t)?;
+        }
+        if let Some(h) = self.height {
+            write!(f, "; earliest height {}", h)?;
+        }
+        if let Some(t) = self.time {
+            write!(f, ", earliest time {}", t)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Timeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, path) in self.paths.iter().enumerate() {
+            writeln!(f, "path {}: {} to {}", i, path.amount, path.address)?;
+            for step in path.steps.iter() {
+                writeln!(
+                    f,
+                    "  {} {}{} output {}: {}",
+                    String::from(step.path.0.as_ref().clone()),
+                    step.template,
+                    step.label
+                        .as_ref()
+                        .map(|l| format!(" ({})", l))
+                        .unwrap_or_default(),
+                    step.output,
+                    step.earliest
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{contract_with, ctx, empty_address};
+    use crate::contract::{Compilable, Compiled, Context, TxTmplIt};
+    use sapio_base::timelocks::{AbsHeight, RelHeight};
+    use std::convert::TryFrom;
+    fn pay(outer: &bool, ctx: Context) -> TxTmplIt {
+        let address = Compiled::from_address(empty_address(ctx.network), None);
+        if *outer {
+            ctx.template()
+                .set_sequence(-1, RelHeight::from(10).into())?
+                .add_output(Amount::from_sat(5000), &contract_with(false, pay), None)?
+                .add_output(Amount::from_sat(1000), &address, None)?
+                .into()
+        } else {
+            ctx.template()
+                .set_lock_time(AbsHeight::try_from(500)?.into())?
+                .add_output(Amount::from_sat(4000), &address, None)?
+                .into()
+        }
+    }
+    #[test]
+    fn test_timeline() {
+        let compiled = contract_with(true, pay).compile(ctx()).unwrap();
+        let timeline = compiled.timeline(Some(Funding {
+            height: 100,
+            time: None,
+        }));
+        assert_eq!(timeline.paths.len(), 2);
+        let (deep, shallow) = (&timeline.paths[0], &timeline.paths[1]);
+        assert_eq!(deep.amount, Amount::from_sat(4000));
+        assert_eq!(deep.steps.len(), 2);
+        assert_eq!(shallow.steps.len(), 1);
+        assert_eq!(shallow.steps[0].output, 1);
+        let waited = Earliest {
+            relative_blocks: 10,
+            height: Some(110),
+            ..Default::default()
+        };
+        assert_eq!(shallow.steps[0].earliest, waited);
+        assert_eq!(deep.steps[0].earliest, waited);
+        assert_eq!(
+            deep.steps[1].earliest,
+            Earliest {
+                height_lock: Some(501),
+                height: Some(501),
+                ..waited
+            }
+        );
+        // without funding, only the relative and absolute parts are known
+        let timeline = compiled.timeline(None);
+        assert_eq!(timeline.paths[0].steps[1].earliest.height, None);
+        assert_eq!(timeline.paths[0].steps[1].earliest.relative_blocks, 10);
+    }
+}
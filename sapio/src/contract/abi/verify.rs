@@ -0,0 +1,496 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checking that the fields of a compiled contract, e.g. one received from a
+//! counterparty, agree with each other
+use super::object::Object;
+use super::spend::visit;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::{Address, Network, Script};
+use miniscript::descriptor::WshInner;
+use miniscript::{Descriptor, DescriptorTrait, Miniscript, MiniscriptKey, Segwitv0, Terminal};
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::{CTVHash, Clause};
+use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// A way in which a compiled contract disagrees with itself. `path` is the
+/// `root_path` of the contract it was found in.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Inconsistency {
+    /// The contract's address is not its descriptor's
+    AddressMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the address of the descriptor on the network being checked
+        expected: ExtendedAddress,
+    },
+    /// The contract's address is for another network
+    WrongNetwork {
+        /// the contract
+        path: SArc<EffectPath>,
+    },
+    /// The contract's policy doesn't compile to its descriptor
+    PolicyMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+    },
+    /// The contract has templates, but no script to commit to them
+    UnknownDescriptor {
+        /// the contract
+        path: SArc<EffectPath>,
+    },
+    /// A template's `ctv_index` is not one of its inputs
+    CtvIndexOutOfRange {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+    },
+    /// A template's key in its map is not the hash of its transaction
+    TemplateKeyMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+        /// the hash of the template's transaction
+        computed: sha256::Hash,
+    },
+    /// A template's `ctv` is not the hash of its transaction
+    TemplateCtvMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+        /// the template's `ctv`
+        ctv: sha256::Hash,
+    },
+    /// A template in `ctv_to_tx` is not committed to by the contract's script
+    TemplateNotCommitted {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+    },
+    /// The contract's script commits to a template not in `ctv_to_tx`
+    UnlistedTemplate {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the hash committed to
+        template: sha256::Hash,
+    },
+    /// A template has a different number of outputs than its transaction
+    OutputCountMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+    },
+    /// A transaction output does not pay to its contract's address
+    OutputScriptMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+        /// the output
+        index: usize,
+    },
+    /// A transaction output's value is not its contract's amount
+    OutputAmountMismatch {
+        /// the contract
+        path: SArc<EffectPath>,
+        /// the template's key in its map
+        template: sha256::Hash,
+        /// the output
+        index: usize,
+        /// the transaction output's value
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        value: Amount,
+        /// the amount of the template's output
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        amount: Amount,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = |path: &SArc<EffectPath>| String::from(path.0.as_ref().clone());
+        match self {
+            Inconsistency::AddressMismatch { path, expected } => {
+                write!(
+                    f,
+                    "{}: address is not the descriptor's {}",
+                    p(path),
+                    expected
+                )
+            }
+            Inconsistency::WrongNetwork { path } => {
+                write!(f, "{}: address is for another network", p(path))
+            }
+            Inconsistency::PolicyMismatch { path } => {
+                write!(f, "{}: policy does not compile to the descriptor", p(path))
+            }
+            Inconsistency::UnknownDescriptor { path } => write!(
+                f,
+                "{}: has templates but no descriptor committing to them",
+                p(path)
+            ),
+            Inconsistency::CtvIndexOutOfRange { path, template } => {
+                write!(f, "{} {}: ctv_index is not an input", p(path), template)
+            }
+            Inconsistency::TemplateKeyMismatch {
+                path,
+                template,
+                computed,
+            } => write!(
+                f,
+                "{} {}: the transaction's hash is {}",
+                p(path),
+                template,
+                computed
+            ),
+            Inconsistency::TemplateCtvMismatch {
+                path,
+                template,
+                ctv,
+            } => write!(
+                f,
+                "{} {}: ctv {} is not the transaction's hash",
+                p(path),
+                template,
+                ctv
+            ),
+            Inconsistency::TemplateNotCommitted { path, template } => write!(
+                f,
+                "{} {}: not committed to by the descriptor",
+                p(path),
+                template
+            ),
+            Inconsistency::UnlistedTemplate { path, template } => write!(
+                f,
+                "{}: descriptor commits to unlisted template {}",
+                p(path),
+                template
+            ),
+            Inconsistency::OutputCountMismatch { path, template } => write!(
+                f,
+                "{} {}: outputs do not match the transaction's",
+                p(path),
+                template
+            ),
+            Inconsistency::OutputScriptMismatch {
+                path,
+                template,
+                index,
+            } => write!(
+                f,
+                "{} {}: output {} does not pay its contract's address",
+                p(path),
+                template,
+                index
+            ),
+            Inconsistency::OutputAmountMismatch {
+                path,
+                template,
+                index,
+                value,
+                amount,
+            } => write!(
+                f,
+                "{} {}: output {} has value {} but amount {}",
+                p(path),
+                template,
+                index,
+                value,
+                amount
+            ),
+        }
+    }
+}
+
+/// Whether every key and template of `c` appears in `ms`
+fn commits_to(ms: &Miniscript<bitcoin::PublicKey, Segwitv0>, c: &Clause) -> bool {
+    let mut committed = true;
+    visit(c, &mut |c| match c {
+        Clause::Key(k) => {
+            committed &=
+                ms.iter_pk().any(|pk| pk == *k) || ms.iter_pkh().any(|h| h == k.to_pubkeyhash())
+        }
+        Clause::TxTemplate(h) => {
+            committed &= ms
+                .iter()
+                .any(|n| matches!(n.node, Terminal::TxTemplate(t) if t == *h))
+        }
+        // emulators give only keys or templates
+        _ => {}
+    });
+    committed
+}
+
+impl Object {
+    /// Check that the fields of this contract, and every contract it creates,
+    /// agree with each other on `network`: that each template's key and
+    /// `ctv` are its transaction's hash, that each output pays its
+    /// contract's `address` and amount, that each `address` is its
+    /// `descriptor`'s, that the `policy` compiles to the `descriptor`, and
+    /// that the `descriptor` commits (via `emulator`, as when it was
+    /// compiled) to exactly the templates in `ctv_to_tx`.
+    ///
+    /// Fails only if `emulator` does.
+    pub fn verify(
+        &self,
+        network: Network,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<Inconsistency>, EmulatorError> {
+        let mut found = vec![];
+        let mut stack = vec![self];
+        while let Some(obj) = stack.pop() {
+            obj.verify_one(network, emulator, &mut found)?;
+            stack.extend(
+                obj.ctv_to_tx
+                    .values()
+                    .chain(obj.suggested_txs.values())
+                    .flat_map(|tmpl| tmpl.outputs.iter().map(|out| &out.contract)),
+            );
+        }
+        Ok(found)
+    }
+
+    fn verify_one(
+        &self,
+        network: Network,
+        emulator: &dyn CTVEmulator,
+        found: &mut Vec<Inconsistency>,
+    ) -> Result<(), EmulatorError> {
+        let path = &self.root_path;
+        if let ExtendedAddress::Address(a) = &self.address {
+            let on_network = Address {
+                payload: a.payload.clone(),
+                network,
+            };
+            // compare the strings, as testnet and regtest share base58 prefixes
+            if a.to_string() != on_network.to_string() {
+                found.push(Inconsistency::WrongNetwork { path: path.clone() });
+            }
+        }
+        if let Some(d) = &self.descriptor {
+            if d.script_pubkey() != Script::from(self.address.clone()) {
+                let expected = d
+                    .address(network)
+                    .map(ExtendedAddress::from)
+                    .unwrap_or_else(|_| ExtendedAddress::Unknown(d.script_pubkey()));
+                found.push(Inconsistency::AddressMismatch {
+                    path: path.clone(),
+                    expected,
+                });
+            }
+        }
+        if let Some(policy) = &self.policy {
+            let compiled = policy
+                .compile()
+                .ok()
+                .and_then(|ms| Descriptor::new_wsh(ms).ok());
+            if compiled.as_ref() != self.descriptor.as_ref() {
+                found.push(Inconsistency::PolicyMismatch { path: path.clone() });
+            }
+        }
+        let mut templates: Vec<_> = self
+            .ctv_to_tx
+            .iter()
+            .chain(self.suggested_txs.iter())
+            .collect();
+        // HashMap order is random, so sort for a stable output
+        templates.sort_by_key(|(h, _)| *h);
+        for (h, tmpl) in templates {
+            let template = *h;
+            if tmpl.ctv_index as usize >= tmpl.tx.input.len() {
+                found.push(Inconsistency::CtvIndexOutOfRange {
+                    path: path.clone(),
+                    template,
+                });
+            } else {
+                let computed = tmpl.tx.get_ctv_hash(tmpl.ctv_index);
+                if computed != template {
+                    found.push(Inconsistency::TemplateKeyMismatch {
+                        path: path.clone(),
+                        template,
+                        computed,
+                    });
+                }
+                if computed != tmpl.ctv {
+                    found.push(Inconsistency::TemplateCtvMismatch {
+                        path: path.clone(),
+                        template,
+                        ctv: tmpl.ctv,
+                    });
+                }
+            }
+            if tmpl.outputs.len() != tmpl.tx.output.len() {
+                found.push(Inconsistency::OutputCountMismatch {
+                    path: path.clone(),
+                    template,
+                });
+            }
+            for (index, (out, txout)) in tmpl.outputs.iter().zip(tmpl.tx.output.iter()).enumerate()
+            {
+                if txout.script_pubkey != Script::from(out.contract.address.clone()) {
+                    found.push(Inconsistency::OutputScriptMismatch {
+                        path: path.clone(),
+                        template,
+                        index,
+                    });
+                }
+                let value = Amount::from_sat(txout.value);
+                if value != out.amount {
+                    found.push(Inconsistency::OutputAmountMismatch {
+                        path: path.clone(),
+                        template,
+                        index,
+                        value,
+                        amount: out.amount,
+                    });
+                }
+            }
+        }
+        if self.ctv_to_tx.is_empty() {
+            return Ok(());
+        }
+        let ms = match self.descriptor.as_ref().map(|d| match d {
+            Descriptor::Wsh(wsh) => Some(wsh.as_inner()),
+            _ => None,
+        }) {
+            Some(Some(WshInner::Ms(ms))) => ms,
+            _ => {
+                found.push(Inconsistency::UnknownDescriptor { path: path.clone() });
+                return Ok(());
+            }
+        };
+        let mut listed: Vec<_> = self.ctv_to_tx.keys().copied().collect();
+        listed.sort();
+        for template in listed {
+            if !commits_to(ms, &emulator.get_signer_for(template)?) {
+                found.push(Inconsistency::TemplateNotCommitted {
+                    path: path.clone(),
+                    template,
+                });
+            }
+        }
+        for n in ms.iter() {
+            if let Terminal::TxTemplate(h) = n.node {
+                if !self.ctv_to_tx.contains_key(&h) {
+                    found.push(Inconsistency::UnlistedTemplate {
+                        path: path.clone(),
+                        template: h,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{contract_with, ctx, empty_address};
+    use crate::contract::{Compilable, Compiled, Context, TxTmplIt};
+    use bitcoin::hashes::Hash;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    fn pay(outer: &bool, ctx: Context) -> TxTmplIt {
+        let address = Compiled::from_address(empty_address(ctx.network), None);
+        if *outer {
+            ctx.template()
+                .add_output(Amount::from_sat(5000), &contract_with(false, pay), None)?
+                .add_anchor_output(None)?
+                .into()
+        } else {
+            ctx.template()
+                .add_output(Amount::from_sat(4000), &address, None)?
+                .into()
+        }
+    }
+    #[test]
+    fn test_verify() {
+        let compiled = contract_with(true, pay).compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.verify(Network::Regtest, &CTVAvailable).unwrap(),
+            vec![]
+        );
+        // as received from a counterparty
+        let received: Compiled =
+            serde_json::from_str(&serde_json::to_string(&compiled).unwrap()).unwrap();
+        assert_eq!(
+            received.verify(Network::Regtest, &CTVAvailable).unwrap(),
+            vec![]
+        );
+
+        let path = compiled.root_path.clone();
+        let (h, tmpl) = compiled.ctv_to_tx.iter().next().unwrap();
+        let h = *h;
+        let inner_path = tmpl.outputs[0].contract.root_path.clone();
+        let is_network = |v: &[Inconsistency]| {
+            v.iter()
+                .all(|i| matches!(i, Inconsistency::WrongNetwork { .. }))
+        };
+        assert!(is_network(
+            &compiled.verify(Network::Bitcoin, &CTVAvailable).unwrap()
+        ));
+
+        let mut tampered = compiled.clone();
+        let wrong = sha256::Hash::from_inner([0; 32]);
+        let tmpl = tampered.ctv_to_tx.remove(&h).unwrap();
+        tampered.ctv_to_tx.insert(wrong, tmpl);
+        assert_eq!(
+            tampered.verify(Network::Regtest, &CTVAvailable).unwrap(),
+            vec![
+                Inconsistency::TemplateKeyMismatch {
+                    path: path.clone(),
+                    template: wrong,
+                    computed: h
+                },
+                Inconsistency::TemplateNotCommitted {
+                    path: path.clone(),
+                    template: wrong
+                },
+                Inconsistency::UnlistedTemplate {
+                    path: path.clone(),
+                    template: h
+                },
+            ]
+        );
+
+        let mut tampered = compiled.clone();
+        tampered.address = Compiled::from_address(empty_address(Network::Regtest), None).address;
+        let inner = &mut tampered.ctv_to_tx.get_mut(&h).unwrap().outputs[0];
+        inner.amount = Amount::from_sat(1);
+        inner.contract.policy = Some(Clause::Trivial);
+        assert_eq!(
+            tampered.verify(Network::Regtest, &CTVAvailable).unwrap(),
+            vec![
+                Inconsistency::AddressMismatch {
+                    path: path.clone(),
+                    expected: compiled.address.clone()
+                },
+                Inconsistency::OutputAmountMismatch {
+                    path,
+                    template: h,
+                    index: 0,
+                    value: Amount::from_sat(5000),
+                    amount: Amount::from_sat(1)
+                },
+                Inconsistency::PolicyMismatch { path: inner_path },
+            ]
+        );
+    }
+}
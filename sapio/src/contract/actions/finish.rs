@@ -39,6 +39,9 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     pub schema: Option<Arc<RootSchema>>,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used, compared to the other
+    /// branches of the contract. Defaults to 1.
+    pub weight: usize,
     /// Type switch to enable/disable compilation with serialized fields
     /// (if negative trait bounds, could remove!)
     pub f: PhantomData<WebAPIStatus>,
@@ -67,6 +70,8 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     fn get_name(&self) -> &Arc<String>;
     /// Get the RootSchema for calling this with an update
     fn get_schema(&self) -> &Option<Arc<RootSchema>>;
+    /// Get the relative likelihood weight of this branch
+    fn get_weight(&self) -> usize;
 }
 
 /// Type Tag for FinishOrFunc Variant
@@ -101,6 +106,9 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
+    fn get_weight(&self) -> usize {
+        self.weight
+    }
 }
 
 impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf, StatefulArguments>
@@ -137,4 +145,7 @@ where
     fn get_schema(&self) -> &Option<Arc<RootSchema>> {
         &self.schema
     }
+    fn get_weight(&self) -> usize {
+        self.weight
+    }
 }
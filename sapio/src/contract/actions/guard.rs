@@ -12,11 +12,24 @@ use sapio_base::Clause;
 /// If bool = true, the computation of the guard is cached, which is useful if e.g. Guard
 /// must contact a remote server or it should be the same across calls *for a given contract
 /// instance*.
+///
+/// The `usize` is a relative likelihood weight, only consulted when the Guard is
+/// bound as a `finish` branch.
 pub enum Guard<ContractSelf> {
     /// Cache Variant should only be called one time per contract and the result saved
-    Cache(fn(&ContractSelf, Context) -> Clause),
+    Cache(fn(&ContractSelf, Context) -> Clause, usize),
     /// Fresh Variant may be called repeatedly
-    Fresh(fn(&ContractSelf, Context) -> Clause),
+    Fresh(fn(&ContractSelf, Context) -> Clause, usize),
+}
+
+impl<ContractSelf> Guard<ContractSelf> {
+    /// the relative likelihood of this Guard being used to spend, when it is a
+    /// `finish` branch.
+    pub fn weight(&self) -> usize {
+        match self {
+            Guard::Cache(_, w) | Guard::Fresh(_, w) => *w,
+        }
+    }
 }
 
 /// A List of Guards, for convenience
@@ -26,4 +26,7 @@ pub struct ThenFunc<'a, ContractSelf> {
     pub func: fn(&ContractSelf, Context) -> TxTmplIt,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// relative likelihood of this branch being used, compared to the other
+    /// branches of the contract. Defaults to 1.
+    pub weight: usize,
 }
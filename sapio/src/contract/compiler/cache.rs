@@ -13,8 +13,8 @@ use sapio_base::Clause;
 use std::collections::HashMap;
 
 pub(crate) enum CacheEntry<T> {
-    Cached(Clause),
-    Fresh(fn(&T, Context) -> Clause),
+    Cached(Clause, usize),
+    Fresh(fn(&T, Context) -> Clause, usize),
 }
 
 /// GuardCache assists with caching the computation of guard functions
@@ -30,8 +30,8 @@ impl<T> GuardCache<T> {
     }
     pub(crate) fn create_entry(g: Option<Guard<T>>, t: &T, ctx: Context) -> Option<CacheEntry<T>> {
         Some(match g? {
-            Guard::Cache(f) => CacheEntry::Cached(f(t, ctx)),
-            Guard::Fresh(f) => CacheEntry::Fresh(f),
+            Guard::Cache(f, w) => CacheEntry::Cached(f(t, ctx), w),
+            Guard::Fresh(f, w) => CacheEntry::Fresh(f, w),
         })
     }
     pub(crate) fn get(
@@ -39,24 +39,26 @@ impl<T> GuardCache<T> {
         t: &T,
         f: fn() -> Option<Guard<T>>,
         ctx: Context,
-    ) -> Option<Clause> {
-        Some(
-            match self
-                .cache
-                .entry(f as usize)
-                .or_insert_with(|| {
-                    Self::create_entry(
-                        f(),
-                        t,
-                        ctx.internal_clone(InternalCompilerTag { _secret: () }),
-                    )
-                })
-                .as_ref()?
-            {
-                CacheEntry::Cached(s) => s.clone(),
-                CacheEntry::Fresh(f) => f(t, ctx),
-            },
-        )
+    ) -> Option<(Clause, usize)> {
+        let hit = self.cache.contains_key(&(f as usize));
+        let entry = self
+            .cache
+            .entry(f as usize)
+            .or_insert_with(|| {
+                Self::create_entry(
+                    f(),
+                    t,
+                    ctx.internal_clone(InternalCompilerTag { _secret: () }),
+                )
+            })
+            .as_ref()?;
+        if let Some(tracer) = ctx.tracer() {
+            tracer.record_guard(ctx.path(), hit && matches!(entry, CacheEntry::Cached(..)));
+        }
+        Some(match entry {
+            CacheEntry::Cached(s, w) => (s.clone(), *w),
+            CacheEntry::Fresh(f, w) => (f(t, ctx), *w),
+        })
     }
 }
 
@@ -69,7 +71,7 @@ pub(crate) fn create_guards<T>(
     guards
         .iter()
         .zip((0..).flat_map(|i| ctx.derive(PathFragment::Branch(i)).ok()))
-        .filter_map(|(x, c)| gc.get(self_ref, *x, c))
+        .filter_map(|(x, c)| gc.get(self_ref, *x, c).map(|(clause, _)| clause))
         .filter(|x| *x != Clause::Trivial) // no point in using any Trivials
         .fold(Clause::Trivial, |acc, item| match acc {
             Clause::Trivial => item,
@@ -0,0 +1,294 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Incremental recompilation of a contract whose effects have changed.
+//!
+//! While compiling, every read of the `EffectDB` is recorded along with a
+//! fingerprint of what was read. Each contract compiled is stored along with
+//! the reads it depends on: those made in its own subtree, and those made by
+//! its ancestors at a path leading to it (which may have determined its
+//! arguments). Recompiling with the same `IncrementalCache` reuses any
+//! contract whose reads are all unchanged.
+//!
+//! An `IncrementalCache` must only be used to recompile the same contract,
+//! with the same arguments, differing only in effects (or the chain snapshot,
+//! `PathKeys` or `StandardnessPolicy`, any of which invalidates every stored
+//! contract). Effects
+//! read other than by the compiler, e.g. through
+//! `Context::get_effects_internal`, are not tracked.
+use super::InternalCompilerTag;
+use crate::contract::context::{ChainSnapshot, PathKeys, StandardnessPolicy};
+use crate::contract::{CompilationError, Compiled, Context};
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::util::amount::Amount;
+use sapio_base::effects::{EffectDB, EffectPath, PathFragment};
+use sapio_base::serialization_helpers::SArc;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The effect reads a contract depends on, and their fingerprints
+type Reads = HashMap<SArc<EffectPath>, sha256::Hash>;
+
+struct IncrementalEntry {
+    compiled: Compiled,
+    funds: Amount,
+    chain: Option<ChainSnapshot>,
+    keys: Option<PathKeys>,
+    standardness: StandardnessPolicy,
+    reads: Reads,
+}
+
+/// Stores the result of compiling each contract in a compilation, so that
+/// a later compilation with different effects can reuse the unaffected
+/// parts. Attach with `Context::with_incremental_cache`.
+#[derive(Default)]
+pub struct IncrementalCache {
+    entries: Mutex<HashMap<SArc<EffectPath>, Arc<IncrementalEntry>>>,
+    log: Mutex<Vec<(SArc<EffectPath>, sha256::Hash)>>,
+    reused: AtomicUsize,
+}
+
+/// Hash of everything at `path` in `effects`
+fn fingerprint(effects: &dyn EffectDB, path: &Arc<EffectPath>) -> sha256::Hash {
+    let values: BTreeMap<&str, _> = effects
+        .get_value(path)
+        .map(|(k, v)| (k.as_str(), v))
+        .collect();
+    sha256::Hash::hash(&serde_json::to_vec(&values).unwrap_or_default()[..])
+}
+
+fn is_prefix(a: &Arc<EffectPath>, b: &Arc<EffectPath>) -> bool {
+    let a: Vec<PathFragment> = a.as_ref().clone().into();
+    let b: Vec<PathFragment> = b.as_ref().clone().into();
+    b.starts_with(&a)
+}
+
+impl IncrementalCache {
+    /// Begin a new compilation, keeping the stored contracts
+    pub(crate) fn begin(&self) {
+        self.log.lock().unwrap().clear();
+        self.reused.store(0, Ordering::Relaxed);
+    }
+    /// The number of contracts reused, rather than recompiled, since the
+    /// cache was last attached to a `Context`
+    pub fn reused(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+    /// Record that the compiler read the effects at `path`
+    pub(crate) fn record_read(&self, path: &Arc<EffectPath>, effects: &dyn EffectDB) {
+        self.log
+            .lock()
+            .unwrap()
+            .push((SArc(path.clone()), fingerprint(effects, path)));
+    }
+    /// Compile the contract at `ctx.path()` with `f`, unless the stored
+    /// result for that path is still valid.
+    pub(crate) fn compile<F>(&self, ctx: Context, f: F) -> Result<Compiled, CompilationError>
+    where
+        F: FnOnce(Context) -> Result<Compiled, CompilationError>,
+    {
+        let path = SArc(ctx.path().clone());
+        let funds = ctx.funds();
+        let effects = ctx.get_effects(InternalCompilerTag { _secret: () }).clone();
+        let stored = self.entries.lock().unwrap().get(&path).cloned();
+        if let Some(entry) = stored {
+            if entry.funds == funds
+                && entry.chain.as_ref() == ctx.chain_snapshot()
+                && entry.keys.as_ref() == ctx.path_keys()
+                && &entry.standardness == ctx.standardness_policy()
+                && entry
+                    .reads
+                    .iter()
+                    .all(|(p, h)| fingerprint(effects.as_ref(), &p.0) == *h)
+            {
+                // traced and charged for as if it had been compiled afresh
+                let _span = ctx.tracer().map(|t| t.start(ctx.path()));
+                if let Some(limiter) = ctx.limiter() {
+                    limiter.charge(&entry.compiled)?;
+                }
+                // make our ancestors depend on the reads we skipped
+                self.log
+                    .lock()
+                    .unwrap()
+                    .extend(entry.reads.iter().map(|(p, h)| (p.clone(), *h)));
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                return Ok(entry.compiled.clone());
+            }
+        }
+        let start = self.log.lock().unwrap().len();
+        let chain = ctx.chain_snapshot().cloned();
+        let keys = ctx.path_keys().cloned();
+        let standardness = ctx.standardness_policy().clone();
+        let compiled = f(ctx)?;
+        let reads: Reads = {
+            let log = self.log.lock().unwrap();
+            log[..start]
+                .iter()
+                .filter(|(p, _)| is_prefix(&p.0, &path.0))
+                .chain(log[start..].iter())
+                .cloned()
+                .collect()
+        };
+        self.entries.lock().unwrap().insert(
+            path,
+            Arc::new(IncrementalEntry {
+                compiled: compiled.clone(),
+                funds,
+                chain,
+                keys,
+                standardness,
+                reads,
+            }),
+        );
+        Ok(compiled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{FinishOrFunc, Guard, ThenFunc, WebAPIEnabled};
+    use crate::contract::compiler::limits::{CompilationLimits, ResourceLimiter};
+    use crate::contract::test_util::key_at;
+    use crate::contract::{Compilable, DynamicContract, TxTmplIt};
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::marker::PhantomData;
+    /// a contract spendable by key `n`, or by paying to key `n + 1`
+    fn child(n: u8) -> DynamicContract<'static, (), u8> {
+        DynamicContract {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[],
+                    conditional_compile_if: &[],
+                    func: |n, ctx| {
+                        let amt = ctx.funds();
+                        ctx.template()
+                            .add_output(amt, &key_at(*n + 1), None)?
+                            .into()
+                    },
+                    name: Arc::new("next".into()),
+                    weight: 1,
+                })
+            }],
+            finish_or: vec![],
+            finish: vec![|| Some(Guard::Fresh(|n, _| Clause::Key(key_at(*n)), 1))],
+            data: n,
+        }
+    }
+    fn stable(_: &(), ctx: Context) -> TxTmplIt {
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &child(10), None)?.into()
+    }
+    /// pays to `child(n)` for each update `n`
+    fn update(_: &(), ctx: Context, n: Option<u8>) -> TxTmplIt {
+        match n {
+            Some(n) => {
+                let amt = ctx.funds();
+                ctx.template().add_output(amt, &child(n), None)?.into()
+            }
+            None => Ok(Box::new(std::iter::empty())),
+        }
+    }
+    fn root() -> DynamicContract<'static, Option<u8>, ()> {
+        DynamicContract {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[],
+                    conditional_compile_if: &[],
+                    func: stable,
+                    name: Arc::new("stable".into()),
+                    weight: 1,
+                })
+            }],
+            finish_or: vec![|| {
+                Some(Box::new(FinishOrFunc::<_, _, _, WebAPIEnabled> {
+                    coerce_args: Ok,
+                    guard: &[|| Some(Guard::Fresh(|_, _| Clause::Key(key_at(1)), 1))],
+                    conditional_compile_if: &[],
+                    func: update,
+                    schema: None,
+                    name: Arc::new("update".into()),
+                    weight: 1,
+                    f: PhantomData,
+                }))
+            }],
+            finish: vec![],
+            data: (),
+        }
+    }
+    fn compile(
+        effects: serde_json::Value,
+        cache: Option<&Arc<IncrementalCache>>,
+        limiter: Option<&ResourceLimiter>,
+    ) -> Compiled {
+        let mut ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(10000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("root").unwrap(),
+            Arc::new(serde_json::from_value::<MapEffectDB>(effects).unwrap()),
+        );
+        if let Some(cache) = cache {
+            ctx = ctx.with_incremental_cache(cache.clone());
+        }
+        if let Some(limiter) = limiter {
+            ctx = ctx.with_limiter(limiter.clone());
+        }
+        root().compile(ctx).unwrap()
+    }
+    #[test]
+    fn test_incremental() {
+        let effects = |n: u8| {
+            serde_json::json!({
+                "effects": {"root/@finish_or_fn/@suggested/update": {"a": n}}
+            })
+        };
+        let cache = Arc::new(IncrementalCache::default());
+        compile(effects(2), Some(&cache), None);
+        assert_eq!(cache.reused(), 0);
+        let incremental = compile(effects(3), Some(&cache), None);
+        // the root must be recompiled, but not the `stable` child
+        assert_eq!(cache.reused(), 1);
+        let scratch = compile(effects(3), None, None);
+        assert_eq!(
+            incremental.to_canonical_json().unwrap(),
+            scratch.to_canonical_json().unwrap()
+        );
+        // nothing changed, so the root itself is reused
+        compile(effects(3), Some(&cache), None);
+        assert_eq!(cache.reused(), 1);
+    }
+    #[test]
+    fn test_incremental_limits() {
+        let effects = || {
+            serde_json::json!({
+                "effects": {"root/@finish_or_fn/@suggested/update": {"a": 3}}
+            })
+        };
+        // the templates counted against a fresh limiter
+        let used = |cache: Option<&Arc<IncrementalCache>>| {
+            let limiter = ResourceLimiter::new(CompilationLimits {
+                max_templates: Some(100),
+                ..Default::default()
+            });
+            compile(effects(), cache, Some(&limiter));
+            let root = EffectPath::try_from("root").unwrap();
+            100 - limiter.remaining(&root).max_templates.unwrap()
+        };
+        let cache = Arc::new(IncrementalCache::default());
+        let scratch = used(Some(&cache));
+        assert!(scratch > 0);
+        // reusing the whole contract costs as much as compiling it
+        assert_eq!(used(Some(&cache)), scratch);
+        assert_eq!(cache.reused(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Enforcement of `CompilationLimits`, so that untrusted arguments can't
+//! make compilation run forever or exhaust memory.
+use super::trace::system_clock;
+use crate::contract::{CompilationError, Compiled};
+use sapio_base::effects::EffectPath;
+pub use sapio_base::limits::CompilationLimits;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Which limit was exceeded, and its value
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceLimit {
+    /// `CompilationLimits::max_depth`
+    Depth(usize),
+    /// `CompilationLimits::max_templates`
+    Templates(usize),
+    /// `CompilationLimits::max_millis`
+    Time(u64),
+}
+
+/// Tracks the resources used by a compilation against its limits. Clones
+/// share the same counters, so a limiter attached to a `Context` covers the
+/// whole compilation.
+#[derive(Clone)]
+pub struct ResourceLimiter {
+    limits: CompilationLimits,
+    templates: Arc<AtomicUsize>,
+    deadline: Option<u64>,
+    clock: fn() -> u64,
+}
+
+impl ResourceLimiter {
+    /// Enforce `limits`, starting the time limit now, using the system
+    /// clock.
+    ///
+    /// On targets without a system clock (e.g. WASM plugins), use
+    /// `ResourceLimiter::with_clock` instead.
+    pub fn new(limits: CompilationLimits) -> Self {
+        Self::with_clock(limits, system_clock)
+    }
+    /// Enforce `limits`, starting the time limit now, using a custom clock
+    /// which must return a monotonic time in microseconds.
+    pub fn with_clock(limits: CompilationLimits, clock: fn() -> u64) -> Self {
+        ResourceLimiter {
+            limits,
+            templates: Default::default(),
+            deadline: limits
+                .max_millis
+                .map(|ms| clock().saturating_add(ms.saturating_mul(1000))),
+            clock,
+        }
+    }
+    /// The limits being enforced
+    pub fn limits(&self) -> &CompilationLimits {
+        &self.limits
+    }
+    /// The limits left for a separate compilation continuing from `path`,
+    /// e.g. one run by a plugin, which starts from its own root path
+    pub fn remaining(&self, path: &EffectPath) -> CompilationLimits {
+        CompilationLimits {
+            max_depth: self
+                .limits
+                .max_depth
+                .map(|max| max.saturating_sub(path.iter().count())),
+            max_templates: self
+                .limits
+                .max_templates
+                .map(|max| max.saturating_sub(self.templates.load(Ordering::Relaxed))),
+            max_millis: self
+                .deadline
+                .map(|deadline| deadline.saturating_sub((self.clock)()) / 1000),
+        }
+    }
+    fn exceeded(limit: ResourceLimit, path: &Arc<EffectPath>) -> CompilationError {
+        CompilationError::ResourceLimitExceeded(limit, path.clone())
+    }
+    fn check_time(&self, path: &Arc<EffectPath>) -> Result<(), CompilationError> {
+        match (self.deadline, self.limits.max_millis) {
+            (Some(deadline), Some(ms)) if (self.clock)() > deadline => {
+                Err(Self::exceeded(ResourceLimit::Time(ms), path))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Check a newly derived path against the depth and time limits
+    pub(crate) fn check_path(&self, path: &Arc<EffectPath>) -> Result<(), CompilationError> {
+        if let Some(max) = self.limits.max_depth {
+            if path.iter().count() > max {
+                return Err(Self::exceeded(ResourceLimit::Depth(max), path));
+            }
+        }
+        self.check_time(path)
+    }
+    /// Count a template created by the branch at `path` against the template
+    /// and time limits
+    pub(crate) fn add_template(&self, path: &Arc<EffectPath>) -> Result<(), CompilationError> {
+        let count = self.templates.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.limits.max_templates {
+            if count > max {
+                return Err(Self::exceeded(ResourceLimit::Templates(max), path));
+            }
+        }
+        self.check_time(path)
+    }
+    /// Charge for the templates and paths of `compiled` (and its children),
+    /// as compiling it here would have, e.g. for a contract taken from a
+    /// cache or compiled by another plugin
+    pub fn charge(&self, compiled: &Compiled) -> Result<(), CompilationError> {
+        self.check_path(&compiled.root_path.0)?;
+        for path in compiled
+            .branches
+            .keys()
+            .chain(compiled.continue_apis.keys())
+        {
+            self.check_path(&path.0)?;
+        }
+        for tmpl in compiled
+            .ctv_to_tx
+            .values()
+            .chain(compiled.suggested_txs.values())
+        {
+            self.add_template(&compiled.root_path.0)?;
+            for out in tmpl.outputs.iter() {
+                self.charge(&out.contract)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::ThenFunc;
+    use crate::contract::test_util::ctx;
+    use crate::contract::{Compilable, Context, DynamicContract, TxTmplIt};
+    use crate::template::Template;
+    use std::convert::TryFrom;
+    /// a contract which only ever pays to itself, with no base case
+    fn forever() -> DynamicContract<'static, (), ()> {
+        DynamicContract {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[],
+                    conditional_compile_if: &[],
+                    func: pay_forever,
+                    name: Arc::new("forever".into()),
+                    weight: 1,
+                })
+            }],
+            finish_or: vec![],
+            finish: vec![],
+            data: (),
+        }
+    }
+    fn pay_forever(_: &(), ctx: Context) -> TxTmplIt {
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &forever(), None)?.into()
+    }
+    /// a contract with a branch which produces templates without end
+    fn endless() -> DynamicContract<'static, (), ()> {
+        DynamicContract {
+            then: vec![|| {
+                Some(ThenFunc {
+                    guard: &[],
+                    conditional_compile_if: &[],
+                    func: endless_templates,
+                    name: Arc::new("endless".into()),
+                    weight: 1,
+                })
+            }],
+            finish_or: vec![],
+            finish: vec![],
+            data: (),
+        }
+    }
+    fn endless_templates(_: &(), ctx: Context) -> TxTmplIt {
+        let t: Template = ctx.template().into();
+        Ok(Box::new(std::iter::repeat_with(move || Ok(t.clone()))))
+    }
+    fn compile_with(
+        contract: DynamicContract<'static, (), ()>,
+        limits: CompilationLimits,
+    ) -> Result<(), CompilationError> {
+        let ctx = ctx().with_limiter(ResourceLimiter::new(limits));
+        contract.compile(ctx).map(|_| ())
+    }
+    #[test]
+    fn test_limits() {
+        match compile_with(
+            forever(),
+            CompilationLimits {
+                max_depth: Some(20),
+                ..Default::default()
+            },
+        ) {
+            Err(CompilationError::ResourceLimitExceeded(ResourceLimit::Depth(20), path)) => {
+                assert_eq!(path.iter().count(), 21)
+            }
+            _ => panic!("expected the depth limit to be exceeded"),
+        }
+        match compile_with(
+            endless(),
+            CompilationLimits {
+                max_templates: Some(5),
+                ..Default::default()
+            },
+        ) {
+            Err(CompilationError::ResourceLimitExceeded(ResourceLimit::Templates(5), _)) => {}
+            _ => panic!("expected the template limit to be exceeded"),
+        }
+    }
+    #[test]
+    fn test_remaining() {
+        let limiter = ResourceLimiter::new(CompilationLimits {
+            max_depth: Some(5),
+            max_templates: Some(3),
+            ..Default::default()
+        });
+        let path = Arc::new(EffectPath::try_from("root/a").unwrap());
+        limiter.add_template(&path).unwrap();
+        assert_eq!(
+            limiter.remaining(&path),
+            CompilationLimits {
+                max_depth: Some(3),
+                max_templates: Some(2),
+                max_millis: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,356 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A content addressed cache for compiling structurally identical
+//! sub-contracts only once.
+//!
+//! Wrap a child in `Memoized` and attach a `CompileCache` to the `Context`
+//! with `Context::with_compile_cache` to use it.
+use super::{Compilable, InternalCompilerTag};
+use crate::contract::abi::diagnostics::Diagnostic;
+use crate::contract::context::{ChainSnapshot, StandardnessPolicy};
+use crate::contract::{CompilationError, Compiled, Context};
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use sapio_base::effects::{EffectPath, PathFragment};
+use sapio_base::serialization_helpers::SArc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A compiled contract along with the path it was compiled at, so that it
+/// may be moved to the path of a later request.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MemoEntry {
+    /// the path the contract was compiled at
+    pub path: SArc<EffectPath>,
+    /// the result of compilation
+    pub compiled: Compiled,
+}
+
+/// A backend for storing compiled contracts by content hash.
+///
+/// Backends are best effort: failing to store an entry is not an error, it
+/// only results in a cache miss later.
+pub trait CompileCache: Send + Sync {
+    /// look up a previously compiled contract
+    fn get(&self, key: &sha256::Hash) -> Option<MemoEntry>;
+    /// store a compiled contract
+    fn put(&self, key: sha256::Hash, entry: MemoEntry);
+}
+
+/// The default `CompileCache`, which lasts as long as the process.
+#[derive(Default)]
+pub struct InMemoryCompileCache {
+    entries: Mutex<HashMap<sha256::Hash, Arc<MemoEntry>>>,
+}
+
+impl CompileCache for InMemoryCompileCache {
+    fn get(&self, key: &sha256::Hash) -> Option<MemoEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .map(|e| e.as_ref().clone())
+    }
+    fn put(&self, key: sha256::Hash, entry: MemoEntry) {
+        self.entries.lock().unwrap().insert(key, Arc::new(entry));
+    }
+}
+
+/// A `CompileCache` which stores each entry as a JSON file in a directory,
+/// so that entries survive across runs.
+///
+/// The cache key does not cover the code of the contract itself, so the
+/// directory must be cleared whenever contract logic changes.
+pub struct DiskCompileCache {
+    dir: PathBuf,
+}
+
+impl DiskCompileCache {
+    /// Use (and create, if needed) `dir` for storing entries.
+    pub fn new(dir: PathBuf) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(DiskCompileCache { dir })
+    }
+    fn file(&self, key: &sha256::Hash) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl CompileCache for DiskCompileCache {
+    fn get(&self, key: &sha256::Hash) -> Option<MemoEntry> {
+        let bytes = std::fs::read(self.file(key)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+    fn put(&self, key: sha256::Hash, entry: MemoEntry) {
+        if let Ok(bytes) = serde_json::to_vec(&entry) {
+            // write then rename so that concurrent runs never see a partial
+            // entry.
+            let tmp = self.dir.join(format!("{}.json.tmp", key));
+            if std::fs::write(&tmp, bytes).is_ok() {
+                let _ = std::fs::rename(&tmp, self.file(&key));
+            }
+        }
+    }
+}
+
+/// A `CompileCache` attached to a `Context`, along with the identity of the
+/// `Context`'s emulator.
+#[derive(Clone)]
+pub(crate) struct AttachedCache {
+    backend: Arc<dyn CompileCache>,
+    emulator: sha256::Hash,
+}
+
+impl AttachedCache {
+    /// The emulator is identified by the clause it would use for the all
+    /// zeros template hash.
+    pub(crate) fn new(
+        backend: Arc<dyn CompileCache>,
+        ctx: &Context,
+    ) -> Result<Self, CompilationError> {
+        let clause = ctx.ctv_emulator(sha256::Hash::default())?;
+        let emulator =
+            sha256::Hash::hash(&serde_json::to_vec(&clause).map_err(CompilationError::custom)?[..]);
+        Ok(AttachedCache { backend, emulator })
+    }
+}
+
+#[derive(Serialize)]
+struct KeyPreimage<'a, T> {
+    version: &'static str,
+    contract: &'static str,
+    arguments: &'a T,
+    amount: u64,
+    network: u32,
+    emulator: sha256::Hash,
+    chain: Option<&'a ChainSnapshot>,
+    standardness: &'a StandardnessPolicy,
+}
+
+/// Wraps a `Compilable` so that compiling it consults the `Context`'s
+/// `CompileCache`, if any. The cache key covers the serialized arguments, the
+/// type of the contract, the amount, the network, the emulator, the chain
+/// snapshot, and the standardness policy.
+///
+/// A cache hit is still charged against the `Context`'s `ResourceLimiter`,
+/// if any, for the templates and path depth of the cached contract.
+///
+/// The cache is bypassed whenever the `Context` carries effects, as those may
+/// alter what the contract compiles to, or `PathKeys`, as keys derived from
+/// them depend on where the contract is compiled.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(transparent)]
+pub struct Memoized<T>(pub T);
+
+impl<T> Compilable for Memoized<T>
+where
+    T: Compilable + Serialize,
+{
+    fn compile(&self, ctx: Context) -> Result<Compiled, CompilationError> {
+        let cache = match ctx.compile_cache() {
+            Some(cache)
+                if ctx.path_keys().is_none()
+                    && ctx
+                        .get_effects(InternalCompilerTag { _secret: () })
+                        .is_empty() =>
+            {
+                cache.clone()
+            }
+            _ => return self.0.compile(ctx),
+        };
+        let key = sha256::Hash::hash(
+            &serde_json::to_vec(&KeyPreimage {
+                version: env!("CARGO_PKG_VERSION"),
+                contract: std::any::type_name::<T>(),
+                arguments: &self.0,
+                amount: ctx.funds().as_sat(),
+                network: ctx.network.magic(),
+                emulator: cache.emulator,
+                chain: ctx.chain_snapshot(),
+                standardness: ctx.standardness_policy(),
+            })
+            .map_err(CompilationError::custom)?[..],
+        );
+        let path = ctx.path().clone();
+        if let Some(MemoEntry {
+            path: from,
+            mut compiled,
+        }) = cache.backend.get(&key)
+        {
+            if from.0 != path {
+                let from: Vec<PathFragment> = from.0.as_ref().clone().into();
+                rebase(&mut compiled, &from, &path);
+            }
+            if let Some(limiter) = ctx.limiter() {
+                limiter.charge(&compiled)?;
+            }
+            return Ok(compiled);
+        }
+        let compiled = self.0.compile(ctx)?;
+        cache.backend.put(
+            key,
+            MemoEntry {
+                path: SArc(path),
+                compiled: compiled.clone(),
+            },
+        );
+        Ok(compiled)
+    }
+}
+
+/// Move every path in `compiled` (and its children) beneath `from` to be
+/// beneath `to` instead.
+fn rebase(compiled: &mut Compiled, from: &[PathFragment], to: &Arc<EffectPath>) {
+    let rebase_path = |p: &Arc<EffectPath>| -> Arc<EffectPath> {
+        let v: Vec<PathFragment> = p.as_ref().clone().into();
+        if v.starts_with(from) {
+            v[from.len()..].iter().fold(to.clone(), |acc, frag| {
+                EffectPath::push(Some(acc), frag.clone())
+            })
+        } else {
+            p.clone()
+        }
+    };
+    compiled.root_path = SArc(rebase_path(&compiled.root_path.0));
+    compiled.continue_apis = std::mem::take(&mut compiled.continue_apis)
+        .into_iter()
+        .map(|(k, mut v)| {
+            v.path = rebase_path(&v.path);
+            (SArc(rebase_path(&k.0)), v)
+        })
+        .collect();
+    compiled.witness_size_estimates = std::mem::take(&mut compiled.witness_size_estimates)
+        .into_iter()
+        .map(|(k, v)| (SArc(rebase_path(&k.0)), v))
+        .collect();
+    compiled.branches = std::mem::take(&mut compiled.branches)
+        .into_iter()
+        .map(|(k, v)| (SArc(rebase_path(&k.0)), v))
+        .collect();
+    for d in compiled.diagnostics.iter_mut() {
+        match d {
+            Diagnostic::PrunedBranch { path, .. }
+            | Diagnostic::DustOutput { path, .. }
+            | Diagnostic::NonStandard { path, .. } => {
+                *path = SArc(rebase_path(&path.0));
+            }
+            Diagnostic::DuplicateGuard { path, duplicate_of } => {
+                *path = SArc(rebase_path(&path.0));
+                *duplicate_of = SArc(rebase_path(&duplicate_of.0));
+            }
+        }
+    }
+    for tmpl in compiled
+        .ctv_to_tx
+        .values_mut()
+        .chain(compiled.suggested_txs.values_mut())
+    {
+        for out in tmpl.outputs.iter_mut() {
+            rebase(&mut out.contract, from, to);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::compiler::limits::ResourceLimiter;
+    use crate::contract::test_util::{ctx, key, pay_contract};
+    use bitcoin::Amount;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    #[derive(Default)]
+    struct CountingCache {
+        inner: InMemoryCompileCache,
+        hits: AtomicUsize,
+    }
+    impl CompileCache for CountingCache {
+        fn get(&self, key: &sha256::Hash) -> Option<MemoEntry> {
+            let r = self.inner.get(key);
+            if r.is_some() {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+            }
+            r
+        }
+        fn put(&self, key: sha256::Hash, entry: MemoEntry) {
+            self.inner.put(key, entry)
+        }
+    }
+    #[test]
+    fn test_memoized() {
+        let key = key();
+        let cache = Arc::new(CountingCache::default());
+        let mut ctx = ctx().with_compile_cache(cache.clone()).unwrap();
+        let a = Memoized(key)
+            .compile(ctx.derive_num(0u64).unwrap())
+            .unwrap();
+        let b = Memoized(key)
+            .compile(ctx.derive_num(1u64).unwrap())
+            .unwrap();
+        assert_eq!(cache.hits.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            serde_json::to_value(&a).unwrap(),
+            serde_json::to_value(&b).unwrap()
+        );
+        // a different amount is a different contract
+        Memoized(key)
+            .compile(
+                ctx.derive_num(2u64)
+                    .unwrap()
+                    .with_amount(Amount::from_sat(1))
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(cache.hits.load(Ordering::SeqCst), 1);
+    }
+    #[test]
+    fn test_memoized_limits() {
+        use crate::contract::compiler::limits::{CompilationLimits, ResourceLimit};
+        let cache = Arc::new(CountingCache::default());
+        let mut ctx = ctx().with_compile_cache(cache.clone()).unwrap();
+        // a contract with one template
+        let compiled = pay_contract()
+            .compile(ctx.derive_str(Arc::new("pay".into())).unwrap())
+            .unwrap();
+        Memoized(compiled.clone())
+            .compile(ctx.derive_num(0u64).unwrap())
+            .unwrap();
+        let limited = ctx
+            .derive_num(1u64)
+            .unwrap()
+            .with_limiter(ResourceLimiter::new(CompilationLimits {
+                max_templates: Some(0),
+                ..Default::default()
+            }));
+        assert!(matches!(
+            Memoized(compiled).compile(limited),
+            Err(CompilationError::ResourceLimitExceeded(
+                ResourceLimit::Templates(0),
+                _
+            ))
+        ));
+        assert_eq!(cache.hits.load(Ordering::SeqCst), 1);
+    }
+    #[test]
+    fn test_rebase() {
+        let p = |s: &str| Arc::new(EffectPath::try_from(s).unwrap());
+        let mut compiled = Compiled::from_address(
+            bitcoin::Address::p2wpkh(&key(), bitcoin::Network::Regtest).unwrap(),
+            None,
+        );
+        compiled.root_path = SArc(p("root/#0/@then_fn"));
+        compiled.witness_size_estimates.insert(SArc(p("other")), 1);
+        let from: Vec<PathFragment> = p("root/#0").as_ref().clone().into();
+        rebase(&mut compiled, &from, &p("root/#1"));
+        assert_eq!(compiled.root_path, SArc(p("root/#1/@then_fn")));
+        assert!(compiled
+            .witness_size_estimates
+            .contains_key(&SArc(p("other"))));
+    }
+}
@@ -11,20 +11,30 @@ use super::CompilationError;
 use super::Compiled;
 use super::Context;
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::diagnostics::{Diagnostic, PruneReason};
 use crate::contract::actions::conditional_compile::CCILWrapper;
 use crate::contract::actions::CallableAsFoF;
 use crate::contract::TxTmplIt;
+use crate::template::standardness::CheckStandardness;
+use crate::template::StandardnessMode;
 use crate::util::amountrange::AmountRange;
 use ::miniscript::*;
-use sapio_base::effects::EffectDB;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
 use sapio_base::serialization_helpers::SArc;
 use sapio_base::Clause;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::collections::LinkedList;
 mod cache;
 use cache::*;
+pub mod incremental;
+pub mod limits;
+pub mod memo;
+pub mod parallel;
+use parallel::MaybeSync;
+pub mod trace;
+mod weights;
 /// Used to prevent unintended callers to internal_clone.
 pub struct InternalCompilerTag {
     _secret: (),
@@ -37,6 +47,7 @@ mod private {
     /// Allow Contract to implement Compile
     impl ImplSeal for super::Compiled {}
     impl ImplSeal for bitcoin::PublicKey {}
+    impl<T> ImplSeal for super::memo::Memoized<T> {}
     impl<'a, C> ImplSeal for C where C: super::AnyContract {}
 }
 /// Compilable is a trait for anything which can be compiled
@@ -62,7 +73,7 @@ impl Compilable for bitcoin::PublicKey {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 enum CTVRequired {
     Yes,
     No,
@@ -73,6 +84,25 @@ enum Nullable {
     No,
 }
 
+/// Reports branches whose clause is the same as one in `seen` or earlier in
+/// `branches`.
+fn report_duplicate_branches(
+    branches: &[(Clause, usize, SArc<EffectPath>)],
+    seen: &mut Vec<(Clause, SArc<EffectPath>)>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    for (clause, _, path) in branches {
+        if let Some((_, duplicate_of)) = seen.iter().find(|(c, _)| c == clause) {
+            diagnostics.push(Diagnostic::DuplicateGuard {
+                path: path.clone(),
+                duplicate_of: duplicate_of.clone(),
+            });
+        } else {
+            seen.push((clause.clone(), path.clone()));
+        }
+    }
+}
+
 fn compute_all_effects<C, A: Default>(
     mut top_effect_ctx: Context,
     self_ref: &C,
@@ -80,6 +110,14 @@ fn compute_all_effects<C, A: Default>(
 ) -> TxTmplIt {
     let mut applied_effects_ctx = top_effect_ctx.derive(PathFragment::Effects)?;
     let default_applied_effect_ctx = top_effect_ctx.derive(PathFragment::DefaultEffect)?;
+    if let Some(cache) = top_effect_ctx.incremental() {
+        cache.record_read(
+            top_effect_ctx.path(),
+            top_effect_ctx
+                .get_effects(InternalCompilerTag { _secret: () })
+                .as_ref(),
+        );
+    }
     top_effect_ctx
         .get_effects(InternalCompilerTag { _secret: () })
         .get_value(top_effect_ctx.path())
@@ -106,165 +144,234 @@ fn compute_all_effects<C, A: Default>(
 impl<'a, T> Compilable for T
 where
     T: AnyContract + 'a,
-    T::Ref: 'a,
+    T::Ref: 'a + MaybeSync,
 {
-    /// The main Compilation Logic for a Contract.
-    /// TODO: Better Document Semantics
-    fn compile(&self, mut ctx: Context) -> Result<Compiled, CompilationError> {
-        let self_ref = self.get_inner_ref();
+    /// Compile the contract, reusing the result of a previous compilation if
+    /// an `IncrementalCache` is attached and none of the effects it read
+    /// changed.
+    fn compile(&self, ctx: Context) -> Result<Compiled, CompilationError> {
+        match ctx.incremental().cloned() {
+            Some(cache) => cache.compile(ctx, |ctx| compile_contract(self, ctx)),
+            None => compile_contract(self, ctx),
+        }
+    }
+}
 
-        let guard_clauses = std::cell::RefCell::new(GuardCache::new());
+/// The main Compilation Logic for a Contract.
+/// TODO: Better Document Semantics
+fn compile_contract<'a, T>(this: &T, mut ctx: Context) -> Result<Compiled, CompilationError>
+where
+    T: AnyContract + 'a,
+    T::Ref: 'a + MaybeSync,
+{
+    let _span = ctx.tracer().map(|t| t.start(ctx.path()));
+    let self_ref = this.get_inner_ref();
 
-        // The code for then_fns and finish_or_fns is very similar, differing
-        // only in that then_fns have a CTV enforcing the contract and
-        // finish_or_fns do not. We can lazily chain iterators to process them
-        // in a row.
-        let then_fns: Vec<_> = {
-            let mut then_fn_ctx = ctx.derive(PathFragment::ThenFn)?;
-            let mut conditional_compile_ctx = then_fn_ctx.derive(PathFragment::CondCompIf)?;
-            let mut guards_ctx = then_fn_ctx.derive(PathFragment::Guard)?;
-            let mut next_tx_ctx = then_fn_ctx.derive(PathFragment::Next)?;
-            self.then_fns()
-                .iter()
-                .filter_map(|func| func())
-                .flat_map(|func| {
-                    let name = PathFragment::Named(SArc(func.name.clone()));
-                    conditional_compile_ctx
-                        .derive(name.clone())
-                        .map(|mut this_ctx| {
-                            match CCILWrapper(func.conditional_compile_if)
-                                .assemble(self_ref, &mut this_ctx)
-                            {
-                                ConditionalCompileType::Fail(errors) => {
-                                    Some((func, name, (errors, Nullable::No)))
-                                }
-                                ConditionalCompileType::Required
-                                | ConditionalCompileType::NoConstraint => {
-                                    Some((func, name, (LinkedList::new(), Nullable::No)))
-                                }
-                                ConditionalCompileType::Nullable => {
-                                    Some((func, name, (LinkedList::new(), Nullable::Yes)))
-                                }
-                                ConditionalCompileType::Skippable
-                                | ConditionalCompileType::Never => None,
+    let guard_clauses = std::cell::RefCell::new(GuardCache::new());
+
+    // The code for then_fns and finish_or_fns is very similar, differing
+    // only in that then_fns have a CTV enforcing the contract and
+    // finish_or_fns do not. We can lazily chain iterators to process them
+    // in a row.
+    let then_fns: Vec<_> = {
+        let mut then_fn_ctx = ctx.derive(PathFragment::ThenFn)?;
+        let mut conditional_compile_ctx = then_fn_ctx.derive(PathFragment::CondCompIf)?;
+        let mut guards_ctx = then_fn_ctx.derive(PathFragment::Guard)?;
+        let mut next_tx_ctx = then_fn_ctx.derive(PathFragment::Next)?;
+        this.then_fns()
+            .iter()
+            .filter_map(|func| func())
+            .flat_map(|func| {
+                let name = PathFragment::Named(SArc(func.name.clone()));
+                conditional_compile_ctx
+                    .derive(name.clone())
+                    .map(|mut this_ctx| {
+                        match CCILWrapper(func.conditional_compile_if)
+                            .assemble(self_ref, &mut this_ctx)
+                        {
+                            ConditionalCompileType::Fail(errors) => {
+                                Some((func, name, (errors, Nullable::No)))
+                            }
+                            ConditionalCompileType::Required
+                            | ConditionalCompileType::NoConstraint => {
+                                Some((func, name, (LinkedList::new(), Nullable::No)))
                             }
-                        })
-                        .transpose()
+                            ConditionalCompileType::Nullable => {
+                                Some((func, name, (LinkedList::new(), Nullable::Yes)))
+                            }
+                            ConditionalCompileType::Skippable | ConditionalCompileType::Never => {
+                                None
+                            }
+                        }
+                    })
+                    .transpose()
+            })
+            .map(|r| {
+                r.and_then(|(func, name, (errors, nullability))| {
+                    let gctx = guards_ctx.derive(name.clone())?;
+                    let ntx_ctx = next_tx_ctx.derive(name)?;
+                    let branch = SArc(ntx_ctx.path().clone());
+                    let guards =
+                        create_guards(self_ref, gctx, func.guard, &mut guard_clauses.borrow_mut());
+                    Ok((
+                        nullability,
+                        CTVRequired::Yes,
+                        guards,
+                        if errors.is_empty() {
+                            Ok((func.func, ntx_ctx))
+                        } else {
+                            Err(CompilationError::ConditionalCompilationFailed(errors))
+                        },
+                        func.weight,
+                        branch,
+                    ))
                 })
-                .map(|r| {
-                    r.and_then(|(func, name, (errors, nullability))| {
-                        let gctx = guards_ctx.derive(name.clone())?;
-                        let ntx_ctx = next_tx_ctx.derive(name)?;
-                        let guards = create_guards(
-                            self_ref,
-                            gctx,
-                            func.guard,
-                            &mut guard_clauses.borrow_mut(),
-                        );
-                        Ok((
-                            nullability,
-                            CTVRequired::Yes,
-                            guards,
+            })
+            .collect::<Result<Vec<_>, CompilationError>>()?
+    };
+    // Sibling then_fns share no state, so in parallel mode all of their
+    // templates are generated up front on the thread pool. Every path was
+    // derived above, so derivation order remains deterministic.
+    let then_fns: Vec<_> = if parallel::enabled(&ctx) {
+        let (then_fns, jobs): (Vec<_>, Vec<_>) = then_fns
+            .into_iter()
+            .map(|(n, c, g, job, w, b)| ((n, c, g, w, b), job))
+            .unzip();
+        let templates = parallel::map(true, jobs, |job| {
+            job.and_then(|(f, ctx)| f(self_ref, ctx)?.collect::<Result<Vec<_>, _>>())
+        });
+        then_fns
+            .into_iter()
+            .zip(templates)
+            .map(|((n, c, g, w, b), r)| {
+                let txtmpls: TxTmplIt = r.map(|v| {
+                    let it: Box<dyn Iterator<Item = _>> = Box::new(v.into_iter().map(Ok));
+                    it
+                });
+                (n, c, g, txtmpls, w, b)
+            })
+            .collect()
+    } else {
+        then_fns
+            .into_iter()
+            .map(|(n, c, g, job, w, b)| (n, c, g, job.and_then(|(f, ctx)| f(self_ref, ctx)), w, b))
+            .collect()
+    };
+    // finish_or_fns may be used to compute additional transactions with
+    // a given argument, but for building the ABI we only precompute with
+    // the default argument.
+    let (continue_apis, finish_or_fns): (
+        HashMap<SArc<EffectPath>, ContinuationPoint>,
+        Vec<(
+            Nullable,
+            CTVRequired,
+            Clause,
+            TxTmplIt,
+            usize,
+            SArc<EffectPath>,
+        )>,
+    ) = {
+        let mut finish_or_fns_ctx = ctx.derive(PathFragment::FinishOrFn)?;
+        let mut conditional_compile_ctx = finish_or_fns_ctx.derive(PathFragment::CondCompIf)?;
+        let mut guard_ctx = finish_or_fns_ctx.derive(PathFragment::Guard)?;
+        let mut suggested_tx_ctx = finish_or_fns_ctx.derive(PathFragment::Suggested)?;
+        this.finish_or_fns()
+            .iter()
+            .filter_map(|func| func())
+            // TODO: De-duplicate this code?
+            .filter_map(|func| {
+                let name = PathFragment::Named(SArc(func.get_name().clone()));
+                conditional_compile_ctx
+                    .derive(name.clone())
+                    .map(|mut this_ctx| {
+                        let constraint = CCILWrapper(func.get_conditional_compile_if())
+                            .assemble(self_ref, &mut this_ctx);
+                        match constraint {
+                            ConditionalCompileType::Fail(errors) => Some((func, name, errors)),
+                            ConditionalCompileType::Required
+                            | ConditionalCompileType::NoConstraint
+                            | ConditionalCompileType::Nullable => {
+                                Some((func, name, LinkedList::new()))
+                            }
+                            ConditionalCompileType::Skippable | ConditionalCompileType::Never => {
+                                None
+                            }
+                        }
+                    })
+                    .transpose()
+            })
+            .map(|r| {
+                r.and_then(|(func, name, errors)| {
+                    let top_effect_ctx = suggested_tx_ctx.derive(name.clone())?;
+                    let branch = SArc(top_effect_ctx.path().clone());
+                    let guard = create_guards(
+                        self_ref,
+                        guard_ctx.derive(name)?,
+                        func.get_guard(),
+                        &mut guard_clauses.borrow_mut(),
+                    );
+                    Ok((
+                        (
+                            SArc(top_effect_ctx.path().clone()),
+                            ContinuationPoint::at(
+                                func.get_schema().clone(),
+                                top_effect_ctx.path().clone(),
+                            ),
+                        ),
+                        (
+                            Nullable::Yes,
+                            CTVRequired::No,
+                            guard,
                             if errors.is_empty() {
-                                (func.func)(self_ref, ntx_ctx)
+                                compute_all_effects(top_effect_ctx, self_ref, func.as_ref())
                             } else {
                                 Err(CompilationError::ConditionalCompilationFailed(errors))
                             },
-                        ))
-                    })
+                            func.get_weight(),
+                            branch,
+                        ),
+                    ))
                 })
-                .collect::<Result<Vec<_>, CompilationError>>()?
-        };
-        // finish_or_fns may be used to compute additional transactions with
-        // a given argument, but for building the ABI we only precompute with
-        // the default argument.
-        let (continue_apis, finish_or_fns): (
-            HashMap<SArc<EffectPath>, ContinuationPoint>,
-            Vec<(Nullable, CTVRequired, Clause, TxTmplIt)>,
-        ) = {
-            let mut finish_or_fns_ctx = ctx.derive(PathFragment::FinishOrFn)?;
-            let mut conditional_compile_ctx = finish_or_fns_ctx.derive(PathFragment::CondCompIf)?;
-            let mut guard_ctx = finish_or_fns_ctx.derive(PathFragment::Guard)?;
-            let mut suggested_tx_ctx = finish_or_fns_ctx.derive(PathFragment::Suggested)?;
-            self.finish_or_fns()
-                .iter()
-                .filter_map(|func| func())
-                // TODO: De-duplicate this code?
-                .filter_map(|func| {
-                    let name = PathFragment::Named(SArc(func.get_name().clone()));
-                    conditional_compile_ctx
-                        .derive(name.clone())
-                        .map(|mut this_ctx| {
-                            let constraint = CCILWrapper(func.get_conditional_compile_if())
-                                .assemble(self_ref, &mut this_ctx);
-                            match constraint {
-                                ConditionalCompileType::Fail(errors) => Some((func, name, errors)),
-                                ConditionalCompileType::Required
-                                | ConditionalCompileType::NoConstraint
-                                | ConditionalCompileType::Nullable => {
-                                    Some((func, name, LinkedList::new()))
-                                }
-                                ConditionalCompileType::Skippable
-                                | ConditionalCompileType::Never => None,
-                            }
-                        })
-                        .transpose()
-                })
-                .map(|r| {
-                    r.and_then(|(func, name, errors)| {
-                        let top_effect_ctx = suggested_tx_ctx.derive(name.clone())?;
-                        let guard = create_guards(
-                            self_ref,
-                            guard_ctx.derive(name)?,
-                            func.get_guard(),
-                            &mut guard_clauses.borrow_mut(),
-                        );
-                        Ok((
-                            (
-                                SArc(top_effect_ctx.path().clone()),
-                                ContinuationPoint::at(
-                                    func.get_schema().clone(),
-                                    top_effect_ctx.path().clone(),
-                                ),
-                            ),
-                            (
-                                Nullable::Yes,
-                                CTVRequired::No,
-                                guard,
-                                if errors.is_empty() {
-                                    compute_all_effects(top_effect_ctx, self_ref, func.as_ref())
-                                } else {
-                                    Err(CompilationError::ConditionalCompilationFailed(errors))
-                                },
-                            ),
-                        ))
-                    })
-                })
-                .collect::<Result<
-                    Vec<(
-                        (SArc<EffectPath>, ContinuationPoint),
-                        (Nullable, CTVRequired, Clause, TxTmplIt),
-                    )>,
-                    CompilationError,
-                >>()?
-                .into_iter()
-                .unzip()
-        };
+            })
+            .collect::<Result<
+                Vec<(
+                    (SArc<EffectPath>, ContinuationPoint),
+                    (
+                        Nullable,
+                        CTVRequired,
+                        Clause,
+                        TxTmplIt,
+                        usize,
+                        SArc<EffectPath>,
+                    ),
+                )>,
+                CompilationError,
+            >>()?
+            .into_iter()
+            .unzip()
+    };
 
-        let mut ctv_to_tx = HashMap::new();
-        let mut suggested_txs = HashMap::new();
-        let mut amount_range = AmountRange::new();
+    let mut ctv_to_tx = HashMap::new();
+    let mut suggested_txs = HashMap::new();
+    let mut amount_range = AmountRange::new();
+    let mut diagnostics = vec![];
+    // templates which pay a feerate, and the branch spending to them
+    let mut feerate_templates = vec![];
+    // every distinct template, and the first branch spending to it
+    let mut new_templates = vec![];
 
-        // If no guards and not CTV, then nothing gets added (not interpreted as Trivial True)
-        // If CTV and no guards, just CTV added.
-        // If CTV and guards, CTV & guards added.
-        let mut clause_accumulator = then_fns
-            .into_iter()
-            .chain(finish_or_fns.into_iter())
-            .map(|(nullability, uses_ctv, guards, r_txtmpls)| {
+    // If no guards and not CTV, then nothing gets added (not interpreted as Trivial True)
+    // If CTV and no guards, just CTV added.
+    // If CTV and guards, CTV & guards added.
+    let clause_accumulator = then_fns
+        .into_iter()
+        .chain(finish_or_fns.into_iter())
+        .map(
+            |(nullability, uses_ctv, guards, r_txtmpls, weight, branch)| {
                 // Compute all guard clauses.
                 // Don't use a threshold here because then miniscript will just
                 // re-compile it into the And for again, causing extra allocations.
+                let unsatisfiable_guard = guards == Clause::Unsatisfiable;
                 let mut guard = guards;
 
                 // it would be an error if any of r_txtmpls is an error instead of just an empty
@@ -272,18 +379,33 @@ where
                 let mut txtmpl_clauses = r_txtmpls?
                     .map(|r_txtmpl| {
                         let txtmpl = r_txtmpl?;
+                        if let Some(limiter) = ctx.limiter() {
+                            limiter.add_template(&branch.0)?;
+                        }
                         let h = txtmpl.hash();
-                        let txtmpl = match uses_ctv {
+                        if txtmpl.feerate_sats_vbyte.is_some() {
+                            feerate_templates.push((uses_ctv, h, branch.clone()));
+                        }
+                        let txtmpl = match match uses_ctv {
                             CTVRequired::Yes => &mut ctv_to_tx,
                             CTVRequired::No => &mut suggested_txs,
                         }
                         .entry(h)
-                        .or_insert(txtmpl);
+                        {
+                            Entry::Occupied(e) => e.into_mut(),
+                            Entry::Vacant(e) => {
+                                new_templates.push((uses_ctv, h, branch.clone()));
+                                e.insert(txtmpl)
+                            }
+                        };
                         amount_range.update_range(txtmpl.max);
                         ctx.ctv_emulator(h)
                     })
                     // Forces any error to abort the whole thing
                     .collect::<Result<Vec<_>, CompilationError>>()?;
+                if let Some(tracer) = ctx.tracer() {
+                    tracer.record_templates(&branch, txtmpl_clauses.len());
+                }
                 if uses_ctv == CTVRequired::Yes {
                     guard = match nullability {
                         Nullable::Yes if txtmpl_clauses.is_empty() => {
@@ -307,73 +429,363 @@ where
                         }
                     };
                 }
-                Ok(guard)
-            })
-            .filter_map(|func| {
-                if let Ok(Clause::Unsatisfiable) = func {
-                    None
-                } else {
-                    Some(func)
+                // Dead branches are pruned below, so note why.
+                if guard == Clause::Unsatisfiable {
+                    diagnostics.push(Diagnostic::PrunedBranch {
+                        path: branch.clone(),
+                        reason: if unsatisfiable_guard {
+                            PruneReason::Unsatisfiable
+                        } else {
+                            PruneReason::NoTemplates
+                        },
+                    });
                 }
+                Ok((guard, weight, branch))
+            },
+        )
+        .filter_map(|func| {
+            if let Ok((Clause::Unsatisfiable, _, _)) = func {
+                None
+            } else {
+                Some(func)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let finish_fns: Vec<_> = {
+        let mut finish_fns_ctx = ctx.derive(PathFragment::FinishFn)?;
+        // Compute all finish_functions at this level, caching if requested.
+        this.finish_fns()
+            .iter()
+            // note that this zip with would loop forever if there were to be a bug here
+            .zip((0..).filter_map(|i| finish_fns_ctx.derive(PathFragment::Branch(i as u64)).ok()))
+            .filter_map(|(func, c)| {
+                let branch = SArc(c.path().clone());
+                guard_clauses
+                    .borrow_mut()
+                    .get(self_ref, *func, c)
+                    .map(|(clause, weight)| (clause, weight, branch))
             })
-            .collect::<Result<Vec<_>, _>>()?;
-        let finish_fns: Vec<_> = {
-            let mut finish_fns_ctx = ctx.derive(PathFragment::FinishFn)?;
-            // Compute all finish_functions at this level, caching if requested.
-            self.finish_fns()
-                .iter()
-                // note that this zip with would loop forever if there were to be a bug here
-                .zip(
-                    (0..)
-                        .filter_map(|i| finish_fns_ctx.derive(PathFragment::Branch(i as u64)).ok()),
-                )
-                .filter_map(|(func, c)| guard_clauses.borrow_mut().get(self_ref, *func, c))
-                .collect()
-        };
-        // If any clauses are returned, use a Threshold with n = 1
-        // It compiles equivalently to a tree of ORs.
+            .collect()
+    };
+    // A branch identical to an earlier one adds nothing but script size, so
+    // note it.
+    let mut seen = vec![];
+    report_duplicate_branches(&clause_accumulator, &mut seen, &mut diagnostics);
+    report_duplicate_branches(&finish_fns, &mut seen, &mut diagnostics);
+    let branches: Vec<(SArc<EffectPath>, Clause)> = clause_accumulator
+        .iter()
+        .chain(finish_fns.iter())
+        .map(|(clause, _, branch)| (branch.clone(), clause.clone()))
+        .collect();
+    // The policy compiler would reject a branch mixing height and time
+    // based timelocks without saying which, so check each branch first.
+    if let Some((branch, _)) = branches
+        .iter()
+        .find(|(_, clause)| clause.check_timelocks().is_err())
+    {
+        return Err(CompilationError::IncompatibleTimelocks(branch.0.clone()));
+    }
+
+    // If every branch is equally likely, use a Threshold with n = 1.  It
+    // compiles equivalently to a tree of ORs. Otherwise, build a tree of
+    // weighted ORs so that the likelier branches get cheaper witnesses.
+    let uniform = {
+        let mut weights = clause_accumulator
+            .iter()
+            .chain(finish_fns.iter())
+            .map(|(_, w, _)| (*w).max(1));
+        let first = weights.next();
+        weights.all(|w| Some(w) == first)
+    };
+    let policy = if uniform {
+        let mut clause_accumulator: Vec<Clause> =
+            clause_accumulator.into_iter().map(|(c, _, _)| c).collect();
         if finish_fns.len() > 0 {
-            clause_accumulator.push(Clause::Threshold(1, finish_fns))
+            clause_accumulator.push(Clause::Threshold(
+                1,
+                finish_fns.into_iter().map(|(c, _, _)| c).collect(),
+            ))
         }
-
-        let policy = match clause_accumulator.len() {
+        match clause_accumulator.len() {
             0 => return Err(CompilationError::EmptyPolicy),
             1 => clause_accumulator
                 .pop()
                 .expect("Length of policy must be at least 1"),
             _ => Clause::Threshold(1, clause_accumulator),
+        }
+    } else {
+        weights::weighted_or(
+            clause_accumulator
+                .into_iter()
+                .chain(finish_fns)
+                .map(|(c, w, _)| (w, c))
+                .collect(),
+        )
+        .ok_or(CompilationError::EmptyPolicy)?
+    };
+
+    let miniscript = policy.compile().map_err(Into::<CompilationError>::into)?;
+    let estimated_max_size = Segwitv0::max_satisfaction_size(&miniscript)
+        .ok_or(CompilationError::TerminateCompilation)?;
+    let witness_size_estimates = branches
+        .iter()
+        .filter_map(|(branch, clause)| {
+            weights::estimate_witness_size(&miniscript, clause).map(|size| (branch.clone(), size))
+        })
+        .collect::<HashMap<_, _>>();
+    let branches = branches.into_iter().collect::<HashMap<_, _>>();
+    // Now that the witness of each branch is known, templates with a feerate
+    // can reserve their exact fee. A template reachable from several branches
+    // pays for the largest witness.
+    let mut feerate_witnesses: HashMap<_, (usize, SArc<EffectPath>)> = HashMap::new();
+    for (uses_ctv, h, branch) in feerate_templates {
+        let size = witness_size_estimates
+            .get(&branch)
+            .copied()
+            .unwrap_or(estimated_max_size);
+        match feerate_witnesses.entry((uses_ctv, h)) {
+            Entry::Occupied(mut e) if e.get().0 < size => {
+                e.insert((size, branch));
+            }
+            Entry::Occupied(_) => {}
+            Entry::Vacant(e) => {
+                e.insert((size, branch));
+            }
+        }
+    }
+    for ((uses_ctv, h), (witness_size, branch)) in feerate_witnesses {
+        let txtmpl = match uses_ctv {
+            CTVRequired::Yes => ctv_to_tx.get_mut(&h),
+            CTVRequired::No => suggested_txs.get_mut(&h),
+        };
+        if let Some(txtmpl) = txtmpl {
+            let rate = txtmpl.feerate_sats_vbyte.unwrap_or_default();
+            txtmpl.max += rate * txtmpl.estimate_vsize(witness_size);
+            if txtmpl.max > ctx.funds() {
+                return Err(CompilationError::FeeExceedsFunds(h, branch.0));
+            }
+            amount_range.update_range(txtmpl.max);
+        }
+    }
+    // Check every template will be relayed, now that its size is known
+    let standardness = ctx.standardness_policy();
+    for (uses_ctv, h, branch) in new_templates {
+        let txtmpl = match uses_ctv {
+            CTVRequired::Yes => &ctv_to_tx[&h],
+            CTVRequired::No => &suggested_txs[&h],
         };
+        let witness_size = witness_size_estimates
+            .get(&branch)
+            .copied()
+            .unwrap_or(estimated_max_size);
+        for violation in standardness.check(txtmpl, witness_size) {
+            if standardness.mode == StandardnessMode::Strict {
+                return Err(CompilationError::NonStandardTemplate(
+                    violation, h, branch.0,
+                ));
+            }
+            diagnostics.push(Diagnostic::non_standard(branch.clone(), h, violation));
+        }
+    }
+    // TODO: Taproot output mode (one leaf per branch). Blocked on a
+    // miniscript with a Tap context, see the Taproot chapter of the docs.
+    let descriptor = Descriptor::new_wsh(miniscript)?;
+    let address = descriptor.address(ctx.network)?.into();
+    let key_origins = ctx.key_origins(&descriptor);
+    let xpubs = ctx.xpubs(&key_origins);
+    let descriptor = Some(descriptor);
+    let policy = Some(policy);
+    let root_path = SArc(ctx.path().clone());
 
-        let miniscript = policy.compile().map_err(Into::<CompilationError>::into)?;
-        let estimated_max_size = Segwitv0::max_satisfaction_size(&miniscript)
-            .ok_or(CompilationError::TerminateCompilation)?;
-        let descriptor = Descriptor::new_wsh(miniscript)?;
-        let address = descriptor.address(ctx.network)?.into();
-        let descriptor = Some(descriptor);
-        let policy = Some(policy);
-        let root_path = SArc(ctx.path().clone());
+    let failed_estimate = ctv_to_tx.values().any(|a| {
+        // witness space not scaled
+        let tx_size = a.tx.get_weight() + estimated_max_size;
+        let fees = amount_range.max() - a.total_amount();
+        a.min_feerate_sats_vbyte
+            .map(|m| fees.as_sat() < (m.as_sat() * tx_size as u64))
+            == Some(false)
+    });
+    if failed_estimate {
+        Err(CompilationError::MinFeerateError)
+    } else {
+        Ok(Compiled {
+            ctv_to_tx,
+            suggested_txs,
+            continue_apis,
+            root_path,
+            address,
+            descriptor,
+            policy,
+            amount_range,
+            witness_size_estimates,
+            branches,
+            key_origins,
+            xpubs,
+            diagnostics,
+        })
+    }
+}
 
-        let failed_estimate = ctv_to_tx.values().any(|a| {
-            // witness space not scaled
-            let tx_size = a.tx.get_weight() + estimated_max_size;
-            let fees = amount_range.max() - a.total_amount();
-            a.min_feerate_sats_vbyte
-                .map(|m| fees.as_sat() < (m.as_sat() * tx_size as u64))
-                == Some(false)
-        });
-        if failed_estimate {
-            Err(CompilationError::MinFeerateError)
-        } else {
-            Ok(Compiled {
-                ctv_to_tx,
-                suggested_txs,
-                continue_apis,
-                root_path,
-                address,
-                descriptor,
-                policy,
-                amount_range,
-            })
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::test_util::{self, ctx, path, pay_branch, pay_contract};
+    use crate::contract::DynamicContract;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    fn key(_: &(), _: Context) -> Clause {
+        Clause::Key(test_util::key())
+    }
+    fn mixed_timelocks(s: &(), c: Context) -> Clause {
+        Clause::And(vec![
+            key(s, c),
+            Clause::After(100),
+            Clause::After(500_000_001),
+        ])
+    }
+    #[test]
+    fn test_diagnostics() {
+        // two branches paying the same template
+        let contract = DynamicContract::<(), ()> {
+            then: vec![
+                || {
+                    Some(ThenFunc {
+                        name: Arc::new("a".into()),
+                        ..pay_branch()?
+                    })
+                },
+                || {
+                    Some(ThenFunc {
+                        name: Arc::new("b".into()),
+                        ..pay_branch()?
+                    })
+                },
+            ],
+            finish: vec![|| Some(Guard::Fresh(key, 1))],
+            ..pay_contract()
+        };
+        let compiled = contract.compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.diagnostics,
+            vec![Diagnostic::DuplicateGuard {
+                path: path("root/@then_fn/@next/b"),
+                duplicate_of: path("root/@then_fn/@next/a")
+            }]
+        );
+        // the duplicate is reported, not removed
+        assert!(compiled
+            .witness_size_estimates
+            .contains_key(&path("root/@then_fn/@next/b")));
+        // a guard no transaction can satisfy is an error naming its branch
+        let contract = DynamicContract::<(), ()> {
+            then: vec![],
+            finish: vec![|| Some(Guard::Fresh(mixed_timelocks, 1))],
+            ..pay_contract()
+        };
+        match contract.compile(ctx()) {
+            Err(CompilationError::IncompatibleTimelocks(branch)) => {
+                assert_eq!(*branch, *path("root/@finish_fn/#0").0)
+            }
+            r => panic!("expected IncompatibleTimelocks, got {:?}", r.map(|_| ())),
+        }
+    }
+    fn pay_dust(_: &(), ctx: Context) -> crate::contract::TxTmplIt {
+        let address = test_util::empty_address(ctx.network);
+        ctx.template()
+            .add_output(
+                bitcoin::Amount::from_sat(100),
+                &Compiled::from_address(address, None),
+                None,
+            )?
+            .into()
+    }
+    #[test]
+    fn test_standardness() {
+        use crate::template::standardness::{StandardnessPolicy, StandardnessViolation};
+        let contract = DynamicContract::<(), ()> {
+            then: vec![|| {
+                Some(ThenFunc {
+                    func: pay_dust,
+                    ..pay_branch()?
+                })
+            }],
+            ..pay_contract()
+        };
+        let ctx = || {
+            Context::new(
+                bitcoin::Network::Bitcoin,
+                bitcoin::Amount::from_sat(10000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("root").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let branch = EffectPath::try_from("root/@then_fn/@next/pay").unwrap();
+        let dust = StandardnessViolation::Dust {
+            index: 0,
+            amount: bitcoin::Amount::from_sat(100),
+            dust_limit: bitcoin::Amount::from_sat(330),
+        };
+        match contract.compile(ctx()) {
+            Err(CompilationError::NonStandardTemplate(violation, _, path)) => {
+                assert_eq!(violation, dust);
+                assert_eq!(*path, branch);
+            }
+            _ => panic!("dust output should not compile on mainnet"),
+        }
+        let permissive = StandardnessPolicy::for_network(bitcoin::Network::Bitcoin)
+            .with_mode(StandardnessMode::Permissive);
+        let compiled = contract
+            .compile(ctx().with_standardness_policy(permissive))
+            .unwrap();
+        assert!(matches!(
+            &compiled.diagnostics[..],
+            [Diagnostic::DustOutput { path, index: 0, .. }] if *path.0 == branch
+        ));
+    }
+    fn derived_key(_: &(), ctx: Context) -> Clause {
+        Clause::Key(ctx.derive_key().unwrap())
+    }
+    #[test]
+    fn test_derive_key() {
+        use crate::contract::context::PathKeys;
+        use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+        let contract = DynamicContract::<(), ()> {
+            then: vec![],
+            finish_or: vec![],
+            finish: vec![|| Some(Guard::Fresh(derived_key, 1)), || {
+                Some(Guard::Fresh(derived_key, 1))
+            }],
+            data: (),
+        };
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[2; 32]).unwrap();
+        let compile = || {
+            let ctx = ctx().with_path_keys(PathKeys {
+                xpub: ExtendedPubKey::from_private(&secp, &xpriv),
+                origin: None,
+                scheme: Default::default(),
+            });
+            contract.compile(ctx).unwrap()
+        };
+        let compiled = compile();
+        // each guard gets its own key, so neither is a duplicate
+        assert!(compiled.diagnostics.is_empty());
+        assert_eq!(compiled.key_origins.len(), 2);
+        for (key, (fingerprint, path)) in compiled.key_origins.iter() {
+            assert_eq!(*fingerprint, xpriv.fingerprint(&secp));
+            let derived = xpriv.derive_priv(&secp, path).unwrap();
+            assert_eq!(
+                ExtendedPubKey::from_private(&secp, &derived).public_key,
+                *key
+            );
         }
+        assert_eq!(compile().descriptor, compiled.descriptor);
+        let json = serde_json::to_value(&compiled).unwrap();
+        let roundtrip: Compiled = serde_json::from_value(json).unwrap();
+        assert_eq!(roundtrip.key_origins, compiled.key_origins);
     }
 }
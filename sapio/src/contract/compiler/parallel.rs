@@ -0,0 +1,120 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for compiling independent sub-contracts in parallel.
+//!
+//! Parallelism is only available with the `parallel` feature, which is not
+//! supported for WASM plugins. Without the feature everything here runs
+//! sequentially and imposes no extra bounds on contracts.
+
+/// `MaybeSync` is `Sync` when the `parallel` feature is enabled, and is
+/// implemented for every type otherwise. Contract data must be `MaybeSync` so
+/// that sibling `then_fns` may be called from different threads.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+/// `MaybeSync` is `Sync` when the `parallel` feature is enabled, and is
+/// implemented for every type otherwise. Contract data must be `MaybeSync` so
+/// that sibling `then_fns` may be called from different threads.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// Whether independent sub-contracts should be compiled in parallel for `ctx`
+pub(crate) fn enabled(ctx: &crate::contract::Context) -> bool {
+    cfg!(feature = "parallel") && ctx.parallel()
+}
+
+/// Map `f` over `v`, on the rayon thread pool if `enabled`. Results are
+/// returned in the same order as the inputs.
+#[cfg(feature = "parallel")]
+pub(crate) fn map<I, O, F>(enabled: bool, v: Vec<I>, f: F) -> Vec<O>
+where
+    I: Send,
+    O: Send,
+    F: Fn(I) -> O + Send + Sync,
+{
+    use rayon::prelude::*;
+    if enabled {
+        v.into_par_iter().map(f).collect()
+    } else {
+        v.into_iter().map(f).collect()
+    }
+}
+/// Map `f` over `v`. Without the `parallel` feature this is always sequential.
+#[cfg(not(feature = "parallel"))]
+pub(crate) fn map<I, O, F>(_enabled: bool, v: Vec<I>, f: F) -> Vec<O>
+where
+    F: Fn(I) -> O,
+{
+    v.into_iter().map(f).collect()
+}
+
+/// Results of compiling on another thread must be `Send`.
+#[allow(dead_code)]
+fn assert_send_sync() {
+    fn is_send_sync<T: Send + Sync>() {}
+    is_send_sync::<crate::contract::CompilationError>();
+    is_send_sync::<crate::contract::Compiled>();
+    is_send_sync::<crate::contract::Context>();
+    is_send_sync::<crate::template::Template>();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::test_util::{ctx_with, key, pay_branch, pay_contract};
+    use crate::contract::{Compilable, Context, DynamicContract, TxTmplIt};
+    use bitcoin::Amount;
+    use sapio_base::Clause;
+    use std::sync::Arc;
+    fn pay_nested(_: &(), ctx: Context) -> TxTmplIt {
+        let nested = pay_contract();
+        ctx.template()
+            .add_outputs(vec![
+                (Amount::from_sat(6000), &nested, None),
+                (Amount::from_sat(6000), &nested, None),
+            ])?
+            .into()
+    }
+    #[test]
+    fn test_parallel_matches_sequential() {
+        // "a" and "b" pay the same template, so there is a diagnostic to
+        // compare as well
+        let contract = DynamicContract::<(), ()> {
+            then: vec![
+                || {
+                    Some(ThenFunc {
+                        func: pay_nested,
+                        name: Arc::new("a".into()),
+                        ..pay_branch()?
+                    })
+                },
+                || {
+                    Some(ThenFunc {
+                        func: pay_nested,
+                        name: Arc::new("b".into()),
+                        ..pay_branch()?
+                    })
+                },
+                pay_branch,
+            ],
+            finish: vec![|| Some(Guard::Fresh(|_, _| Clause::Key(key()), 1))],
+            ..pay_contract()
+        };
+        let ctx = || ctx_with(Amount::from_sat(20000));
+        let sequential = contract.compile(ctx()).unwrap();
+        let parallel = contract.compile(ctx().with_parallelism(true)).unwrap();
+        assert!(!sequential.diagnostics.is_empty());
+        assert!(!sequential.witness_size_estimates.is_empty());
+        assert_eq!(
+            sequential.to_canonical_json().unwrap(),
+            parallel.to_canonical_json().unwrap()
+        );
+    }
+}
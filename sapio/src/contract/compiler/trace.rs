@@ -0,0 +1,344 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Opt-in tracing of compilation, for finding slow or exploding paths.
+use sapio_base::effects::EffectPath;
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// How long the compilation of the contract at `path` took, including the
+/// compilation of any contracts it created.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct CompileTrace {
+    /// the path of the contract compiled
+    pub path: String,
+    /// total time taken, in microseconds
+    pub micros: u64,
+}
+
+/// The number of templates produced by the branch at `path`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct TemplateTrace {
+    /// the path of the branch
+    pub path: String,
+    /// the number of templates produced
+    pub count: usize,
+}
+
+/// A guard evaluated at `path`, and whether the Clause came from the
+/// `GuardCache` rather than a fresh call.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct GuardTrace {
+    /// the path the guard was evaluated at
+    pub path: String,
+    /// true if the Clause was taken from the cache
+    pub cached: bool,
+}
+
+/// The structured output of a `CompilationTracer`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct CompilationReport {
+    /// time taken for each contract compiled, sorted by path
+    pub compiles: Vec<CompileTrace>,
+    /// templates produced per branch, sorted by path
+    pub templates: Vec<TemplateTrace>,
+    /// guard evaluations, sorted by path
+    pub guards: Vec<GuardTrace>,
+}
+
+impl CompilationReport {
+    /// Render the report in the "folded stacks" format used by flamegraph
+    /// tools. Each line is a path (fragments separated by `;`) followed by
+    /// the microseconds spent in that contract excluding its children.
+    pub fn to_folded_stacks(&self) -> String {
+        let stacks: BTreeMap<Vec<String>, u64> = self
+            .compiles
+            .iter()
+            .map(|c| (c.path.split('/').map(String::from).collect(), c.micros))
+            .collect();
+        let mut self_time = stacks.clone();
+        for stack in stacks.keys() {
+            // charge this stack's time to its nearest traced ancestor only
+            if let Some(parent) = (1..stack.len())
+                .rev()
+                .map(|i| &stack[..i])
+                .find(|p| stacks.contains_key(*p))
+            {
+                let t = self_time.get_mut(parent).expect("Parent must be present");
+                *t = t.saturating_sub(stacks[stack]);
+            }
+        }
+        self_time
+            .iter()
+            .map(|(stack, t)| format!("{} {}\n", stack.join(";"), t))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct TraceLog {
+    compiles: BTreeMap<String, u64>,
+    templates: BTreeMap<String, usize>,
+    guards: Vec<GuardTrace>,
+}
+
+/// A handle for recording a compilation trace. Clones share the same log,
+/// so a tracer may be attached to a `Context` and inspected after
+/// compilation completes.
+#[derive(Clone)]
+pub struct CompilationTracer {
+    log: Arc<Mutex<TraceLog>>,
+    clock: fn() -> u64,
+}
+
+impl CompilationTracer {
+    /// Create a new tracer using the system clock.
+    ///
+    /// On targets without a system clock (e.g. WASM plugins), use
+    /// `CompilationTracer::with_clock` instead.
+    pub fn new() -> Self {
+        Self::with_clock(system_clock)
+    }
+    /// Create a new tracer using a custom clock, which must return a
+    /// monotonic time in microseconds.
+    pub fn with_clock(clock: fn() -> u64) -> Self {
+        CompilationTracer {
+            log: Default::default(),
+            clock,
+        }
+    }
+    /// Begin timing the compilation at `path`. The time is recorded when the
+    /// returned `CompileSpan` is dropped.
+    pub(crate) fn start(&self, path: &Arc<EffectPath>) -> CompileSpan {
+        CompileSpan {
+            tracer: self.clone(),
+            path: path.as_ref().clone().into(),
+            start: (self.clock)(),
+        }
+    }
+    pub(crate) fn record_templates(&self, path: &SArc<EffectPath>, count: usize) {
+        self.log
+            .lock()
+            .unwrap()
+            .templates
+            .insert(path.0.as_ref().clone().into(), count);
+    }
+    pub(crate) fn record_guard(&self, path: &Arc<EffectPath>, cached: bool) {
+        self.log.lock().unwrap().guards.push(GuardTrace {
+            path: path.as_ref().clone().into(),
+            cached,
+        });
+    }
+    /// Record the `report` of a compilation run separately, e.g. by another
+    /// plugin, as though it had been traced beneath `path`.
+    pub fn merge(&self, path: &EffectPath, report: CompilationReport) {
+        let prefix = String::from(path.clone());
+        let beneath = |p: String| format!("{}/{}", prefix, p);
+        let mut log = self.log.lock().unwrap();
+        for c in report.compiles {
+            log.compiles.insert(beneath(c.path), c.micros);
+        }
+        for t in report.templates {
+            log.templates.insert(beneath(t.path), t.count);
+        }
+        for g in report.guards {
+            log.guards.push(GuardTrace {
+                path: beneath(g.path),
+                cached: g.cached,
+            });
+        }
+    }
+    /// Generate a report of everything recorded so far.
+    pub fn report(&self) -> CompilationReport {
+        let log = self.log.lock().unwrap();
+        let mut guards = log.guards.clone();
+        guards.sort_by(|a, b| a.path.cmp(&b.path));
+        CompilationReport {
+            compiles: log
+                .compiles
+                .iter()
+                .map(|(path, micros)| CompileTrace {
+                    path: path.clone(),
+                    micros: *micros,
+                })
+                .collect(),
+            templates: log
+                .templates
+                .iter()
+                .map(|(path, count)| TemplateTrace {
+                    path: path.clone(),
+                    count: *count,
+                })
+                .collect(),
+            guards,
+        }
+    }
+}
+
+impl Default for CompilationTracer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Microseconds since the unix epoch, or always 0 on targets without a
+/// system clock.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn system_clock() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn system_clock() -> u64 {
+    0
+}
+
+/// Records the time elapsed for a compilation when dropped.
+pub(crate) struct CompileSpan {
+    tracer: CompilationTracer,
+    path: String,
+    start: u64,
+}
+
+impl Drop for CompileSpan {
+    fn drop(&mut self) {
+        let elapsed = (self.tracer.clock)().saturating_sub(self.start);
+        self.tracer
+            .log
+            .lock()
+            .unwrap()
+            .compiles
+            .insert(std::mem::take(&mut self.path), elapsed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::{Guard, ThenFunc};
+    use crate::contract::{test_util, Compilable, Context, DynamicContract, TxTmplIt};
+    use sapio_base::Clause;
+    use std::convert::TryFrom;
+    #[test]
+    fn test_folded_stacks() {
+        let p = |s: &str| String::from(EffectPath::try_from(s).unwrap());
+        let report = CompilationReport {
+            compiles: vec![
+                CompileTrace {
+                    path: p("root"),
+                    micros: 100,
+                },
+                CompileTrace {
+                    path: p("root/@then_fn/@next/a"),
+                    micros: 30,
+                },
+                CompileTrace {
+                    path: p("root/@then_fn/@next/a/@then_fn/@next/b"),
+                    micros: 10,
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            report.to_folded_stacks(),
+            "root 70\n\
+             root;@then_fn;@next;a 20\n\
+             root;@then_fn;@next;a;@then_fn;@next;b 10\n"
+        );
+    }
+    fn older(_: &(), _: Context) -> Clause {
+        Clause::Older(10)
+    }
+    fn older_guard() -> Option<Guard<()>> {
+        Some(Guard::Cache(older, 1))
+    }
+    fn pay_nested(_: &(), ctx: Context) -> TxTmplIt {
+        ctx.template()
+            .add_output(
+                bitcoin::Amount::from_sat(5000),
+                &test_util::pay_contract(),
+                None,
+            )?
+            .into()
+    }
+    #[test]
+    fn test_trace() {
+        // two branches sharing a cached guard, one paying a nested contract
+        let contract = DynamicContract::<(), ()> {
+            then: vec![
+                || {
+                    Some(ThenFunc {
+                        guard: &[older_guard],
+                        func: pay_nested,
+                        name: Arc::new("a".into()),
+                        ..test_util::pay_branch()?
+                    })
+                },
+                || {
+                    Some(ThenFunc {
+                        guard: &[older_guard],
+                        name: Arc::new("b".into()),
+                        ..test_util::pay_branch()?
+                    })
+                },
+            ],
+            ..test_util::pay_contract()
+        };
+        let tracer = CompilationTracer::new();
+        contract
+            .compile(test_util::ctx().with_tracer(tracer.clone()))
+            .unwrap();
+        let report = tracer.report();
+        let compiles: Vec<_> = report.compiles.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(compiles, ["root", "root/@then_fn/@next/a/#0"]);
+        let templates: Vec<_> = report
+            .templates
+            .iter()
+            .map(|t| (t.path.as_str(), t.count))
+            .collect();
+        assert_eq!(
+            templates,
+            [
+                ("root/@then_fn/@next/a", 1),
+                ("root/@then_fn/@next/a/#0/@then_fn/@next/pay", 1),
+                ("root/@then_fn/@next/b", 1),
+            ]
+        );
+        let guards: Vec<_> = report
+            .guards
+            .iter()
+            .map(|g| (g.path.as_str(), g.cached))
+            .collect();
+        assert_eq!(
+            guards,
+            [
+                ("root/@then_fn/@guard/a/#0", false),
+                ("root/@then_fn/@guard/b/#0", true),
+            ]
+        );
+    }
+    #[test]
+    fn test_merge() {
+        let nested = CompilationTracer::new();
+        test_util::pay_contract()
+            .compile(test_util::ctx().with_tracer(nested.clone()))
+            .unwrap();
+        let tracer = CompilationTracer::new();
+        tracer.merge(&test_util::path("outer/@then_fn").0, nested.report());
+        let report = tracer.report();
+        let compiles: Vec<_> = report.compiles.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(compiles, ["outer/@then_fn/root"]);
+        assert_eq!(
+            report.templates[0].path,
+            "outer/@then_fn/root/@then_fn/@next/pay"
+        );
+    }
+}
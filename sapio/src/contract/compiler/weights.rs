@@ -0,0 +1,206 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for weighted branches and per-branch witness size estimates
+use ::miniscript::*;
+use bitcoin::blockdata::transaction::SigHashType;
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d};
+use bitcoin::secp256k1::Signature;
+use sapio_base::Clause;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::collections::HashSet;
+
+/// Combines weighted branches into a tree of binary `Or`s, Huffman style, so
+/// that the most likely branches end up closest to the root. Ties are broken
+/// by insertion order so the result is deterministic. A weight of 0 is treated
+/// as 1.
+///
+/// Returns None if there are no branches.
+pub(crate) fn weighted_or(branches: Vec<(usize, Clause)>) -> Option<Clause> {
+    let mut nodes: Vec<Option<Clause>> = Vec::with_capacity(2 * branches.len());
+    let mut heap = BinaryHeap::new();
+    for (weight, clause) in branches {
+        heap.push(Reverse((weight.max(1), nodes.len())));
+        nodes.push(Some(clause));
+    }
+    loop {
+        let Reverse((w_a, a)) = heap.pop()?;
+        match heap.pop() {
+            None => return nodes[a].take(),
+            Some(Reverse((w_b, b))) => {
+                let clause = Clause::Or(vec![(w_a, nodes[a].take()?), (w_b, nodes[b].take()?)]);
+                heap.push(Reverse((w_a.saturating_add(w_b), nodes.len())));
+                nodes.push(Some(clause));
+            }
+        }
+    }
+}
+
+/// A Satisfier which can satisfy only the terms used by a single branch,
+/// returning maximum-size dummy signatures and preimages.
+#[derive(Default)]
+struct BranchSatisfier {
+    keys: HashSet<bitcoin::PublicKey>,
+    sha256: HashSet<sha256::Hash>,
+    hash256: HashSet<sha256d::Hash>,
+    ripemd160: HashSet<ripemd160::Hash>,
+    hash160: HashSet<hash160::Hash>,
+    after: HashSet<u32>,
+    older: HashSet<u32>,
+    templates: HashSet<sha256::Hash>,
+}
+
+impl BranchSatisfier {
+    fn new(branch: &Clause) -> Self {
+        let mut s = Self::default();
+        s.collect(branch);
+        s
+    }
+    fn collect(&mut self, c: &Clause) {
+        match c {
+            Clause::Unsatisfiable | Clause::Trivial => {}
+            Clause::Key(k) => {
+                self.keys.insert(*k);
+            }
+            Clause::After(t) => {
+                self.after.insert(*t);
+            }
+            Clause::Older(t) => {
+                self.older.insert(*t);
+            }
+            Clause::Sha256(h) => {
+                self.sha256.insert(*h);
+            }
+            Clause::Hash256(h) => {
+                self.hash256.insert(*h);
+            }
+            Clause::Ripemd160(h) => {
+                self.ripemd160.insert(*h);
+            }
+            Clause::Hash160(h) => {
+                self.hash160.insert(*h);
+            }
+            Clause::TxTemplate(h) => {
+                self.templates.insert(*h);
+            }
+            Clause::And(v) | Clause::Threshold(_, v) => v.iter().for_each(|c| self.collect(c)),
+            Clause::Or(v) => v.iter().for_each(|(_, c)| self.collect(c)),
+        }
+    }
+    /// A signature with a 33 byte r and s, which DER encodes to 72 bytes.
+    fn dummy_sig() -> BitcoinSig {
+        let mut rs = [0u8; 64];
+        rs[0] = 0x80;
+        rs[31] = 1;
+        rs[32] = 0x80;
+        rs[63] = 1;
+        (
+            Signature::from_compact(&rs).expect("r and s are below the curve order"),
+            SigHashType::All,
+        )
+    }
+}
+
+impl Satisfier<bitcoin::PublicKey> for BranchSatisfier {
+    fn lookup_sig(&self, k: &bitcoin::PublicKey) -> Option<BitcoinSig> {
+        self.keys.get(k).map(|_| Self::dummy_sig())
+    }
+    fn lookup_pkh_pk(&self, h: &hash160::Hash) -> Option<bitcoin::PublicKey> {
+        self.keys.iter().find(|k| k.to_pubkeyhash() == *h).cloned()
+    }
+    fn lookup_pkh_sig(&self, h: &hash160::Hash) -> Option<(bitcoin::PublicKey, BitcoinSig)> {
+        self.lookup_pkh_pk(h).map(|k| (k, Self::dummy_sig()))
+    }
+    fn lookup_sha256(&self, h: sha256::Hash) -> Option<Preimage32> {
+        self.sha256.get(&h).map(|_| [0; 32])
+    }
+    fn lookup_hash256(&self, h: sha256d::Hash) -> Option<Preimage32> {
+        self.hash256.get(&h).map(|_| [0; 32])
+    }
+    fn lookup_ripemd160(&self, h: ripemd160::Hash) -> Option<Preimage32> {
+        self.ripemd160.get(&h).map(|_| [0; 32])
+    }
+    fn lookup_hash160(&self, h: hash160::Hash) -> Option<Preimage32> {
+        self.hash160.get(&h).map(|_| [0; 32])
+    }
+    fn check_older(&self, t: u32) -> bool {
+        self.older.contains(&t)
+    }
+    fn check_after(&self, t: u32) -> bool {
+        self.after.contains(&t)
+    }
+    fn check_tx_template(&self, h: sha256::Hash) -> bool {
+        self.templates.contains(&h)
+    }
+}
+
+/// Estimates the serialized witness size (stack items plus the witness
+/// script) of spending `ms` using only the terms present in `branch`.
+///
+/// Returns None if the branch alone cannot satisfy the script.
+pub(crate) fn estimate_witness_size(
+    ms: &Miniscript<bitcoin::PublicKey, Segwitv0>,
+    branch: &Clause,
+) -> Option<usize> {
+    // pass the satisfier by value: miniscript's impl for `&S` does not
+    // forward `check_tx_template`, so CTV branches would never be satisfied
+    let stack = ms
+        .satisfy(BranchSatisfier::new(branch))
+        .or_else(|_| ms.satisfy_malleable(BranchSatisfier::new(branch)))
+        .ok()?;
+    let script_len = ms.script_size();
+    let items = stack
+        .iter()
+        .map(|item| VarInt(item.len() as u64).len() + item.len())
+        .sum::<usize>();
+    Some(
+        VarInt(stack.len() as u64 + 1).len() + items + VarInt(script_len as u64).len() + script_len,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    #[test]
+    fn test_weighted_or() {
+        let h = |i: u8| Clause::Sha256(sha256::Hash::from_inner([i; 32]));
+        assert_eq!(weighted_or(vec![]), None);
+        assert_eq!(weighted_or(vec![(3, h(0))]), Some(h(0)));
+        // the likeliest branch should be nearest the root
+        let c = weighted_or(vec![(1, h(0)), (1, h(1)), (10, h(2))]);
+        assert_eq!(
+            c,
+            Some(Clause::Or(vec![
+                (2, Clause::Or(vec![(1, h(0)), (1, h(1))])),
+                (10, h(2))
+            ]))
+        );
+    }
+    #[test]
+    fn test_estimate_witness_size() {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let key = |i: u8| bitcoin::PublicKey {
+            compressed: true,
+            key: bitcoin::secp256k1::PublicKey::from_secret_key(
+                &secp,
+                &bitcoin::secp256k1::SecretKey::from_slice(&[i; 32]).unwrap(),
+            ),
+        };
+        let a = Clause::Key(key(1));
+        let b = Clause::And(vec![Clause::Key(key(2)), Clause::Older(144)]);
+        let ms = weighted_or(vec![(10, a.clone()), (1, b.clone())])
+            .unwrap()
+            .compile()
+            .unwrap();
+        let size_a = estimate_witness_size(&ms, &a).unwrap();
+        let size_b = estimate_witness_size(&ms, &b).unwrap();
+        assert!(size_a < size_b);
+        assert_eq!(estimate_witness_size(&ms, &Clause::Older(144)), None);
+    }
+}
@@ -6,22 +6,41 @@
 
 //! general non-parameter compilation state required by all contracts
 use super::{Amount, Compilable, CompilationError, Compiled};
+use crate::contract::compiler::incremental::IncrementalCache;
+use crate::contract::compiler::limits::{CompilationLimits, ResourceLimiter};
+use crate::contract::compiler::memo::{AttachedCache, CompileCache};
+use crate::contract::compiler::trace::CompilationTracer;
 use crate::contract::compiler::InternalCompilerTag;
+pub use crate::template::StandardnessPolicy;
 use crate::util::amountrange::AmountRange;
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use bitcoin::util::bip32::{ExtendedPubKey, KeySource};
 use bitcoin::Network;
 use miniscript::Descriptor;
 use miniscript::DescriptorTrait;
+use miniscript::{ForEach, ForEachKey};
+pub use sapio_base::chain_snapshot::ChainSnapshot;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
 pub use sapio_base::effects::{EffectDB, MapEffectDB};
+pub use sapio_base::path_keys::PathKeys;
 use sapio_base::serialization_helpers::SArc;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::convert::TryInto;
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+/// `PathKeys` attached to a Context, and the origin of every key derived
+/// from them so far.
+struct KeyDeriver {
+    keys: PathKeys,
+    secp: Secp256k1<VerifyOnly>,
+    derived: Mutex<BTreeMap<bitcoin::PublicKey, KeySource>>,
+}
 
 /// Context is used to track statet during compilation such as remaining value.
 pub struct Context {
@@ -33,7 +52,15 @@ pub struct Context {
     /// TODO: reversed linked list of ARCs to better de-duplicate memory.
     path: Arc<EffectPath>,
     already_derived: HashSet<PathFragment>,
-    effects: Arc<MapEffectDB>,
+    effects: Arc<dyn EffectDB>,
+    tracer: Option<CompilationTracer>,
+    parallel: bool,
+    compile_cache: Option<AttachedCache>,
+    limiter: Option<ResourceLimiter>,
+    incremental: Option<Arc<IncrementalCache>>,
+    chain: Option<Arc<ChainSnapshot>>,
+    keys: Option<Arc<KeyDeriver>>,
+    standardness: Arc<StandardnessPolicy>,
 }
 
 impl Context {
@@ -44,7 +71,7 @@ impl Context {
         available_funds: Amount,
         emulator: Arc<dyn CTVEmulator>,
         path: EffectPath,
-        effects: Arc<MapEffectDB>,
+        effects: Arc<dyn EffectDB>,
     ) -> Self {
         Context {
             available_funds,
@@ -54,14 +81,171 @@ impl Context {
             path: Arc::new(path),
             already_derived: Default::default(),
             effects,
+            tracer: None,
+            parallel: false,
+            compile_cache: None,
+            limiter: None,
+            incremental: None,
+            chain: None,
+            keys: None,
+            standardness: Arc::new(StandardnessPolicy::for_network(network)),
+        }
+    }
+    /// Attach a tracer to this context, recording compilation of this and any
+    /// derived contexts.
+    pub fn with_tracer(mut self, tracer: CompilationTracer) -> Self {
+        self.tracer = Some(tracer);
+        self
+    }
+    /// Compile sibling outputs and `then_fns` of this and any derived
+    /// contexts in parallel. Has no effect unless sapio is built with the
+    /// `parallel` feature.
+    pub fn with_parallelism(mut self, enabled: bool) -> Self {
+        self.parallel = enabled;
+        self
+    }
+    /// Attach a `CompileCache`, used by this and any derived contexts to
+    /// compile `Memoized` contracts.
+    pub fn with_compile_cache(
+        mut self,
+        cache: Arc<dyn CompileCache>,
+    ) -> Result<Self, CompilationError> {
+        self.compile_cache = Some(AttachedCache::new(cache, &self)?);
+        Ok(self)
+    }
+    /// Attach a `ResourceLimiter`, failing compilation of this and any
+    /// derived contexts once a limit is exceeded.
+    pub fn with_limiter(mut self, limiter: ResourceLimiter) -> Self {
+        self.limiter = Some(limiter);
+        self
+    }
+    /// Get this Context's limiter, if limits are set
+    pub fn limiter(&self) -> Option<&ResourceLimiter> {
+        self.limiter.as_ref()
+    }
+    /// The limits left for a compilation started from this Context, e.g. by
+    /// a plugin, which are unlimited if no limiter is attached
+    pub fn child_limits(&self) -> CompilationLimits {
+        self.limiter
+            .as_ref()
+            .map(|l| l.remaining(&self.path))
+            .unwrap_or_default()
+    }
+    /// Attach an `IncrementalCache`, starting a new compilation which reuses
+    /// whatever the cache holds from earlier compilations that is unaffected
+    /// by changes in effects.
+    pub fn with_incremental_cache(mut self, cache: Arc<IncrementalCache>) -> Self {
+        cache.begin();
+        self.incremental = Some(cache);
+        self
+    }
+    pub(crate) fn incremental(&self) -> Option<&Arc<IncrementalCache>> {
+        self.incremental.as_ref()
+    }
+    /// Attach a snapshot of the chain, for this and any derived contexts to
+    /// compile against.
+    pub fn with_chain_snapshot(mut self, chain: ChainSnapshot) -> Self {
+        self.chain = Some(Arc::new(chain));
+        self
+    }
+    /// Get the state of the chain being compiled against, if known
+    pub fn chain_snapshot(&self) -> Option<&ChainSnapshot> {
+        self.chain.as_deref()
+    }
+    /// Check the templates of this and any derived contexts against
+    /// `policy`, rather than the network's default.
+    pub fn with_standardness_policy(mut self, policy: StandardnessPolicy) -> Self {
+        self.standardness = Arc::new(policy);
+        self
+    }
+    /// Get the policy templates are checked against
+    pub fn standardness_policy(&self) -> &StandardnessPolicy {
+        &self.standardness
+    }
+    /// Attach an extended key, for this and any derived contexts to derive
+    /// a fresh key per path from with `Context::derive_key`.
+    pub fn with_path_keys(mut self, keys: PathKeys) -> Self {
+        self.keys = Some(Arc::new(KeyDeriver {
+            keys,
+            secp: Secp256k1::verification_only(),
+            derived: Default::default(),
+        }));
+        self
+    }
+    /// Get the extended key attached to this Context, if any
+    pub fn path_keys(&self) -> Option<&PathKeys> {
+        self.keys.as_ref().map(|k| &k.keys)
+    }
+    /// Derive the key for this Context's path from the attached `PathKeys`.
+    /// The same path always derives the same key, so derive a new Context
+    /// (e.g. with `derive_str`) for each distinct key needed.
+    ///
+    /// Where the key came from is recorded in the `key_origins` of any
+    /// compiled contract using it.
+    pub fn derive_key(&self) -> Result<bitcoin::PublicKey, CompilationError> {
+        let deriver = self.keys.as_ref().ok_or(CompilationError::NoPathKeys)?;
+        let (key, source) = deriver.keys.derive(&deriver.secp, &self.path)?;
+        deriver.derived.lock().unwrap().insert(key, source);
+        Ok(key)
+    }
+    /// The attached `PathKeys`, re-rooted at the key for this Context's
+    /// path, e.g. for passing to a plugin which compiles from its own root
+    /// path.
+    pub fn child_path_keys(&self) -> Result<Option<PathKeys>, CompilationError> {
+        self.keys
+            .as_ref()
+            .map(|deriver| Ok(deriver.keys.child_keys(&deriver.secp, &self.path)?))
+            .transpose()
+    }
+    /// The origins of the keys in `descriptor` which were derived with
+    /// `Context::derive_key`
+    pub(crate) fn key_origins(
+        &self,
+        descriptor: &Descriptor<bitcoin::PublicKey>,
+    ) -> BTreeMap<bitcoin::PublicKey, KeySource> {
+        let mut origins = BTreeMap::new();
+        if let Some(deriver) = &self.keys {
+            let derived = deriver.derived.lock().unwrap();
+            descriptor.for_each_key(|k| {
+                if let ForEach::Key(k) = k {
+                    if let Some(source) = derived.get(k) {
+                        origins.insert(*k, source.clone());
+                    }
+                }
+                true
+            });
+        }
+        origins
+    }
+    /// The extended key the keys in `key_origins` were derived from, if any
+    pub(crate) fn xpubs(
+        &self,
+        key_origins: &BTreeMap<bitcoin::PublicKey, KeySource>,
+    ) -> BTreeMap<ExtendedPubKey, KeySource> {
+        match &self.keys {
+            Some(deriver) if !key_origins.is_empty() => {
+                std::iter::once((deriver.keys.xpub, deriver.keys.source())).collect()
+            }
+            _ => BTreeMap::new(),
         }
     }
+    pub(crate) fn compile_cache(&self) -> Option<&AttachedCache> {
+        self.compile_cache.as_ref()
+    }
+    /// Whether this Context compiles independent sub-contracts in parallel
+    pub fn parallel(&self) -> bool {
+        self.parallel
+    }
+    /// Get this Context's tracer, if tracing is enabled
+    pub fn tracer(&self) -> Option<&CompilationTracer> {
+        self.tracer.as_ref()
+    }
     /// Get this Context's effect database, for clients
-    pub unsafe fn get_effects_internal(&self) -> &Arc<MapEffectDB> {
+    pub unsafe fn get_effects_internal(&self) -> &Arc<dyn EffectDB> {
         &self.effects
     }
     /// Get this Context's effect database
-    pub(crate) fn get_effects(&self, _: InternalCompilerTag) -> &Arc<MapEffectDB> {
+    pub(crate) fn get_effects(&self, _: InternalCompilerTag) -> &Arc<dyn EffectDB> {
         &self.effects
     }
     /// Gets this Context's Path, but does not clone (left to caller)
@@ -89,6 +273,9 @@ impl Context {
         } else {
             self.already_derived.insert(path.clone());
             let new_path = EffectPath::push(Some(self.path.clone()), path);
+            if let Some(limiter) = &self.limiter {
+                limiter.check_path(&new_path)?;
+            }
             Ok(Context {
                 available_funds: self.available_funds,
                 emulator: self.emulator.clone(),
@@ -96,6 +283,14 @@ impl Context {
                 network: self.network,
                 already_derived: Default::default(),
                 effects: self.effects.clone(),
+                tracer: self.tracer.clone(),
+                parallel: self.parallel,
+                compile_cache: self.compile_cache.clone(),
+                limiter: self.limiter.clone(),
+                incremental: self.incremental.clone(),
+                chain: self.chain.clone(),
+                keys: self.keys.clone(),
+                standardness: self.standardness.clone(),
             })
         }
     }
@@ -109,6 +304,14 @@ impl Context {
             network: self.network,
             already_derived: self.already_derived.clone(),
             effects: self.effects.clone(),
+            tracer: self.tracer.clone(),
+            parallel: self.parallel,
+            compile_cache: self.compile_cache.clone(),
+            limiter: self.limiter.clone(),
+            incremental: self.incremental.clone(),
+            chain: self.chain.clone(),
+            keys: self.keys.clone(),
+            standardness: self.standardness.clone(),
         }
     }
 
@@ -143,6 +346,14 @@ impl Context {
                 network: self.network,
                 already_derived: self.already_derived.clone(),
                 effects: self.effects.clone(),
+                tracer: self.tracer.clone(),
+                parallel: self.parallel,
+                compile_cache: self.compile_cache.clone(),
+                limiter: self.limiter.clone(),
+                incremental: self.incremental.clone(),
+                chain: self.chain.clone(),
+                keys: self.keys.clone(),
+                standardness: self.standardness.clone(),
             })
         }
     }
@@ -190,6 +401,11 @@ impl Context {
                 a.update_range(Amount::from_sat(21_000_000 * 100_000_000));
                 a
             }),
+            witness_size_estimates: HashMap::new(),
+            branches: HashMap::new(),
+            key_origins: BTreeMap::new(),
+            xpubs: BTreeMap::new(),
+            diagnostics: vec![],
         }
     }
 }
@@ -7,13 +7,18 @@
 //! error types that can be returned from Sapio.
 //! Where possible, concrete error types are wrapped, but in order to handle
 //! errors created by the user we allow boxing an error trait.
+use crate::contract::compiler::limits::ResourceLimit;
 use crate::contract::object::ObjectError;
+use crate::template::standardness::StandardnessViolation;
+use bitcoin::hashes::sha256;
 use sapio_base::effects::EffectDBError;
+use sapio_base::effects::EffectPath;
 use sapio_base::effects::ValidFragmentError;
 use sapio_ctv_emulator_trait::EmulatorError;
 use std::collections::LinkedList;
 use std::error::Error;
 use std::fmt;
+use std::sync::Arc;
 /// Sapio's core error type.
 #[derive(Debug)]
 pub enum CompilationError {
@@ -41,6 +46,8 @@ pub enum CompilationError {
     IncompatibleLockTime,
     /// Error if a sequence at index j >= inputs.len() is attempted to be set
     NoSuchSequence,
+    /// Error if the contract is set to be spent at an index j >= inputs.len()
+    NoSuchInput,
     /// Error if parsing an Amount failed
     ParseAmountError(bitcoin::util::amount::ParseAmountError),
     /// Error from the Policy Compiler
@@ -55,8 +62,24 @@ pub enum CompilationError {
     ConditionalCompilationFailed(LinkedList<String>),
     /// Error fromt the Effects system
     EffectDBError(EffectDBError),
+    /// Error if a `CompilationLimits` limit was exceeded, at the given path
+    ResourceLimitExceeded(ResourceLimit, Arc<EffectPath>),
+    /// Error if a template breaks the `Context`'s `StandardnessPolicy`, with
+    /// the template's hash and the path of the branch which created it
+    NonStandardTemplate(StandardnessViolation, sha256::Hash, Arc<EffectPath>),
+    /// Error if the fee set by `Builder::set_feerate` leaves a template
+    /// needing more than the contract's funds, with the template's hash and
+    /// the path of the branch which created it
+    FeeExceedsFunds(sha256::Hash, Arc<EffectPath>),
+    /// Error if a branch's guards mix height and time based timelocks, so
+    /// that no transaction could satisfy it, with the path of the branch
+    IncompatibleTimelocks(Arc<EffectPath>),
+    /// Error if a key was derived from a Context without `PathKeys`
+    NoPathKeys,
+    /// Error deriving a key from `PathKeys`
+    KeyDerivationError(bitcoin::util::bip32::Error),
     /// Unknown Error type -- either from a user or from some unhandled dependency
-    Custom(Box<dyn std::error::Error>),
+    Custom(Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl From<ValidFragmentError> for CompilationError {
@@ -78,7 +101,7 @@ impl From<std::convert::Infallible> for CompilationError {
 
 impl CompilationError {
     /// Create a custom compilation error instance
-    pub fn custom<E: std::error::Error + 'static>(e: E) -> Self {
+    pub fn custom<E: std::error::Error + Send + Sync + 'static>(e: E) -> Self {
         CompilationError::Custom(Box::new(e))
     }
 }
@@ -104,6 +127,11 @@ impl From<miniscript::Error> for CompilationError {
         CompilationError::MiniscriptE(v)
     }
 }
+impl From<bitcoin::util::bip32::Error> for CompilationError {
+    fn from(e: bitcoin::util::bip32::Error) -> Self {
+        CompilationError::KeyDerivationError(e)
+    }
+}
 impl From<ObjectError> for CompilationError {
     fn from(e: ObjectError) -> Self {
         CompilationError::CompiledObjectError(e)
@@ -112,7 +140,33 @@ impl From<ObjectError> for CompilationError {
 
 impl fmt::Display for CompilationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            CompilationError::ResourceLimitExceeded(limit, path) => write!(
+                f,
+                "ResourceLimitExceeded({:?}) at {}",
+                limit,
+                String::from(path.as_ref().clone())
+            ),
+            CompilationError::NonStandardTemplate(violation, template, path) => write!(
+                f,
+                "NonStandardTemplate({}) {} at {}",
+                violation,
+                template,
+                String::from(path.as_ref().clone())
+            ),
+            CompilationError::FeeExceedsFunds(template, path) => write!(
+                f,
+                "FeeExceedsFunds {} at {}",
+                template,
+                String::from(path.as_ref().clone())
+            ),
+            CompilationError::IncompatibleTimelocks(path) => write!(
+                f,
+                "IncompatibleTimelocks at {}",
+                String::from(path.as_ref().clone())
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
@@ -16,6 +16,8 @@ pub mod compiler;
 pub mod error;
 pub use error::CompilationError;
 pub mod context;
+#[cfg(test)]
+pub(crate) mod test_util;
 use bitcoin::util::amount::Amount;
 pub use compiler::Compilable;
 pub use context::Context;
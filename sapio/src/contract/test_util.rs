@@ -0,0 +1,110 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Scaffolding shared by the contract tests
+use super::actions::ThenFunc;
+use super::{Compiled, Context, DynamicContract, TxTmplIt};
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::amount::Amount;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::serialization_helpers::SArc;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// A fixed secret key
+pub(crate) fn secret() -> SecretKey {
+    secret_at(1)
+}
+/// The public key for `secret`
+pub(crate) fn key() -> bitcoin::PublicKey {
+    key_at(1)
+}
+/// The `i`th fixed secret key
+pub(crate) fn secret_at(i: u8) -> SecretKey {
+    SecretKey::from_slice(&[i; 32]).unwrap()
+}
+/// The public key for `secret_at(i)`
+pub(crate) fn key_at(i: u8) -> bitcoin::PublicKey {
+    bitcoin::PublicKey {
+        compressed: true,
+        key: bitcoin::secp256k1::PublicKey::from_secret_key(&Secp256k1::new(), &secret_at(i)),
+    }
+}
+/// A regtest Context at `root` with 10000 sats and CTV available
+pub(crate) fn ctx() -> Context {
+    ctx_with(Amount::from_sat(10000))
+}
+/// As `ctx`, but with `amount` available
+pub(crate) fn ctx_with(amount: Amount) -> Context {
+    Context::new(
+        bitcoin::Network::Regtest,
+        amount,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("root").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+}
+/// `s` as a path
+pub(crate) fn path(s: &str) -> SArc<EffectPath> {
+    SArc(Arc::new(EffectPath::try_from(s).unwrap()))
+}
+/// The address of an empty script, which anyone can spend
+pub(crate) fn empty_address(network: bitcoin::Network) -> bitcoin::Address {
+    bitcoin::Address::p2wsh(&bitcoin::Script::new(), network)
+}
+/// A template paying 5000 sats to `empty_address`
+pub(crate) fn pay(_: &(), ctx: Context) -> TxTmplIt {
+    let address = empty_address(ctx.network);
+    ctx.template()
+        .add_output(
+            Amount::from_sat(5000),
+            &Compiled::from_address(address, None),
+            None,
+        )?
+        .into()
+}
+/// An unguarded branch named "pay" using `pay`
+pub(crate) fn pay_branch() -> Option<ThenFunc<'static, ()>> {
+    Some(ThenFunc {
+        guard: &[],
+        conditional_compile_if: &[],
+        func: pay,
+        name: Arc::new("pay".into()),
+        weight: 1,
+    })
+}
+/// A contract with only `pay_branch`
+pub(crate) fn pay_contract() -> DynamicContract<'static, (), ()> {
+    DynamicContract {
+        then: vec![pay_branch],
+        finish_or: vec![],
+        finish: vec![],
+        data: (),
+    }
+}
+/// The data of a `contract_with` contract
+pub(crate) type WithFunc<T> = (T, fn(&T, Context) -> TxTmplIt);
+/// A contract whose only branch, "pay", calls `func` with `data`
+pub(crate) fn contract_with<T: 'static>(
+    data: T,
+    func: fn(&T, Context) -> TxTmplIt,
+) -> DynamicContract<'static, (), WithFunc<T>> {
+    DynamicContract {
+        then: vec![|| {
+            Some(ThenFunc {
+                guard: &[],
+                conditional_compile_if: &[],
+                func: |(data, func), ctx| func(data, ctx),
+                name: Arc::new("pay".into()),
+                weight: 1,
+            })
+        }],
+        finish_or: vec![],
+        finish: vec![],
+        data: (data, func),
+    }
+}
@@ -7,10 +7,12 @@
 //! Interactive Transaction Template Builder
 pub use super::{Output, OutputMeta};
 use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use crate::contract::abi::diagnostics::dust_limit;
+use crate::contract::compiler::parallel;
+use crate::contract::{Compilable, CompilationError, Context};
 use bitcoin::util::amount::Amount;
 use bitcoin::VarInt;
-use miniscript::DescriptorTrait;
+use miniscript::{Descriptor, DescriptorTrait, Miniscript, Terminal};
 use sapio_base::effects::PathFragment;
 use sapio_base::timelocks::*;
 use sapio_base::CTVHash;
@@ -21,12 +23,15 @@ use std::convert::TryInto;
 /// finalizing into a Template.
 pub struct Builder {
     sequences: Vec<Option<AnyRelTimeLock>>,
+    ctv_index: u32,
     outputs: Vec<Output>,
     version: i32,
     lock_time: Option<AnyAbsTimeLock>,
     ctx: Context,
     fees: Amount,
     min_feerate: Option<Amount>,
+    feerate: Option<Amount>,
+    anchors: Vec<u32>,
     // Metadata Fields:
     metadata: TemplateMetadata,
 }
@@ -36,12 +41,15 @@ impl Builder {
     pub fn new(ctx: Context) -> Builder {
         Builder {
             sequences: vec![None],
+            ctv_index: 0,
             outputs: vec![],
             version: 2,
             lock_time: None,
             metadata: TemplateMetadata::new(),
             fees: Amount::from_sat(0),
             min_feerate: None,
+            feerate: None,
+            anchors: vec![],
             ctx,
         }
     }
@@ -85,6 +93,64 @@ impl Builder {
         Ok(ret)
     }
 
+    /// Creates several Outputs at once, compiling them in parallel if the
+    /// builder's Context has parallelism enabled. Paths are derived in order,
+    /// exactly as if `add_output` had been called for each output in turn.
+    pub fn add_outputs(
+        mut self,
+        outputs: Vec<(Amount, &(dyn Compilable + Sync), Option<OutputMeta>)>,
+    ) -> Result<Self, CompilationError> {
+        let mut jobs = Vec::with_capacity(outputs.len());
+        for (amount, contract, metadata) in outputs {
+            let subctx = self
+                .ctx
+                .derive(PathFragment::Branch(
+                    (self.outputs.len() + jobs.len()) as u64,
+                ))?
+                .with_amount(amount)?;
+            self = self.spend_amount(amount)?;
+            jobs.push((amount, contract, metadata, subctx));
+        }
+        let compiled = parallel::map(
+            parallel::enabled(&self.ctx),
+            jobs,
+            |(amount, contract, metadata, subctx)| -> Result<Output, CompilationError> {
+                Ok(Output {
+                    amount,
+                    contract: contract.compile(subctx)?,
+                    metadata: metadata.unwrap_or_else(Default::default),
+                })
+            },
+        );
+        for output in compiled {
+            self.outputs.push(output?);
+        }
+        Ok(self)
+    }
+
+    /// Adds a dust-valued anchor output, which a child transaction can spend
+    /// to bump this template's fee at broadcast time (see
+    /// `Object::cpfp_psbt`). The anchor is spendable by `key`, or by anyone
+    /// if `None`, in which case it is a P2WSH of `OP_TRUE`.
+    pub fn add_anchor_output(
+        self,
+        key: Option<bitcoin::PublicKey>,
+    ) -> Result<Self, CompilationError> {
+        let descriptor = match key {
+            Some(key) => Descriptor::new_wpkh(key)?,
+            None => Descriptor::new_wsh(Miniscript::from_ast(Terminal::True)?)?,
+        };
+        let amount = dust_limit(&descriptor.script_pubkey());
+        let index = self.outputs.len() as u32;
+        let address = descriptor.address(self.ctx.network)?;
+        let mut anchor = Context::compiled_from_descriptor(descriptor, None);
+        // compiled_from_descriptor always uses a mainnet address
+        anchor.address = address.into();
+        let mut ret = self.add_output(amount, &anchor, None)?;
+        ret.anchors.push(index);
+        Ok(ret)
+    }
+
     /// adds available funds to the builder's context object.
     /// TODO: Make guarantee there is some external input?
     pub fn add_amount(mut self, a: Amount) -> Self {
@@ -92,7 +158,7 @@ impl Builder {
         self
     }
 
-    /// Adds another output. Follow with a call to
+    /// Adds another input. Follow with a call to
     /// set_sequence(-1, ...) to fill in the back.
     pub fn add_sequence(mut self) -> Self {
         self.sequences.push(None);
@@ -148,6 +214,30 @@ impl Builder {
         Ok(self)
     }
 
+    /// set the transaction version, 2 by default. Version 3 opts into Bitcoin
+    /// Core's TRUC (topologically restricted until confirmation) relay
+    /// policy, under which a template can have a zero fee and be bumped by a
+    /// child in a package, but must be small, as must any template spending
+    /// it before it confirms. Compilation checks these rules against the
+    /// `Context`'s `StandardnessPolicy`.
+    pub fn set_version(mut self, version: i32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// set the index of the input which spends the contract, so that the
+    /// template hash commits to it. The contract spends input 0 by default.
+    ///
+    /// The other inputs, e.g. other covenant coins being co-spent, must
+    /// already have been added with `add_sequence`.
+    pub fn set_ctv_index(mut self, i: u32) -> Result<Self, CompilationError> {
+        if i as usize >= self.sequences.len() {
+            return Err(CompilationError::NoSuchInput);
+        }
+        self.ctv_index = i;
+        Ok(self)
+    }
+
     /// overwrite any existing label with the provided string,
     /// or set a label if none provided thus far.
     pub fn set_label(mut self, label: String) -> Self {
@@ -204,50 +294,47 @@ impl Builder {
         self
     }
 
+    /// Pay fees for this template at `sat_per_vbyte`, replacing any feerate
+    /// set before.
+    ///
+    /// The exact fee depends on the witness spending the contract, so it is
+    /// computed once the contract's script is known, using the spending
+    /// branch's maximum satisfaction size. It is then added to the template's
+    /// (and so the contract's) required amount, as with `add_fees`.
+    /// Compilation fails with `CompilationError::FeeExceedsFunds` if the
+    /// contract's funds can't cover it.
+    pub fn set_feerate(mut self, sat_per_vbyte: Amount) -> Self {
+        self.feerate = Some(sat_per_vbyte);
+        self
+    }
+
     /// more efficient that get_tx() to estimate a tx size, not including witness
     pub fn estimate_tx_size(&self) -> u64 {
-        let mut input_weight: u64 = 0;
-        let inputs_with_witnesses: u64 = self.sequences.len() as u64;
-        let scale_factor = 1u64;
-        for _seq in &self.sequences {
-            input_weight += scale_factor
-                * (32 + 4 + 4 + // outpoint (32+4) + nSequence
-                VarInt(0u64).len() as u64 + 0);
-            //if !input.witness.is_empty() {
-            //    inputs_with_witnesses += 1;
-            //    input_weight += VarInt(input.witness.len() as u64).len();
-            //    for elem in &input.witness {
-            //        input_weight += VarInt(elem.len() as u64).len() + elem.len();
-            //    }
-            //}
-        }
+        let input_size = self.sequences.len() as u64
+            * (32 + 4 + 4 + // outpoint (32+4) + nSequence
+                VarInt(0u64).len() as u64); // empty script_sig
         let mut output_size: u64 = 0;
         for output in &self.outputs {
-            let spk = output
-                .contract
-                .descriptor
-                .as_ref()
-                .map(|d| d.script_pubkey().len() as u64);
+            let spk = bitcoin::Script::from(output.contract.address.clone()).len() as u64;
             output_size += 8 + // value
-                (VarInt(spk.unwrap_or(0)).len() as u64) +
-                spk.unwrap_or(0);
+                (VarInt(spk).len() as u64) +
+                spk;
         }
-        let non_input_size : u64=
         // version:
         4 +
         // count varints:
-        (VarInt(self.sequences.len() as u64).len() as u64 +
-        VarInt(self.outputs.len() as u64).len() as u64)+
+        VarInt(self.sequences.len() as u64).len() as u64 +
+        VarInt(self.outputs.len() as u64).len() as u64 +
+        input_size +
         output_size +
         // lock_time
-        4;
-        if inputs_with_witnesses == 0 {
-            non_input_size * scale_factor + input_weight
-        } else {
-            non_input_size * scale_factor + input_weight + (self.sequences.len() as u64)
-                - inputs_with_witnesses
-                + 2
-        }
+        4
+    }
+
+    /// estimate the virtual size of the transaction, given the size of the
+    /// witness (including its stack item count) spending the contract.
+    pub fn estimate_vsize(&self, witness_size: usize) -> u64 {
+        super::vsize(self.estimate_tx_size(), self.sequences.len(), witness_size)
     }
 }
 impl From<Builder> for Template {
@@ -255,10 +342,12 @@ impl From<Builder> for Template {
         let tx = t.get_tx();
         Template {
             outputs: t.outputs,
-            ctv: tx.get_ctv_hash(0),
-            ctv_index: 0,
+            ctv: tx.get_ctv_hash(t.ctv_index),
+            ctv_index: t.ctv_index,
             max: tx.total_amount() + t.fees,
             min_feerate_sats_vbyte: t.min_feerate,
+            feerate_sats_vbyte: t.feerate,
+            anchors: t.anchors,
             tx,
             metadata_map_s2s: t.metadata,
         }
@@ -271,3 +360,76 @@ impl From<Builder> for crate::contract::TxTmplIt {
         Ok(Box::new(std::iter::once(Ok(t.into()))))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{ctx, ctx_with, empty_address, pay_branch, pay_contract};
+    #[test]
+    fn test_ctv_index() {
+        let tx = ctx().template().add_sequence().get_tx();
+        assert!(matches!(
+            ctx().template().add_sequence().set_ctv_index(2),
+            Err(CompilationError::NoSuchInput)
+        ));
+        let tmpl: Template = ctx()
+            .template()
+            .add_sequence()
+            .set_ctv_index(1)
+            .unwrap()
+            .into();
+        assert_eq!(tmpl.ctv_index, 1);
+        assert_eq!(tmpl.ctv, tx.get_ctv_hash(1));
+        assert_ne!(tmpl.ctv, tx.get_ctv_hash(0));
+    }
+    fn pay_with_feerate(_: &(), ctx: Context) -> crate::contract::TxTmplIt {
+        let address = empty_address(ctx.network);
+        ctx.template()
+            .add_output(
+                Amount::from_sat(5000),
+                &crate::contract::Compiled::from_address(address, None),
+                None,
+            )?
+            .set_feerate(Amount::from_sat(2))
+            .into()
+    }
+    #[test]
+    fn test_set_feerate() {
+        use crate::contract::actions::ThenFunc;
+        use crate::contract::{Compiled, DynamicContract};
+        let address = empty_address(bitcoin::Network::Regtest);
+        let builder = ctx()
+            .template()
+            .add_output(
+                Amount::from_sat(5000),
+                &Compiled::from_address(address, None),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            builder.estimate_tx_size(),
+            bitcoin::consensus::serialize(&builder.get_tx()).len() as u64
+        );
+        let contract = DynamicContract::<(), ()> {
+            then: vec![|| {
+                Some(ThenFunc {
+                    func: pay_with_feerate,
+                    ..pay_branch()?
+                })
+            }],
+            ..pay_contract()
+        };
+        let compiled = crate::contract::Compilable::compile(&contract, ctx()).unwrap();
+        let tmpl = compiled.ctv_to_tx.values().next().unwrap();
+        let witness_size = *compiled.witness_size_estimates.values().next().unwrap();
+        let fee = tmpl.max - tmpl.total_amount();
+        assert_eq!(fee, Amount::from_sat(2 * tmpl.estimate_vsize(witness_size)));
+        assert_eq!(compiled.amount_range.max(), tmpl.max);
+        // the outputs fit, but not once the fee is added
+        let poor = ctx_with(Amount::from_sat(5100));
+        assert!(matches!(
+            crate::contract::Compilable::compile(&contract, poor),
+            Err(crate::contract::CompilationError::FeeExceedsFunds(..))
+        ));
+    }
+}
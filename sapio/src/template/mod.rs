@@ -17,6 +17,9 @@ pub use output::{Output, OutputMeta};
 pub mod builder;
 pub use builder::Builder;
 
+pub mod standardness;
+pub use standardness::{StandardnessMode, StandardnessPolicy};
+
 /// Metadata Struct which has some standard defined fields
 /// and can be extended via a hashmap
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
@@ -54,8 +57,8 @@ pub struct Template {
     /// the precomputed template hash for this Template
     #[serde(rename = "precomputed_template_hash")]
     pub ctv: sha256::Hash,
-    /// the index used for the template hash. (TODO: currently always 0, although
-    /// future version may support other indexes)
+    /// the index of the input spending the contract, which the template hash
+    /// commits to.
     #[serde(rename = "precomputed_template_hash_idx")]
     pub ctv_index: u32,
     /// the amount being sent to this Template (TODO: currently computed via tx.total_amount())
@@ -72,6 +75,24 @@ pub struct Template {
     )]
     #[schemars(with = "Option<i64>")]
     pub min_feerate_sats_vbyte: Option<Amount>,
+    /// the feerate this template pays for its own size at, set with
+    /// `Builder::set_feerate`. The fee is included in `max`.
+    #[serde(
+        rename = "feerate_sats_vbyte",
+        with = "bitcoin::util::amount::serde::as_sat::opt",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    #[schemars(with = "Option<i64>")]
+    pub feerate_sats_vbyte: Option<Amount>,
+    /// the indexes of the outputs added with `Builder::add_anchor_output`,
+    /// which can be spent by a child transaction to bump the fee
+    #[serde(
+        rename = "anchor_outputs",
+        skip_serializing_if = "Vec::is_empty",
+        default
+    )]
+    pub anchors: Vec<u32>,
     /// any metadata fields attached to this template
     #[serde(
         skip_serializing_if = "TemplateMetadata::skip_serializing",
@@ -101,4 +122,21 @@ impl Template {
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
     }
+
+    /// estimate the virtual size of the transaction, given the size of the
+    /// witness (including its stack item count) spending the contract.
+    pub fn estimate_vsize(&self, witness_size: usize) -> u64 {
+        // without witnesses, the transaction serializes in the legacy format
+        let base_size = bitcoin::consensus::serialize(&self.tx).len() as u64;
+        vsize(base_size, self.tx.input.len(), witness_size)
+    }
+}
+
+/// The virtual size of a transaction with `inputs` inputs and `base_size`
+/// bytes excluding witnesses, where only the contract's input has a witness,
+/// of `witness_size` bytes.
+pub(crate) fn vsize(base_size: u64, inputs: usize, witness_size: usize) -> u64 {
+    // segwit marker and flag, and an empty stack for every other input
+    let witness_size = 2 + witness_size as u64 + inputs.saturating_sub(1) as u64;
+    (base_size * 4 + witness_size).div_ceil(4)
 }
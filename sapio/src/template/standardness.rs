@@ -0,0 +1,310 @@
+// Copyright Judica, Inc 2021
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Checks that templates will be relayed, following Bitcoin Core's policy
+use super::Template;
+use crate::contract::abi::diagnostics::dust_limit_at;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+
+pub use sapio_base::standardness::{StandardnessMode, StandardnessPolicy};
+
+/// Checks templates against a `StandardnessPolicy`
+pub trait CheckStandardness {
+    /// Every way in which `tmpl` breaks this policy, when spending the
+    /// contract with a witness of `witness_size` bytes. This includes the
+    /// TRUC rules between `tmpl` and the templates spending its outputs.
+    fn check(&self, tmpl: &Template, witness_size: usize) -> Vec<StandardnessViolation>;
+}
+
+impl CheckStandardness for StandardnessPolicy {
+    fn check(&self, tmpl: &Template, witness_size: usize) -> Vec<StandardnessViolation> {
+        let mut violations = vec![];
+        if self.mode == StandardnessMode::Off {
+            return violations;
+        }
+        let tx = &tmpl.tx;
+        if tx.version < 1 || tx.version > self.max_version {
+            violations.push(StandardnessViolation::Version(tx.version));
+        }
+        let vsize = tmpl.estimate_vsize(witness_size);
+        if vsize * 4 > self.max_weight {
+            violations.push(StandardnessViolation::TooLarge { weight: vsize * 4 });
+        }
+        if tx.version == 3 && vsize > self.truc_max_vsize {
+            violations.push(StandardnessViolation::TrucTooLarge { vsize });
+        }
+        let mut op_returns = 0;
+        for (index, out) in tx.output.iter().enumerate() {
+            let script = &out.script_pubkey;
+            if script.is_op_return() {
+                op_returns += 1;
+                if script.len() > self.max_op_return_size {
+                    violations.push(StandardnessViolation::OpReturnTooLarge {
+                        index,
+                        size: script.len(),
+                    });
+                }
+                continue;
+            }
+            if !(script.is_p2pkh() || script.is_p2sh() || script.is_witness_program()) {
+                violations.push(StandardnessViolation::NonStandardScript { index });
+                continue;
+            }
+            let amount = Amount::from_sat(out.value);
+            let dust_limit = dust_limit_at(script, self.dust_relay_feerate);
+            if amount < dust_limit {
+                violations.push(StandardnessViolation::Dust {
+                    index,
+                    amount,
+                    dust_limit,
+                });
+            }
+        }
+        if op_returns > self.max_op_return_outputs {
+            violations.push(StandardnessViolation::TooManyOpReturns(op_returns));
+        }
+        violations.extend(check_truc_children(self, tmpl));
+        violations
+    }
+}
+
+/// The TRUC rules between `tmpl` and the templates spending its outputs
+/// before it confirms: a TRUC transaction may only have TRUC children,
+/// and only one, which must be small; and a TRUC transaction may not
+/// spend an unconfirmed non-TRUC one. Anchor outputs count as having a
+/// child.
+fn check_truc_children(policy: &StandardnessPolicy, tmpl: &Template) -> Vec<StandardnessViolation> {
+    let mut violations = vec![];
+    let truc = tmpl.tx.version == 3;
+    let mut with_children: BTreeSet<usize> = if truc {
+        tmpl.anchors.iter().map(|i| *i as usize).collect()
+    } else {
+        BTreeSet::new()
+    };
+    for (index, out) in tmpl.outputs.iter().enumerate() {
+        let contract = &out.contract;
+        let witness_size = contract.max_witness_size().unwrap_or(0);
+        for child in contract
+            .ctv_to_tx
+            .values()
+            .chain(contract.suggested_txs.values())
+            .filter(|child| spends_unconfirmed(child))
+        {
+            if (child.tx.version == 3) != truc {
+                violations.push(StandardnessViolation::TrucVersionMismatch {
+                    index,
+                    child: child.ctv,
+                });
+            } else if truc {
+                with_children.insert(index);
+                let vsize = child.estimate_vsize(witness_size);
+                if vsize > policy.truc_child_max_vsize {
+                    violations.push(StandardnessViolation::TrucChildTooLarge {
+                        index,
+                        child: child.ctv,
+                        vsize,
+                    });
+                }
+            }
+        }
+    }
+    if with_children.len() > 1 {
+        violations.push(StandardnessViolation::TrucTooManyChildren(
+            with_children.len(),
+        ));
+    }
+    violations
+}
+
+/// Whether `tmpl` may spend the contract before the transaction creating it
+/// confirms, i.e. it has no relative timelock on the contract's input
+fn spends_unconfirmed(tmpl: &Template) -> bool {
+    let sequence = tmpl.tx.input[tmpl.ctv_index as usize].sequence;
+    tmpl.tx.version < 2 || sequence & (1 << 31) != 0 || sequence & 0xffff == 0
+}
+
+/// A reason a template would not be relayed
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StandardnessViolation {
+    /// The transaction's version is not standard
+    Version(i32),
+    /// The transaction is too large
+    TooLarge {
+        /// the estimated weight of the transaction
+        weight: u64,
+    },
+    /// An OP_RETURN output's script is too large
+    OpReturnTooLarge {
+        /// the index of the output
+        index: usize,
+        /// the size of the script
+        size: usize,
+    },
+    /// There are too many OP_RETURN outputs
+    TooManyOpReturns(usize),
+    /// An output's script is not of a standard type
+    NonStandardScript {
+        /// the index of the output
+        index: usize,
+    },
+    /// A version 3 transaction is too large
+    TrucTooLarge {
+        /// the estimated size of the transaction, in vbytes
+        vsize: u64,
+    },
+    /// A version 3 transaction spending an output of an unconfirmed version
+    /// 3 transaction is too large
+    TrucChildTooLarge {
+        /// the index of the output spent
+        index: usize,
+        /// the hash of the template spending it
+        child: sha256::Hash,
+        /// the estimated size of the child, in vbytes
+        vsize: u64,
+    },
+    /// An output may be spent before confirmation by a template with a
+    /// different TRUC-ness
+    TrucVersionMismatch {
+        /// the index of the output spent
+        index: usize,
+        /// the hash of the template spending it
+        child: sha256::Hash,
+    },
+    /// A version 3 transaction has more than one output which may be spent
+    /// before it confirms
+    TrucTooManyChildren(usize),
+    /// An output is below the dust limit
+    Dust {
+        /// the index of the output
+        index: usize,
+        /// the amount of the output
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        amount: Amount,
+        /// the smallest amount the output could have
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        dust_limit: Amount,
+    },
+}
+
+impl fmt::Display for StandardnessViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StandardnessViolation::Version(v) => write!(f, "version {} is not standard", v),
+            StandardnessViolation::TooLarge { weight } => {
+                write!(f, "weight of {} is too large", weight)
+            }
+            StandardnessViolation::OpReturnTooLarge { index, size } => {
+                write!(f, "OP_RETURN output {} is {} bytes, too large", index, size)
+            }
+            StandardnessViolation::TooManyOpReturns(n) => {
+                write!(f, "{} OP_RETURN outputs are too many", n)
+            }
+            StandardnessViolation::NonStandardScript { index } => {
+                write!(f, "output {} has a nonstandard script", index)
+            }
+            StandardnessViolation::TrucTooLarge { vsize } => {
+                write!(f, "version 3 size of {} vbytes is too large", vsize)
+            }
+            StandardnessViolation::TrucChildTooLarge {
+                index,
+                child,
+                vsize,
+            } => write!(
+                f,
+                "output {} is spent by version 3 template {} of {} vbytes, too large for a child",
+                index, child, vsize
+            ),
+            StandardnessViolation::TrucVersionMismatch { index, child } => write!(
+                f,
+                "output {} may be spent unconfirmed by template {}, but only one is version 3",
+                index, child
+            ),
+            StandardnessViolation::TrucTooManyChildren(n) => write!(
+                f,
+                "version 3 template has {} outputs which may be spent unconfirmed",
+                n
+            ),
+            StandardnessViolation::Dust {
+                index,
+                amount,
+                dust_limit,
+            } => write!(
+                f,
+                "output {} has {}, below the dust limit of {}",
+                index, amount, dust_limit
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::test_util::{contract_with, ctx, empty_address};
+    use crate::contract::{Compilable, Compiled, Context, TxTmplIt};
+    /// (version, version of the contract paid to if any, add an anchor)
+    type Spec = (i32, Option<i32>, bool);
+    fn pay(spec: &Spec, ctx: Context) -> TxTmplIt {
+        let (version, child, anchor) = *spec;
+        let to: Box<dyn Compilable> = match child {
+            Some(v) => Box::new(contract_with((v, None, false), pay)),
+            None => Box::new(Compiled::from_address(empty_address(ctx.network), None)),
+        };
+        let mut b = ctx.template().set_version(version).add_output(
+            Amount::from_sat(5000),
+            to.as_ref(),
+            None,
+        )?;
+        if anchor {
+            b = b.add_anchor_output(None)?;
+        }
+        b.into()
+    }
+    fn violations(spec: Spec) -> Vec<StandardnessViolation> {
+        contract_with(spec, pay)
+            .compile(ctx())
+            .unwrap()
+            .diagnostics
+            .into_iter()
+            .map(|d| match d {
+                crate::contract::abi::diagnostics::Diagnostic::NonStandard {
+                    violation, ..
+                } => violation,
+                d => panic!("unexpected {:?}", d),
+            })
+            .collect()
+    }
+    #[test]
+    fn test_truc() {
+        assert_eq!(violations((3, Some(3), false)), vec![]);
+        assert_eq!(violations((2, Some(2), true)), vec![]);
+        assert!(matches!(
+            &violations((3, Some(2), false))[..],
+            [StandardnessViolation::TrucVersionMismatch { index: 0, .. }]
+        ));
+        assert!(matches!(
+            &violations((2, Some(3), false))[..],
+            [StandardnessViolation::TrucVersionMismatch { index: 0, .. }]
+        ));
+        assert_eq!(
+            violations((3, Some(3), true)),
+            vec![StandardnessViolation::TrucTooManyChildren(2)]
+        );
+        assert_eq!(violations((3, None, true)), vec![]);
+        assert!(matches!(
+            &violations((4, None, false))[..],
+            [StandardnessViolation::Version(4)]
+        ));
+    }
+}
@@ -34,7 +34,7 @@ impl From<AmountF64> for Amount {
 
 /// `AmountRange` makes it simple to track and update the range of allowed values
 /// for a contract to receive.
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct AmountRange {
     #[serde(rename = "min_btc", skip_serializing_if = "Option::is_none", default)]
     min: Option<AmountF64>,
@@ -58,4 +58,8 @@ impl AmountRange {
     pub fn max(&self) -> Amount {
         self.max.unwrap_or(Amount::min_value().into()).0
     }
+    /// Check if `amount` is within the range. Unset bounds are unbounded.
+    pub fn contains(&self, amount: Amount) -> bool {
+        self.min.iter().all(|m| m.0 <= amount) && self.max.iter().all(|m| amount <= m.0)
+    }
 }
@@ -16,7 +16,7 @@ use std::convert::TryFrom;
 
 /// A type that handles (gracefully) the fact that certain widely used
 /// output types do not have an address
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum ExtendedAddress {
     /// A regular standard address type
@@ -43,7 +43,7 @@ impl ExtendedAddress {
 }
 
 /// Internal type for processing OpReturn through serde
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
 #[serde(try_from = "Script")]
 #[serde(into = "Script")]
 pub struct OpReturn(Script);
@@ -64,6 +64,17 @@ impl From<OpReturn> for Script {
         o.0
     }
 }
+impl std::fmt::Display for ExtendedAddress {
+    /// The address, or the hex of the script if there is no address
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExtendedAddress::Address(a) => write!(f, "{}", a),
+            ExtendedAddress::OpReturn(OpReturn(s)) | ExtendedAddress::Unknown(s) => {
+                write!(f, "{:x}", s)
+            }
+        }
+    }
+}
 impl From<Address> for ExtendedAddress {
     fn from(a: Address) -> Self {
         ExtendedAddress::Address(a)
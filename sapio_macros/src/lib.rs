@@ -44,7 +44,10 @@ pub fn compile_if(args: TokenStream, input: TokenStream) -> TokenStream {
 /// ```ignore
 /// #[guard(
 ///     /// optional, if desired to only be invoked once
-///     cached
+///     cached,
+///     /// optional: relative likelihood of this guard being used when it is
+///     /// a `finish` branch, defaults to 1
+///     weight = 10
 /// )]
 /// fn name(self, ctx) {
 ///     /*Clause*/
@@ -62,7 +65,7 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
     let guard_name = format_ident!("guard_{}", name);
     let block = input.block;
     let mut ty = format_ident!("Fresh");
-    for arg in args {
+    for arg in &args {
         match arg {
             NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("cached") => {
                 ty = format_ident!("Cached");
@@ -70,15 +73,32 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
             _ => {}
         }
     }
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
         fn #guard_name(&self, #context_arg) -> sapio::sapio_base::Clause
         #block
         fn  #name() -> Option<sapio::contract::actions::Guard<Self>> {
-            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name))
+            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name, #weight))
         }
     })
 }
 
+fn get_weight(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("weight") => match &v.lit {
+                Lit::Int(l) => {
+                    let w: usize = l.base10_parse().expect("Weight must be a usize");
+                    return quote! {#w};
+                }
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    quote! {1}
+}
+
 fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2::TokenStream) {
     let mut compile_if_array = None;
     let mut guarded_by_array = None;
@@ -123,7 +143,9 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
 ///     /// optional: only compile these branches if these compile_if statements permit
 ///     compile_if= "[compile_if_1, ... compile_if_n]",
 ///     /// optional: protect these branches with the conjunction (and) of these clauses
-///     guarded_by= "[guard_1, ... guard_n]"
+///     guarded_by= "[guard_1, ... guard_n]",
+///     /// optional: relative likelihood of this branch being used, defaults to 1
+///     weight = 10
 /// )]
 /// fn name(self, ctx) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -141,6 +163,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
     let then_fn_name = format_ident!("then_{}", name);
     let block = input.block;
     let (cia, gba) = get_arrays(&args);
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
             /// (missing docs fix)
             fn #name<'a>() -> Option<sapio::contract::actions::ThenFunc<'a, Self>>{
@@ -149,6 +172,7 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
                     conditional_compile_if: &#cia,
                     func: Self::#then_fn_name,
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
                 })
             }
             /// (missing docs fix)
@@ -233,7 +257,9 @@ fn web_api_schema(
 ///     ///  optional: Enables compiling this for a json callable continuation
 ///     web_api,
 ///     /// helper for coercing args for json api, could be arbitrary
-///     coerce_args = "default_coerce"
+///     coerce_args = "default_coerce",
+///     /// optional: relative likelihood of this branch being used, defaults to 1
+///     weight = 10
 /// )]
 /// fn name(self, ctx:Context, o:UpdateType) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -262,6 +288,7 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
     let continue_schema_for_name = format_ident!("continue_schema_for_{}", name);
     let web_api_schema_s = web_api_schema(&args, &continue_schema_for_name, &arg_type);
     let coerce_args_f = coerce_args(&args);
+    let weight = get_weight(&args);
     proc_macro::TokenStream::from(quote! {
             #web_api_schema_s
             /// (missing docs fix)
@@ -278,6 +305,7 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
                     func: Self::#continue_name,
                     schema: Self::#continue_schema_for_name.map(|f|f()),
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    weight: #weight,
                     f: std::default::Default::default()
                 };
                 Some(Box::new(f))